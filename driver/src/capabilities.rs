@@ -0,0 +1,87 @@
+// What the adapter firmware reports it can do, asked for when the device is opened.
+//
+// Response args are [Major, Minor, Max payload (2 bytes), Protocols (4 bytes), Max filters, CAN buses].
+// Protocols has bit N set if J2534 protocol ID N is supported. CAN buses was added in 1.1, so
+// older firmware, which only drives one, does not send it. Firmware with a different
+// major protocol version speaks a different CommMsg protocol, so is refused
+
+use byteorder::{ByteOrder, LittleEndian};
+use j2534_rust::{PassthruError, Protocol};
+use crate::passthru_drv::set_error_string;
+
+/// CommMsg protocol version this driver speaks
+pub const PROTOCOL_VERSION_MAJOR: u8 = 1;
+pub const PROTOCOL_VERSION_MINOR: u8 = 1;
+
+const RESPONSE_SIZE: usize = 9;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Capabilities {
+    pub version_major: u8,
+    pub version_minor: u8,
+    /// Most data bytes a single Tx message can carry
+    pub max_payload: u16,
+    /// Bit N set for each supported protocol ID N
+    pub protocols: u32,
+    /// Filters a channel can have, each one takes a CAN mailbox
+    pub max_filters: u8,
+    /// CAN interfaces the adapter can open channels on at once
+    pub can_buses: u8,
+}
+
+impl Capabilities {
+    pub fn from_args(args: &[u8]) -> Option<Self> {
+        if args.len() < RESPONSE_SIZE {
+            return None;
+        }
+        Some(Self {
+            version_major: args[0],
+            version_minor: args[1],
+            max_payload: LittleEndian::read_u16(&args[2..4]),
+            protocols: LittleEndian::read_u32(&args[4..8]),
+            max_filters: args[8],
+            can_buses: args.get(RESPONSE_SIZE).copied().unwrap_or(1),
+        })
+    }
+
+    /// Encodes the response, as the firmware does
+    #[cfg(test)]
+    pub fn to_args(self) -> Vec<u8> {
+        let mut args = vec![self.version_major, self.version_minor];
+        args.extend_from_slice(&self.max_payload.to_le_bytes());
+        args.extend_from_slice(&self.protocols.to_le_bytes());
+        args.push(self.max_filters);
+        args.push(self.can_buses);
+        args
+    }
+
+    /// Newer minor versions only add to the protocol, so are still compatible
+    pub fn check_version(&self) -> Result<(), String> {
+        if self.version_major != PROTOCOL_VERSION_MAJOR {
+            return Err(format!(
+                "Adapter firmware uses protocol version {}.{}, this driver uses {}.{}. Update the {}",
+                self.version_major,
+                self.version_minor,
+                PROTOCOL_VERSION_MAJOR,
+                PROTOCOL_VERSION_MINOR,
+                if self.version_major > PROTOCOL_VERSION_MAJOR { "driver" } else { "firmware" }
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn supports(&self, protocol: Protocol) -> bool {
+        let id = protocol as u32;
+        id < 32 && self.protocols & (1 << id) != 0
+    }
+
+    /// Fails with ERR_NOT_SUPPORTED if the firmware cannot do `protocol`
+    pub fn check_protocol(&self, protocol: Protocol) -> Result<(), PassthruError> {
+        if self.supports(protocol) {
+            Ok(())
+        } else {
+            set_error_string(format!("Adapter does not support {:?}", protocol));
+            Err(PassthruError::ERR_NOT_SUPPORTED)
+        }
+    }
+}
@@ -1,59 +1,177 @@
 use j2534_rust::*;
-use lazy_static::*;
 use crate::logger::*;
-use std::collections::VecDeque;
+use std::collections::BTreeMap;
 use std::sync::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{spawn, JoinHandle};
+use std::time::{Duration, Instant};
 use crate::comm::*;
 use byteorder::{LittleEndian, ByteOrder, WriteBytesExt};
 use crate::passthru_drv::set_error_string;
-
-lazy_static! {
-    static ref CAN_CHANNEL: RwLock<Option<Channel>> = RwLock::new(None);
-    static ref KLINE_CHANNEL: RwLock<Option<Channel>> = RwLock::new(None);
-    static ref J1850_CHANNEL: RwLock<Option<Channel>> = RwLock::new(None);
-    static ref SCI_CHANNEL: RwLock<Option<Channel>> = RwLock::new(None);
-}
-
+use crate::devices;
+use crate::capabilities::Capabilities;
+use crate::indications::{RxIndications, TxIndications};
+use crate::rx_queue::RxQueue;
+use crate::tx_queue::TxQueue;
 
 // Defined in J2534 spec. Each channel can have up to 10 filters
 const MAX_FILTERS_PER_CHANNEL: usize = 10;
 
+// Messages a channel queues to send before PassThruWriteMsgs has to wait, or return ERR_BUFFER_FULL
+const MAX_TX_MSGS: usize = 1000;
+
+// J2534-2 protocol IDs of CAN and ISO15765 on a given CAN bus. CAN_CH1 is 0x9000, CAN_CH2 0x9001...
+const CAN_CH1: u32 = 0x9000;
+const ISO15765_CH1: u32 = 0x9400;
+const J2534_2_CHANNELS: u32 = 128;
+
 type Result<T> = std::result::Result<T, PassthruError>;
 
-enum ChannelID {
-    Can = 0,
+/// Physical part of the adapter a channel uses, such as a CAN controller or the K-Line transceiver.
+/// Only one channel can be on each at a time. The firmware knows a channel by the ID of its resource
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Resource {
+    Can0 = 0,
     Kline = 1,
     J1850 = 2,
-    Sci = 3
+    Sci = 3,
+    Can1 = 4,
+}
+
+impl Resource {
+    /// Resources which can carry `protocol`, in the order they are handed out
+    fn for_protocol(protocol: ProtocolId) -> &'static [Resource] {
+        match protocol.can_bus {
+            Some(0) => return &[Resource::Can0],
+            Some(1) => return &[Resource::Can1],
+            Some(_) => return &[],
+            None => {}
+        }
+        match protocol.protocol {
+            // Plain CAN is always the first bus, the one on pins 6 and 14 of the OBD connector
+            Protocol::ISO15765 | Protocol::CAN => &[Resource::Can0],
+            Protocol::ISO14230 | Protocol::ISO9141 => &[Resource::Kline],
+            Protocol::J1850PWM | Protocol::J1850VPW => &[Resource::J1850],
+            Protocol::SCI_A_ENGINE | Protocol::SCI_A_TRANS | Protocol::SCI_B_ENGINE | Protocol::SCI_B_TRANS => &[Resource::Sci]
+        }
+    }
+
+    /// ID the firmware knows the channel on this resource by
+    pub fn fw_id(self) -> u32 {
+        self as u32
+    }
+}
+
+/// Protocol ID an application connects with. Either one from J2534-1, or a J2534-2 ID
+/// of CAN or ISO15765 on a particular CAN bus of the adapter
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ProtocolId {
+    /// ID as the application gave it, which its messages have to use
+    pub id: u32,
+    /// Protocol the channel speaks
+    pub protocol: Protocol,
+    /// CAN bus the J2534-2 ID asks for, from 0
+    pub can_bus: Option<u32>,
+}
+
+impl ProtocolId {
+    /// Returns None if `id` is not a protocol ID this driver knows
+    pub fn parse(id: u32) -> Option<Self> {
+        let (protocol, can_bus) = match id {
+            _ if (CAN_CH1..CAN_CH1 + J2534_2_CHANNELS).contains(&id) => (Protocol::CAN, Some(id - CAN_CH1)),
+            _ if (ISO15765_CH1..ISO15765_CH1 + J2534_2_CHANNELS).contains(&id) => (Protocol::ISO15765, Some(id - ISO15765_CH1)),
+            _ => (Protocol::try_from(id).ok()?, None)
+        };
+        Some(Self { id, protocol, can_bus })
+    }
+
+    /// Fails with ERR_NOT_SUPPORTED if the adapter does not have the CAN bus the ID asks for
+    fn check_can_bus(&self, caps: &Capabilities) -> Result<()> {
+        match self.can_bus {
+            Some(bus) if bus >= caps.can_buses as u32 => {
+                set_error_string(format!("Protocol ID 0x{:04X} is CAN bus {}, the adapter only has {}", self.id, bus + 1, caps.can_buses));
+                Err(PassthruError::ERR_NOT_SUPPORTED)
+            },
+            _ => Ok(())
+        }
+    }
+}
+
+struct Entry {
+    resource: Resource,
+    /// None whilst the adapter is opening the channel
+    channel: Option<Arc<RwLock<Channel>>>,
+}
+
+/// Channels open on one adapter, keyed by the ID handed to the application.
+/// IDs are handed out in turn rather than reused straight away, so a stale
+/// ID from a closed channel does not end up on the next one opened
+pub struct ChannelTable {
+    entries: RwLock<BTreeMap<u32, Entry>>,
+    next_id: Mutex<u32>,
 }
 
+impl Default for ChannelTable {
+    fn default() -> Self {
+        Self { entries: RwLock::new(BTreeMap::new()), next_id: Mutex::new(1) }
+    }
+}
 
-impl ChannelID {
-    fn get_channel(&self) -> &'static RwLock<Option<Channel>> {
-        match self {
-            ChannelID::Can => &CAN_CHANNEL,
-            ChannelID::Kline => &KLINE_CHANNEL,
-            ChannelID::J1850 => &J1850_CHANNEL,
-            ChannelID::Sci => &SCI_CHANNEL
+impl ChannelTable {
+    /// Drops every channel, without telling the adapter, once its Tx thread has stopped. Only used when the adapter is being closed
+    pub fn clear(&self) {
+        let entries = std::mem::take(&mut *self.entries.write().unwrap());
+        for channel in entries.into_values().filter_map(|e| e.channel) {
+            channel.write().unwrap().stop_tx();
         }
     }
 
-    pub fn from_u32(id: u32) -> Result<Self> {
-        match id {
-            0 => Ok(ChannelID::Can),
-            1 => Ok(ChannelID::Kline),
-            2 => Ok(ChannelID::J1850),
-            3 => Ok(ChannelID::Sci),
-            _ => Err(PassthruError::ERR_INVALID_CHANNEL_ID)
+    /// Sets up every open channel, with its filters and config, on a newly connected adapter.
+    /// Used after the adapter has been unplugged and plugged back in
+    pub fn restore(&self, dev: &MacchinaM2) -> Result<()> {
+        for channel in self.entries.read().unwrap().values().filter_map(|e| e.channel.as_ref()) {
+            channel.read().unwrap().restore(dev)?;
+        }
+        Ok(())
+    }
+
+    fn get(&self, local_id: u32) -> Option<Arc<RwLock<Channel>>> {
+        self.entries.read().unwrap().get(&local_id).and_then(|e| e.channel.clone())
+    }
+
+    /// Finds the channel the firmware sent data for
+    fn by_fw_id(&self, fw_id: u32) -> Option<Arc<RwLock<Channel>>> {
+        self.entries.read().unwrap().values().find(|e| e.resource.fw_id() == fw_id).and_then(|e| e.channel.clone())
+    }
+
+    /// Picks the next ID which is not in use
+    fn alloc_id(&self, entries: &BTreeMap<u32, Entry>) -> u32 {
+        let mut next = self.next_id.lock().unwrap();
+        while entries.contains_key(&next) {
+            *next = *next % (devices::CHANNEL_ID_STRIDE - 1) + 1;
         }
+        let id = *next;
+        *next = id % (devices::CHANNEL_ID_STRIDE - 1) + 1;
+        id
     }
+}
+
+/// Finds the channel a channel ID refers to
+fn lookup(channel_id: u32) -> Result<Arc<RwLock<Channel>>> {
+    let (device_id, local_id) = devices::split_channel_id(channel_id);
+    let device = devices::get(device_id).map_err(|_| PassthruError::ERR_INVALID_CHANNEL_ID)?;
+    device.channels.get(local_id).ok_or(PassthruError::ERR_INVALID_CHANNEL_ID)
+}
 
-    pub fn from_protocol(id: Protocol) -> Self {
-        match id {
-            Protocol::ISO15765 | Protocol::CAN => ChannelID::Can,
-            Protocol::ISO14230 | Protocol::ISO9141 => ChannelID::Kline,
-            Protocol::J1850PWM | Protocol::J1850VPW => ChannelID::J1850,
-            Protocol::SCI_A_ENGINE | Protocol::SCI_A_TRANS | Protocol::SCI_B_ENGINE | Protocol::SCI_B_TRANS => ChannelID::Sci
+/// Runs `op` with write access to a channel
+fn with_channel<T, F: FnOnce(&mut Channel) -> Result<T>>(channel_id: u32, op: F) -> Result<T> {
+    let channel = lookup(channel_id)?;
+    let guard = channel.write();
+    match guard {
+        Ok(mut c) => op(&mut c),
+        Err(e) => {
+            set_error_string(format!("Write guard failed: {}", e));
+            Err(PassthruError::ERR_FAILED)
         }
     }
 }
@@ -61,201 +179,246 @@ impl ChannelID {
 pub struct ChannelComm{}
 
 impl ChannelComm {
-    /// Attempts to create a new communication channel
+    /// Attempts to create a new communication channel, on the first free resource which can carry `protocol`
     /// # Returns
     /// Channel ID if operation was OK
-    pub fn create_channel(protocol: Protocol, baud_rate: u32, flags: u32) -> Result<u32> {
-        let protocol_id = ChannelID::from_protocol(protocol);
-        match protocol_id.get_channel().write() {
-            Ok(mut channel) => {
-                if channel.is_some() { // Already occupied!
-                    return Err(PassthruError::ERR_CHANNEL_IN_USE)
+    pub fn create_channel(device_id: u32, protocol: ProtocolId, baud_rate: u32, flags: u32) -> Result<u32> {
+        // No need to ask the adapter to open a channel it already said it cannot do,
+        // or that the manifest told the application it does not have
+        run_on_m2(device_id, |dev| {
+            dev.settings().check_protocol(protocol.protocol)?;
+            match dev.capabilities() {
+                Some(c) => {
+                    c.check_protocol(protocol.protocol)?;
+                    protocol.check_can_bus(c)
+                },
+                None => Ok(())
+            }
+        })?;
+        let device = devices::get(device_id)?;
+        // Resource and ID are reserved, so the table is not locked whilst the adapter opens the channel,
+        // and the other channels of the adapter keep working
+        let (local_id, resource) = match device.channels.entries.write() {
+            Ok(mut entries) => {
+                let resources = Resource::for_protocol(protocol);
+                if resources.is_empty() {
+                    set_error_string(format!("Protocol ID 0x{:04X} is not a CAN bus of the adapter", protocol.id));
+                    return Err(PassthruError::ERR_NOT_SUPPORTED);
                 }
-                Channel::new(protocol_id as u32, protocol, baud_rate, flags) // If ID, create a new channel
-                    .map(|chan| {
-                        // If channel creation OK, set it in the channel list
-                        let idx = chan.id;
-                        *channel = Some(chan);
-                        idx as u32 // Return the ID
-                    })
+                let resource = match resources.iter().find(|r| !entries.values().any(|e| e.resource == **r)) {
+                    Some(r) => *r,
+                    None => return Err(PassthruError::ERR_CHANNEL_IN_USE) // Already occupied!
+                };
+                let local_id = device.channels.alloc_id(&entries);
+                entries.insert(local_id, Entry { resource, channel: None });
+                (local_id, resource)
             }
             Err(e) => {
                 set_error_string(format!("Write guard failed: {}", e));
-                Err(PassthruError::ERR_FAILED)
+                return Err(PassthruError::ERR_FAILED);
+            }
+        };
+        let res = Channel::new(device_id, resource, protocol, baud_rate, flags);
+        let mut entries = device.channels.entries.write().unwrap();
+        match (res, entries.get_mut(&local_id)) {
+            (Ok(chan), Some(entry)) => {
+                entry.channel = Some(Arc::new(RwLock::new(chan)));
+                Ok(devices::channel_id(device_id, local_id))
+            },
+            // Adapter was closed whilst the channel was opening
+            (Ok(mut chan), None) => {
+                chan.stop_tx();
+                Err(PassthruError::ERR_DEVICE_NOT_CONNECTED)
+            },
+            (Err(e), _) => {
+                entries.remove(&local_id);
+                Err(e)
             }
         }
     }
 
-    pub fn force_destroy_all_channels() {
-        // This simply destroys all channels, only in the event that M2 is being force shutdown
-        // Do this by simply removing everything causing everything to be dropped
-        CAN_CHANNEL.write().unwrap().take().take();
-        KLINE_CHANNEL.write().unwrap().take().take();
-        J1850_CHANNEL.write().unwrap().take().take();
-        SCI_CHANNEL.write().unwrap().take().take();
-    }
-
     pub fn destroy_channel(channel_id: u32) -> Result<()> {
-        match ChannelID::from_u32(channel_id)?.get_channel().write() {
-            Ok(mut channel) => {
-                if let Some(c) = channel.take() {
-                    c.destroy()
-                } else {
-                    Err(PassthruError::ERR_INVALID_CHANNEL_ID)
-                }
-            }
-            Err(e) => {
-                set_error_string(format!("Write guard failed: {}", e));
-                Err(PassthruError::ERR_FAILED)
+        let (device_id, local_id) = devices::split_channel_id(channel_id);
+        let device = devices::get(device_id).map_err(|_| PassthruError::ERR_INVALID_CHANNEL_ID)?;
+        let entry = {
+            let mut entries = device.channels.entries.write().unwrap();
+            // One still being opened is not the application's to close yet
+            match entries.get(&local_id) {
+                Some(e) if e.channel.is_some() => entries.remove(&local_id),
+                _ => None
             }
+        };
+        match entry.and_then(|e| e.channel) {
+            Some(c) => {
+                device.periodic.clear_channel(channel_id);
+                let mut channel = c.write().unwrap();
+                // Nothing still queued can go out on the next channel opened on the resource
+                channel.stop_tx();
+                channel.destroy()
+            },
+            None => Err(PassthruError::ERR_INVALID_CHANNEL_ID)
         }
     }
+
+    /// Returns the Tx queue of a channel, which its Tx thread holds on to until it exits
+    #[cfg(test)]
+    pub fn tx_queue(channel_id: u32) -> Result<Weak<TxQueue>> {
+        Ok(Arc::downgrade(&lookup(channel_id)?.read().unwrap().tx_data))
+    }
+
+    /// Returns the adapter a channel is on, and the ID the firmware knows it by
+    pub fn firmware_channel(channel_id: u32) -> Result<(u32, u32)> {
+        let (device_id, _) = devices::split_channel_id(channel_id);
+        let channel = lookup(channel_id)?;
+        let fw_id = channel.read().unwrap().fw_id();
+        Ok((device_id, fw_id))
+    }
  
     pub fn create_channel_filter(channel_id: u32, filter_type: FilterType, mask_bytes: &[u8], pattern_bytes: &[u8], fc_bytes: &[u8]) -> Result<u32> {
-        match ChannelID::from_u32(channel_id)?.get_channel().write() {
-            Ok(mut channel) => {
-                if let Some(c) = channel.as_mut() {
-                    c.add_filter(filter_type, mask_bytes, pattern_bytes, fc_bytes)
-                } else {
-                    Err(PassthruError::ERR_INVALID_CHANNEL_ID)
-                }
-            }
+        with_channel(channel_id, |c| c.add_filter(filter_type, mask_bytes, pattern_bytes, fc_bytes))
+    }
+
+    /// Queues messages to send, in order. With a timeout of 0, returns once they are queued,
+    /// failing with ERR_BUFFER_FULL if they do not all fit. Otherwise waits up to `timeout_ms`
+    /// for them to be sent
+    /// # Returns
+    /// How many messages were queued (timeout of 0) or sent, and the error which stopped the rest
+    pub fn write_channel_data(channel_id: u32, msgs: &[PASSTHRU_MSG], timeout_ms: u32) -> (u32, Result<()>) {
+        let channel = match lookup(channel_id) {
+            Ok(c) => c,
+            Err(e) => return (0, Err(e))
+        };
+        // Checked up front, so the channel is not locked whilst waiting for room in the queue
+        let (queue, valid, invalid) = match channel.read() {
+            Ok(c) => {
+                let mut invalid = Ok(());
+                let valid = msgs.iter().position(|m| {
+                    invalid = c.check_tx_msg(m);
+                    invalid.is_err()
+                }).unwrap_or(msgs.len());
+                (c.tx_data.clone(), valid, invalid)
+            },
             Err(e) => {
-                set_error_string(format!("Write guard failed: {}", e));
-                Err(PassthruError::ERR_FAILED)
+                set_error_string(format!("Read guard failed: {}", e));
+                return (0, Err(PassthruError::ERR_FAILED));
+            }
+        };
+        let deadline = (timeout_ms != 0).then(|| Instant::now() + Duration::from_millis(timeout_ms as u64));
+        let write = deadline.map(|_| queue.track_write());
+        let mut queued = 0;
+        let mut res = invalid;
+        for msg in &msgs[..valid] {
+            if let Err(e) = queue.push(msg, write, deadline) {
+                res = Err(e);
+                break;
             }
+            queued += 1;
         }
+        match write.zip(deadline) {
+            Some((write, deadline)) => {
+                let (sent, sent_res) = queue.wait_sent(write, deadline);
+                (sent, sent_res.and(res))
+            },
+            None => (queued, res)
+        }
+    }
+
+    /// Starts sending `msg` every `interval_ms`, once it has been checked the channel can send it
+    pub fn start_periodic(channel_id: u32, msg: &PASSTHRU_MSG, interval_ms: u32) -> Result<u32> {
+        let channel = lookup(channel_id)?;
+        channel.read().unwrap().check_tx_msg(msg)?;
+        let (device_id, _) = devices::split_channel_id(channel_id);
+        devices::get(device_id)?.periodic.start(channel_id, msg, interval_ms)
+    }
+
+    pub fn stop_periodic(channel_id: u32, msg_id: u32) -> Result<()> {
+        lookup(channel_id)?;
+        let (device_id, _) = devices::split_channel_id(channel_id);
+        devices::get(device_id)?.periodic.stop(channel_id, msg_id)
     }
 
-    pub fn write_channel_data(channel_id: u32, msg: &PASSTHRU_MSG, require_response: bool) -> Result<()> {
-        match ChannelID::from_u32(channel_id)?.get_channel().read() {
-            Ok(channel) => {
-                if let Some(c) = channel.as_ref() {
-                    c.transmit_data(msg, require_response)
-                } else {
-                    Err(PassthruError::ERR_INVALID_CHANNEL_ID)
+    pub fn clear_periodic(channel_id: u32) -> PassthruError {
+        match lookup(channel_id) {
+            Ok(_) => {
+                let (device_id, _) = devices::split_channel_id(channel_id);
+                if let Ok(d) = devices::get(device_id) {
+                    d.periodic.clear_channel(channel_id);
                 }
-            }
-            Err(e) => {
-                set_error_string(format!("Write guard failed: {}", e));
-                Err(PassthruError::ERR_FAILED)
-            }
+                PassthruError::STATUS_NOERROR
+            },
+            Err(e) => e
         }
     }
 
     pub fn ioctl_get_cfg(channel_id: u32, param_name: IoctlParam) -> Result<u32> {
-        match ChannelID::from_u32(channel_id)?.get_channel().write() {
-            Ok(mut channel) => {
-                if let Some(c) = channel.as_mut() {
-                    c.ioctl_get_config(param_name)
-                } else {
-                    Err(PassthruError::ERR_INVALID_CHANNEL_ID)
-                }
-            }
-            Err(e) => {
-                set_error_string(format!("Write guard failed: {}", e));
-                Err(PassthruError::ERR_FAILED)
-            }
-        }
+        with_channel(channel_id, |c| c.ioctl_get_config(param_name))
     }
 
     pub fn ioctl_set_cfg(channel_id: u32, param_name: IoctlParam, value: u32) -> Result<()> {
-        match ChannelID::from_u32(channel_id)?.get_channel().write() {
-            Ok(mut channel) => {
-                if let Some(c) = channel.as_mut() {
-                    c.ioctl_set_config(param_name, value)
-                } else {
-                    Err(PassthruError::ERR_INVALID_CHANNEL_ID)
-                }
-            }
-            Err(e) => {
-                set_error_string(format!("Write guard failed: {}", e));
-                Err(PassthruError::ERR_FAILED)
-            }
-        }
+        with_channel(channel_id, |c| c.ioctl_set_config(param_name, value))
     }
 
     pub fn remove_filter(channel_id: u32, filter_id: u32) -> Result<()> {
-        match ChannelID::from_u32(channel_id)?.get_channel().write() {
-            Ok(mut channel) => {
-                if let Some(c) = channel.as_mut() {
-                    c.remove_filter(filter_id as usize)
-                } else {
-                    Err(PassthruError::ERR_INVALID_CHANNEL_ID)
-                }
-            }
-            Err(e) => {
-                set_error_string(format!("Write guard failed: {}", e));
-                Err(PassthruError::ERR_FAILED)
-            }
-        }
+        with_channel(channel_id, |c| c.remove_filter(filter_id as usize))
     }
 
     pub fn clear_all_filters(channel_id: u32) -> PassthruError {
-        match ChannelID::from_u32(channel_id) {
-            Ok(c) => match c.get_channel().write().unwrap().as_mut() {
-                Some(c) => c.remove_all_filters(),
-                None => PassthruError::ERR_INVALID_CHANNEL_ID
-            },
-            Err(e) => e
-        }
+        with_channel(channel_id, |c| Ok(c.remove_all_filters())).unwrap_or_else(|e| e)
     }
 
     pub fn clear_rx_buffer(channel_id: u32) -> PassthruError {
-        match ChannelID::from_u32(channel_id) {
-            Ok(c) => match c.get_channel().write().unwrap().as_mut() {
-                Some(c) => c.clear_rx_buffer(),
-                None => PassthruError::ERR_INVALID_CHANNEL_ID
-            },
+        match lookup(channel_id) {
+            Ok(c) => c.read().unwrap().clear_rx_buffer(),
             Err(e) => e
         }
     }
 
     pub fn clear_tx_buffer(channel_id: u32) -> PassthruError {
-        match ChannelID::from_u32(channel_id) {
-            Ok(c) => match c.get_channel().write().unwrap().as_mut() {
-                Some(c) => c.clear_tx_buffer(),
-                None => PassthruError::ERR_INVALID_CHANNEL_ID
-            },
+        match lookup(channel_id) {
+            Ok(c) => c.read().unwrap().clear_tx_buffer(),
             Err(e) => e
         }
     }
 
-    pub fn read_channel_data(channel_id: u32) -> Result<Option<PASSTHRU_MSG>> {
-        let channel = ChannelID::from_u32(channel_id)?.get_channel();
-
-        if let Some(c) = channel.read().unwrap().as_ref() {
-            if c.rx_available() == 0 {
-                return Ok(None)
-            }
-        } else {
-            return Err(PassthruError::ERR_INVALID_CHANNEL_ID)
-        }
-
-        match channel.write() {
-            Ok(mut c) => {
-                Ok(c.as_mut().unwrap().pop_rx_queue())
-            },
+    /// Reads up to `max` received messages. With a timeout of 0, returns what has been received
+    /// already, otherwise waits up to `timeout_ms` for `max` of them
+    /// # Returns
+    /// The messages, and whether any were dropped since the last read as the Rx queue was full
+    pub fn read_channel_data(channel_id: u32, max: usize, timeout_ms: u32) -> Result<(Vec<PASSTHRU_MSG>, bool)> {
+        let channel = lookup(channel_id)?;
+        // Not locked whilst waiting, so the receiver thread can add the messages being waited for
+        let queue = match channel.read() {
+            Ok(c) => c.rx_data.clone(),
             Err(e) => {
-                set_error_string(format!("Write guard failed: {}", e));
-                Err(PassthruError::ERR_FAILED)
+                set_error_string(format!("Read guard failed: {}", e));
+                return Err(PassthruError::ERR_FAILED);
             }
-        }
+        };
+        let deadline = (timeout_ms != 0).then(|| Instant::now() + Duration::from_millis(timeout_ms as u64));
+        Ok(queue.read(max, deadline))
     }
 
-    /// Used by the receiver thread running on the M2 to write data to our Rx buffer
-    pub fn receive_channel_data(msg: &CommMsg) {
-        if let Ok(c) = ChannelID::from_u32(msg.args[0] as u32) {
-            match c.get_channel().write() {
-                Ok(mut wg) => {
-                    if let Some(channel) = wg.as_mut() {
-                        let tx_flags = LittleEndian::read_u32(&msg.args[1..5]);
-                        let data = &msg.args[5..];
-                        channel.on_receive_data(tx_flags, data)
-                    }
+    /// Messages dropped as the Rx queue was full, since the channel was connected,
+    /// and since PassThruReadMsgs last reported the overflow
+    pub fn rx_dropped(channel_id: u32) -> Result<(u32, u32)> {
+        Ok(lookup(channel_id)?.read().unwrap().rx_data.dropped())
+    }
+
+    /// Used by the receiver thread of an adapter's connection to write data to our Rx buffer
+    pub fn receive_channel_data(device_id: u32, msg: &CommMsg) {
+        let device = match devices::get(device_id) {
+            Ok(d) => d,
+            Err(_) => return // Adapter is being closed
+        };
+        if let Some(c) = device.channels.by_fw_id(msg.args[0] as u32) {
+            match c.read() {
+                Ok(channel) => {
+                    let tx_flags = LittleEndian::read_u32(&msg.args[1..5]);
+                    let data = &msg.args[5..];
+                    channel.on_receive_data(tx_flags, data)
                 },
                 Err(_) => {
-                    log_warn(format!("Error sending data to channel {} - Write guard failed", msg.args[0]))
+                    log_warn(format!("Error sending data to channel {} - Read guard failed", msg.args[0]))
                 }
             }
         }
@@ -263,21 +426,74 @@ impl ChannelComm {
 }
 
 
-const MAX_QUEUE_MSGS: usize = 500;
-/// J2534 API Channel
+/// Filter set on a channel, kept so it can be set again if the adapter reconnects
 #[derive(Debug, Clone)]
+struct Filter {
+    filter_type: FilterType,
+    mask: Vec<u8>,
+    pattern: Vec<u8>,
+    flow_control: Vec<u8>,
+}
+
+/// J2534 API Channel
+#[derive(Debug)]
 struct Channel {
-    id: u32,
+    /// Adapter the channel is on
+    device_id: u32,
+    resource: Resource,
+    /// Protocol ID the application connected with
+    protocol_id: u32,
     protocol: Protocol,
     baud_rate: u32,
     flags: u32,
-    filters: [u8; MAX_FILTERS_PER_CHANNEL],
-    tx_data: VecDeque<PASSTHRU_MSG>, // 1000 Tx messages (~4MB)
-    rx_data: VecDeque<PASSTHRU_MSG>, // 1000 Rx messages (~4MB)
+    filters: [Option<Filter>; MAX_FILTERS_PER_CHANNEL],
+    /// Config params set by the application, in the order they were set
+    config: Vec<(IoctlParam, u32)>,
+    tx_data: Arc<TxQueue>, // 1000 Tx messages (~4MB)
+    /// Sends what is in tx_data
+    tx_thread: Option<JoinHandle<()>>,
+    /// Holds up to MAX_QUEUE_MSGS of the config
+    rx_data: Arc<RxQueue>,
+    /// LOOPBACK config param, for protocols where the driver echoes sent messages rather than the adapter
+    loopback: Arc<AtomicBool>,
+    /// Checks what the adapter sends, before it goes in rx_data
+    rx_indications: RxIndications,
 }
 
 impl Channel {
-    pub fn new(id: u32, protocol: Protocol, baud_rate: u32, flags: u32) -> Result<Self> {
+    pub fn new(device_id: u32, resource: Resource, protocol: ProtocolId, baud_rate: u32, flags: u32) -> Result<Self> {
+        let max_rx_msgs = run_on_m2(device_id, |dev| {
+            Self::open_on(dev, resource.fw_id(), protocol.protocol, baud_rate, flags)?;
+            Ok(dev.settings().max_queue_msgs)
+        })?;
+        let tx_data = Arc::new(TxQueue::new(MAX_TX_MSGS));
+        let rx_data = Arc::new(RxQueue::new(max_rx_msgs));
+        let loopback = Arc::new(AtomicBool::new(false));
+        let tx_queue = tx_data.clone();
+        let indications = TxIndications::new(protocol.protocol, rx_data.clone(), loopback.clone());
+        let fw_id = resource.fw_id();
+        Ok(Self{
+            device_id,
+            resource,
+            protocol_id: protocol.id,
+            protocol: protocol.protocol,
+            baud_rate,
+            flags,
+            filters: Default::default(),
+            config: Vec::new(),
+            tx_data,
+            tx_thread: Some(spawn(move || Self::drain_tx(device_id, fw_id, tx_queue, indications))),
+            rx_data,
+            loopback,
+            rx_indications: RxIndications::new(protocol.protocol),
+        })
+    }
+
+    fn fw_id(&self) -> u32 {
+        self.resource.fw_id()
+    }
+
+    fn open_on(dev: &MacchinaM2, id: u32, protocol: Protocol, baud_rate: u32, flags: u32) -> Result<()> {
         // First arg id (u32)
         // Second arg protocol (RAW)
         // Third arg baud rate
@@ -288,36 +504,54 @@ impl Channel {
         }
         log_debug(format!("Requesting channel open. ID: {}, Protocol: {:?}, baud: {}, flags: 0x{:04X}", id, protocol, baud_rate, flags));
         let mut msg = CommMsg::new_with_args(MsgType::OpenChannel, dst.as_mut_slice());
-        run_on_m2(|dev |{
-            match dev.write_and_read_ptcmd(&mut msg, 100) {
-                M2Resp::Ok(_) => {
-                    log_debug_str("M2 opened channel!");
-                    Ok(Self{
-                        id, 
-                        protocol, 
-                        baud_rate, 
-                        flags, 
-                        filters: [0x00; MAX_FILTERS_PER_CHANNEL], 
-                        tx_data: VecDeque::new(), 
-                        rx_data: VecDeque::new(),
-                    })
-                },
-                M2Resp::Err{status, string} => {
-                    log_error(format!("M2 failed to open channel {} (Status {:?}): {}", id, status, string));
-                    set_error_string(string);
-                    Err(status)
-                }
+        match dev.write_and_read_ptcmd(&mut msg, dev.settings().timeouts.quick_ms) {
+            M2Resp::Ok(_) => {
+                log_debug_str("M2 opened channel!");
+                Ok(())
+            },
+            M2Resp::Err{status, string} => {
+                log_error(format!("M2 failed to open channel {} (Status {:?}): {}", id, status, string));
+                set_error_string(string);
+                Err(status)
             }
-        })
+        }
     }
 
-    pub fn add_filter(&mut self, filter_type: FilterType, mask_bytes: &[u8], pattern_bytes: &[u8], fc_bytes: &[u8]) -> Result<u32> {
-        let free_id = self.filters.iter().enumerate().find(| (_, v) | {**v == 0}).map_or(99, |x| x.0);
-
-        if free_id == 99 {
-            return Err(PassthruError::ERR_EXCEEDED_LIMIT)
+    /// Opens the channel again on a newly connected adapter, along with its filters and config
+    pub fn restore(&self, dev: &MacchinaM2) -> Result<()> {
+        log_info(format!("Restoring channel {} after reconnect", self.fw_id()));
+        Self::open_on(dev, self.fw_id(), self.protocol, self.baud_rate, self.flags)?;
+        for (idx, filter) in self.filters.iter().enumerate() {
+            if let Some(f) = filter {
+                self.set_filter_on(dev, idx, f)?;
+            }
+        }
+        for (pname, pvalue) in self.config.iter() {
+            self.set_config_on(dev, *pname, *pvalue)?;
         }
+        Ok(())
+    }
+
+    pub fn add_filter(&mut self, filter_type: FilterType, mask_bytes: &[u8], pattern_bytes: &[u8], fc_bytes: &[u8]) -> Result<u32> {
+        // Each filter takes a mailbox, and the adapter may have fewer than J2534 allows
+        let limit = run_on_m2(self.device_id, |dev| Ok(dev.capabilities().map_or(MAX_FILTERS_PER_CHANNEL, |c| c.max_filters as usize)))?;
+        let free_id = match self.filters.iter().take(limit).position(|f| f.is_none()) {
+            Some(id) => id,
+            None => return Err(PassthruError::ERR_EXCEEDED_LIMIT)
+        };
+        let filter = Filter {
+            filter_type,
+            mask: mask_bytes.to_vec(),
+            pattern: pattern_bytes.to_vec(),
+            flow_control: fc_bytes.to_vec()
+        };
+        run_on_m2(self.device_id, |dev| self.set_filter_on(dev, free_id, &filter))?;
+        self.filters[free_id] = Some(filter); // Mark it as used
+        Ok(free_id as u32)
+    }
 
+    fn set_filter_on(&self, dev: &MacchinaM2, free_id: usize, filter: &Filter) -> Result<()> {
+        let (filter_type, mask_bytes, pattern_bytes, fc_bytes) = (filter.filter_type, &filter.mask, &filter.pattern, &filter.flow_control);
         // Mask and pattern MUST be present, Flow control is only if FilterType is ISO15765
         // Create our args
         // First arg: channel id (u32)
@@ -327,49 +561,46 @@ impl Channel {
         // fifth arg: pattern size (u32)
         // sixth arg: flow control size (Can be 0) (u32)
         let mut dst: Vec<u8> = Vec::new();
-        for arg in [self.id, free_id as u32, filter_type as u32, mask_bytes.len() as u32, pattern_bytes.len() as u32, fc_bytes.len() as u32].iter() {
+        for arg in [self.fw_id(), free_id as u32, filter_type as u32, mask_bytes.len() as u32, pattern_bytes.len() as u32, fc_bytes.len() as u32].iter() {
             dst.write_u32::<LittleEndian>(*arg).unwrap();
         }
         dst.extend_from_slice(mask_bytes);
         dst.extend_from_slice(pattern_bytes);
         dst.extend_from_slice(fc_bytes);
-        log_debug(format!("Setting {} (ID: {}) on channel {}. Mask: {:02X?}, Pattern: {:02X?}, FlowControl: {:02X?}", filter_type, self.id, free_id, mask_bytes, pattern_bytes, fc_bytes));
+        log_debug(format!("Setting {} (ID: {}) on channel {}. Mask: {:02X?}, Pattern: {:02X?}, FlowControl: {:02X?}", filter_type, self.fw_id(), free_id, mask_bytes, pattern_bytes, fc_bytes));
         let mut msg = CommMsg::new_with_args(MsgType::SetChannelFilter, dst.as_mut_slice());
-        run_on_m2(|dev |{
-            match dev.write_and_read_ptcmd(&mut msg, 250) {
-                M2Resp::Ok(_) => {
-                    log_debug(format!("M2 set filter {} on channel {}!", free_id, self.id));
-                    self.filters[free_id] = 1; // Mark it as used
-                    Ok(free_id as u32)
-                },
-                M2Resp::Err{status, string} => {
-                    log_error(format!("M2 failed to set filter {} on channel {} (Status {:?}): {}", free_id, self.id, status, string));
-                    set_error_string(string);
-                    Err(status)
-                }
+        match dev.write_and_read_ptcmd(&mut msg, dev.settings().timeouts.command_ms) {
+            M2Resp::Ok(_) => {
+                log_debug(format!("M2 set filter {} on channel {}!", free_id, self.fw_id()));
+                Ok(())
+            },
+            M2Resp::Err{status, string} => {
+                log_error(format!("M2 failed to set filter {} on channel {} (Status {:?}): {}", free_id, self.fw_id(), status, string));
+                set_error_string(string);
+                Err(status)
             }
-        })
+        }
     }
 
     pub fn remove_filter(&mut self, id: usize) -> Result<()> {
-        if self.filters[id] == 0 {
+        if !self.filters.get(id).is_some_and(|f| f.is_some()) {
             return Err(PassthruError::ERR_INVALID_MSG_ID)
         }
         let mut dst: Vec<u8> = Vec::new();
-        for arg in [self.id, id as u32].iter() {
+        for arg in [self.fw_id(), id as u32].iter() {
             dst.write_u32::<LittleEndian>(*arg).unwrap();
         }
-        log_debug(format!("Removing channel {} filter {}", self.id, id));
+        log_debug(format!("Removing channel {} filter {}", self.fw_id(), id));
         let mut msg = CommMsg::new_with_args(MsgType::RemoveChannelFilter, dst.as_mut_slice());
-        run_on_m2(|dev |{
-            match dev.write_and_read_ptcmd(&mut msg, 100) {
+        run_on_m2(self.device_id, |dev |{
+            match dev.write_and_read_ptcmd(&mut msg, dev.settings().timeouts.quick_ms) {
                 M2Resp::Ok(_) => {
                     log_debug_str("M2 closed filter OK!");
-                    self.filters[id] = 0; // Mark it as used
+                    self.filters[id] = None; // Mark it as free
                     Ok(())
                 },
                 M2Resp::Err{status, string} => {
-                    log_error(format!("M2 failed to close filter {} on channel {} (Status {:?}): {}", id, self.id, status, string));
+                    log_error(format!("M2 failed to close filter {} on channel {} (Status {:?}): {}", id, self.fw_id(), status, string));
                     set_error_string(string);
                     Err(status)
                 }
@@ -378,9 +609,9 @@ impl Channel {
     }
 
     pub fn remove_all_filters(&mut self) -> PassthruError {
-        let populated_list = self.filters.clone();
+        let populated_list: Vec<bool> = self.filters.iter().map(|f| f.is_some()).collect();
         for (idx, filter) in populated_list.iter().enumerate() {
-            if *filter != 0 { // Filter is populated
+            if *filter { // Filter is populated
                 if let Err(e) = self.remove_filter(idx) {
                     return e
                 }
@@ -390,117 +621,161 @@ impl Channel {
     }
 
     pub fn destroy(&self) -> Result<()> {
-        log_debug(format!("Requesting channel destroy. ID: {}", self.id));
+        log_debug(format!("Requesting channel destroy. ID: {}", self.fw_id()));
         let mut dst: Vec<u8> = Vec::new();
-        dst.write_u32::<LittleEndian>(self.id).unwrap();
+        dst.write_u32::<LittleEndian>(self.fw_id()).unwrap();
         let mut msg = CommMsg::new_with_args(MsgType::CloseChannel, dst.as_mut_slice());
-        run_on_m2(|dev |{
-            match dev.write_and_read_ptcmd(&mut msg, 250) {
+        run_on_m2(self.device_id, |dev |{
+            match dev.write_and_read_ptcmd(&mut msg, dev.settings().timeouts.command_ms) {
                 M2Resp::Ok(_) => Ok(()),
                 M2Resp::Err{status, string} => {
-                    log_error(format!("M2 failed to respond to close channel {} (Status {:?}): {}, assuming close was OK", self.id, status, string));
+                    log_error(format!("M2 failed to respond to close channel {} (Status {:?}): {}, assuming close was OK", self.fw_id(), status, string));
                     Ok(())
                 }
             }
         })
     }
 
-    pub fn transmit_data(&self, ptmsg: &PASSTHRU_MSG, require_response: bool) -> Result<()> {
-        if ptmsg.protocol_id != self.protocol as u32 {
+    /// Checks a message is one the channel can send
+    pub fn check_tx_msg(&self, ptmsg: &PASSTHRU_MSG) -> Result<()> {
+        if ptmsg.protocol_id != self.protocol_id {
             return Err(PassthruError::ERR_MSG_PROTOCOL_ID);
         }
+        let max_payload = run_on_m2(self.device_id, |dev| Ok(dev.capabilities().map_or(u32::MAX, |c| c.max_payload as u32)))?;
+        if ptmsg.data_size > max_payload {
+            set_error_string(format!("Message is {} bytes, the adapter can send at most {}", ptmsg.data_size, max_payload));
+            return Err(PassthruError::ERR_INVALID_MSG);
+        }
+        Ok(())
+    }
+
+    /// Sends the messages queued on a channel, in order, each once the last has been confirmed.
+    /// As soon as one is, its TX_DONE and loopback message go in the Rx queue.
+    /// Runs on its own thread until the queue is stopped
+    fn drain_tx(device_id: u32, fw_id: u32, queue: Arc<TxQueue>, indications: TxIndications) {
+        while let Some((tag, ptmsg)) = queue.take() {
+            let res = Self::transmit_data(device_id, fw_id, &ptmsg);
+            if res.is_ok() {
+                indications.sent(fw_id, &ptmsg);
+            }
+            // Indicated first, so a write which waited for the send can read them straight away
+            queue.complete(tag, res);
+        }
+    }
+
+    /// The driver echoes sent messages itself on CAN and ISO15765, so the echo is only made once
+    /// the whole message has gone. Other protocols leave it to the adapter
+    fn driver_loopback(&self) -> bool {
+        matches!(self.protocol, Protocol::CAN | Protocol::ISO15765)
+    }
+
+    fn transmit_data(device_id: u32, fw_id: u32, ptmsg: &PASSTHRU_MSG) -> Result<()> {
         // Build Tx message
         let mut dst: Vec<u8> = Vec::new();
-        for arg in [self.id, ptmsg.tx_flags].iter() {
+        for arg in [fw_id, ptmsg.tx_flags].iter() {
             dst.write_u32::<LittleEndian>(*arg).unwrap();
         }
         dst.extend_from_slice(&ptmsg.data[0..ptmsg.data_size as usize]);
         let mut msg = CommMsg::new_with_args(MsgType::TransmitChannelData, dst.as_mut_slice());
-        log_debug(format!("Channel {} writing message: {}. Response required?: {}", self.id, ptmsg, require_response));
-        run_on_m2(|dev| {
-            if require_response {
-                match dev.write_and_read_ptcmd(&mut msg, 1000) {
-                    M2Resp::Ok(_) => Ok(()),
-                    M2Resp::Err{status, string}  => {
-                        log_error(format!("M2 failed to write data to channel {} (Status {:?}): {}", self.id, status, string));
-                        set_error_string(string);
-                        Err(status)
-                    }
+        log_debug(format!("Channel {} writing message: {}", fw_id, ptmsg));
+        run_on_m2(device_id, |dev| {
+            match dev.write_and_read_ptcmd(&mut msg, dev.settings().timeouts.tx_ms) {
+                M2Resp::Ok(_) => Ok(()),
+                M2Resp::Err{status, string}  => {
+                    log_error(format!("M2 failed to write data to channel {} (Status {:?}): {}", fw_id, status, string));
+                    set_error_string(string);
+                    Err(status)
                 }
-            } else {
-                dev.write_comm_struct(msg)
             }
         })
     }
 
-    pub fn pop_rx_queue(&mut self) -> Option<PASSTHRU_MSG> {
-        self.rx_data.pop_front()
-    }
-
-    pub fn rx_available(&self) -> usize {
-        self.rx_data.len()
+    /// Drops anything still queued to send, and waits for the message being sent, if any
+    fn stop_tx(&mut self) {
+        self.tx_data.stop();
+        if let Some(t) = self.tx_thread.take() {
+            let _ = t.join();
+        }
     }
 
-    pub fn on_receive_data(&mut self, rx_status: u32, data: &[u8]) {
-        if self.rx_data.len() < MAX_QUEUE_MSGS {
+    pub fn on_receive_data(&self, rx_status: u32, data: &[u8]) {
+        if let Some((rx_status, data)) = self.rx_indications.check(rx_status, data) {
             let mut msg = PASSTHRU_MSG {
                 data_size: data.len() as u32,
                 extra_data_size: 0,
                 rx_status,
-                protocol_id: self.protocol as u32,
-                timestamp: std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_micros() as u32,
+                protocol_id: self.protocol_id,
                 ..Default::default()
             };
             msg.data[..data.len()].copy_from_slice(data);
-            //log_debug(format!("Channel {} buffering message. RxStatus: {:08X}, data: {:02X?}", self.id, rx_status, &data));
-            self.rx_data.push_back(msg);
-        } else {
-            // Data is lost if queue is too big!
-            log_warn(format!("Rx queue in channel {} is full. Data has been lost!", self.id));
+            //log_debug(format!("Channel {} buffering message. RxStatus: {:08X}, data: {:02X?}", self.fw_id(), rx_status, &data));
+            if let Err(1) = self.rx_data.push(msg) {
+                // Data is lost if queue is too big! Only logged once, until the application reads
+                log_warn(format!("Rx queue in channel {} is full. Data has been lost!", self.fw_id()));
+            }
         }
     }
 
 
-    pub fn clear_rx_buffer(&mut self) -> PassthruError {
+    pub fn clear_rx_buffer(&self) -> PassthruError {
         self.rx_data.clear();
+        self.rx_indications.clear();
         PassthruError::STATUS_NOERROR
     }
 
-    pub fn clear_tx_buffer(&mut self) -> PassthruError {
-        // nothing to do
+    pub fn clear_tx_buffer(&self) -> PassthruError {
+        if self.tx_data.clear(PassthruError::ERR_FAILED) {
+            set_error_string("Tx buffer was cleared before every message was sent".into());
+        }
         PassthruError::STATUS_NOERROR
     }
 
     pub fn ioctl_set_config(&mut self, pname: IoctlParam, pvalue: u32) -> Result<()> {
+        if pname == IoctlParam::LOOPBACK && self.driver_loopback() {
+            if pvalue > 1 {
+                set_error_string(format!("LOOPBACK must be 0 or 1, not {}", pvalue));
+                return Err(PassthruError::ERR_INVALID_IOCTL_VALUE);
+            }
+            self.loopback.store(pvalue == 1, Ordering::Relaxed);
+            return Ok(());
+        }
+        run_on_m2(self.device_id, |dev| self.set_config_on(dev, pname, pvalue))?;
+        self.config.retain(|(p, _)| *p != pname);
+        self.config.push((pname, pvalue));
+        Ok(())
+    }
+
+    fn set_config_on(&self, dev: &MacchinaM2, pname: IoctlParam, pvalue: u32) -> Result<()> {
         let mut dst: Vec<u8> = Vec::new();
-        dst.push(self.id as u8);
+        dst.push(self.fw_id() as u8);
         for arg in [pname as u32, pvalue].iter() {
             dst.write_u32::<LittleEndian>(*arg).unwrap();
         }
         let mut msg = CommMsg::new_with_args(MsgType::IoctlSet, dst.as_mut_slice());
-        log_debug(format!("Channel {} writing IOCTL Param: {}. Param value: {}", self.id, pname, pvalue));
-        run_on_m2(|dev| {
-            match dev.write_and_read_ptcmd(&mut msg, 100) {
-                M2Resp::Ok(_) => Ok(()),
-                M2Resp::Err{status, string}  => {
-                    log_error(format!("M2 failed to set IOCTL {} (Status {:?}): {}", self.id, status, string));
-                    set_error_string(string);
-                    Err(status)
-                }
+        log_debug(format!("Channel {} writing IOCTL Param: {}. Param value: {}", self.fw_id(), pname, pvalue));
+        match dev.write_and_read_ptcmd(&mut msg, dev.settings().timeouts.quick_ms) {
+            M2Resp::Ok(_) => Ok(()),
+            M2Resp::Err{status, string}  => {
+                log_error(format!("M2 failed to set IOCTL {} (Status {:?}): {}", self.fw_id(), status, string));
+                set_error_string(string);
+                Err(status)
             }
-        })
+        }
     }
 
     pub fn ioctl_get_config(&mut self, pname: IoctlParam) -> Result<u32> {
+        if pname == IoctlParam::LOOPBACK && self.driver_loopback() {
+            return Ok(self.loopback.load(Ordering::Relaxed) as u32);
+        }
         let mut dst: Vec<u8> = Vec::new();
-        dst.push(self.id as u8);
+        dst.push(self.fw_id() as u8);
         for arg in [pname as u32].iter() {
             dst.write_u32::<LittleEndian>(*arg).unwrap();
         }
         let mut msg = CommMsg::new_with_args(MsgType::IoctlGet, dst.as_mut_slice());
-        log_debug(format!("Channel {} requesting IOCTL Param: {}", self.id, pname));
-        run_on_m2(|dev| {
-            match dev.write_and_read_ptcmd(&mut msg, 100) {
+        log_debug(format!("Channel {} requesting IOCTL Param: {}", self.fw_id(), pname));
+        run_on_m2(self.device_id, |dev| {
+            match dev.write_and_read_ptcmd(&mut msg, dev.settings().timeouts.quick_ms) {
                 M2Resp::Ok(v) => {
                     if v.len() != 4 {
                         log_error(format!("M2 responded to get IOCTL {}, but response was an invalid length!", pname));
@@ -519,4 +794,11 @@ impl Channel {
             }
         })
     }
+}
+
+impl Drop for Channel {
+    /// Lets the Tx thread exit, without waiting for a message it is sending
+    fn drop(&mut self) {
+        self.tx_data.stop();
+    }
 }
\ No newline at end of file
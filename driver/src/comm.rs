@@ -1,33 +1,23 @@
 use channels::ChannelComm;
 use logger::{log_debug, log_error, log_warn};
-use serialport::*;
-use std::{io::{Error, ErrorKind, Read, Write}, sync::{Mutex}, convert::TryFrom};
+use std::{io::{ErrorKind, Read, Write}, sync::Mutex, convert::TryFrom};
 use std::sync::{Arc, atomic::AtomicBool, atomic::Ordering};
-use std::sync::mpsc::{channel, Sender, Receiver};
-use std::thread::spawn;
-use std::sync::RwLock;
-use lazy_static::lazy_static;
-use crate::{channels, logger::{self, log_error_str, log_m2_msg}};
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
+use std::thread::{spawn, JoinHandle};
+use std::time::Duration;
+use crate::{channels, devices, logger::{self, log_error_str, log_info_str, log_m2_msg}};
+use crate::transport::{self, Transport};
+use crate::frame::FrameDecoder;
+use crate::pending::PendingRequests;
+use crate::capabilities::Capabilities;
+use crate::device_info::DeviceInfo;
+use crate::selector::Selector;
+use crate::config::Settings;
 use j2534_rust::{PassthruError};
 use crate::passthru_drv::set_error_string;
-use byteorder::{ByteOrder, WriteBytesExt, LittleEndian};
 
-#[cfg(windows)]
-use winreg::{RegKey, RegValue, enums::HKEY_LOCAL_MACHINE};
-
-lazy_static! {
-    pub static ref M2: RwLock<Option<MacchinaM2>> = RwLock::new(None);
-    static ref MSG_ID: Arc<Mutex<u8>> = Arc::new(Mutex::new(1));
-}
-
-fn get_id() -> u8 {
-    let mut x = MSG_ID.lock().unwrap();
-    *x += 1;
-    if *x >= 100 {
-        *x = 1
-    }
-    *x
-}
+#[cfg(all(windows, not(test)))]
+use winreg::{RegKey, enums::HKEY_LOCAL_MACHINE};
 
 #[derive(Debug, Clone)]
 pub enum M2Resp {
@@ -36,9 +26,67 @@ pub enum M2Resp {
 }
 
 pub struct MacchinaM2 {
+    model: DeviceModel,
     is_running: Arc<AtomicBool>,
+    /// False once the link to the adapter is lost
+    is_connected: Arc<AtomicBool>,
+    /// Why the link was lost
+    link_error: Arc<Mutex<String>>,
+    /// False whilst the adapter is not answering keepalives
+    is_responsive: Arc<AtomicBool>,
     tx_send_queue: Sender<CommMsg>,
-    rx_recv_queue: Vec<Receiver<CommMsg>>
+    pending: Arc<PendingRequests>,
+    /// None until `query_capabilities` is called, in which case nothing is checked up front
+    capabilities: Option<Capabilities>,
+    settings: Settings,
+    /// Reader, writer, channel dispatch and watchdog threads, joined by `stop`
+    threads: Vec<JoinHandle<()>>
+}
+
+/// Opens the link to the adapter again, after it was lost
+pub type Reopen = Arc<dyn Fn() -> Result<Box<dyn Transport>> + Send + Sync>;
+
+/// How often to try reopening the link whilst the adapter is unplugged
+const RECONNECT_INTERVAL_MS: u64 = 250;
+
+/// Longest a worker thread goes without checking if it should exit
+const THREAD_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Sleeps for `duration`, unless `is_running` is cleared first
+fn sleep_while_running(is_running: &AtomicBool, duration: Duration) {
+    let start = std::time::Instant::now();
+    while is_running.load(Ordering::Relaxed) {
+        let left = duration.saturating_sub(start.elapsed());
+        if left.is_zero() {
+            return;
+        }
+        std::thread::sleep(std::cmp::min(left, THREAD_POLL_INTERVAL));
+    }
+}
+
+/// Keepalive sent to the adapter, to notice if the firmware hangs whilst the port stays open
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Heartbeat {
+    /// Time between keepalives, which is also how long each one waits for an answer. 0 disables them
+    pub interval_ms: u64,
+    /// Keepalives missed in a row before the adapter is treated as hung
+    pub max_misses: u32,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self { interval_ms: 1000, max_misses: 3 }
+    }
+}
+
+/// State of the link to the adapter, as reported to the application
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LinkStatus {
+    Ok = 0,
+    /// Port is open, but the firmware stopped answering keepalives
+    NotResponding = 1,
+    /// Port was lost, waiting for the adapter to be plugged back in
+    Disconnected = 2,
 }
 
 unsafe impl Send for MacchinaM2{}
@@ -46,48 +94,183 @@ unsafe impl Sync for MacchinaM2{}
 
 type Result<T> = std::io::Result<T>;
 
-#[cfg(all(feature = "M2", unix))]
-const JSON_PATH : &str = "~/.passthru/macchina_m2.json";
-#[cfg(all(feature = "A0", unix))]
-const JSON_PATH: &str = "~/.passthru/macchina_a0.json";
-
-#[cfg(all(feature = "M2", windows))]
-const REG_PATH : &str = "SOFTWARE\\WOW6432Node\\PassThruSupport.04.04\\Macchina-Passthru-M2";
-#[cfg(all(feature = "A0", windows))]
-const REG_PATH: &str = "SOFTWARE\\WOW6432Node\\PassThruSupport.04.04\\Macchina-Passthru-A0";
-
-#[cfg(unix)]
-fn get_comm_port() -> Option<String> {
-    if let Ok(content) = std::fs::read_to_string(shellexpand::tilde(JSON_PATH).to_string()) {
-        return match serde_json::from_str::<serde_json::Value>(content.as_str()) {
-            Ok(v) => v["COM-PORT"].as_str().map(String::from),
-            Err(_) => None
+/// Adapter hardware on the other end of the link
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DeviceModel {
+    /// Macchina M2. Native USB. Older firmware pads every message to a full COMM_MSG_SIZE block
+    M2,
+    /// Macchina A0. ESP32 behind a UART bridge at 2Mbps without flow control. Messages are length prefixed
+    A0,
+}
+
+impl DeviceModel {
+    /// Parses the DEVICE attribute of the driver config
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_uppercase().as_str() {
+            "M2" => Some(DeviceModel::M2),
+            "A0" => Some(DeviceModel::A0),
+            _ => None
+        }
+    }
+
+    /// Works out the model from the firmware version string. These end in either _M2 or _A0
+    pub fn from_fw_version(version: &str) -> Option<Self> {
+        if version.ends_with("_M2") {
+            Some(DeviceModel::M2)
+        } else if version.ends_with("_A0") {
+            Some(DeviceModel::A0)
+        } else {
+            None
         }
     }
-    None
 }
 
-#[cfg(windows)]
-fn get_comm_port() -> Option<String> {
-    if let Ok(reg) = RegKey::predef(HKEY_LOCAL_MACHINE).open_subkey(REG_PATH) {
-        logger::log_info_str("Found regkey");
-        return match reg.get_value("COM-PORT") {
-            Ok(s) => {
-                logger::log_info(format!("Com port is {}", s));
-                Some(s)
-            },
-            Err(_) => None
+/// Where to find the adapter, as read from the JSON file or registry
+#[derive(Debug, Clone)]
+pub struct DeviceConfig {
+    pub port: String,
+    /// None if the config does not say, in which case the adapter is probed
+    pub model: Option<DeviceModel>,
+    pub settings: Settings,
+}
+
+/// JSON configs, in the folder given by `json_dir`. Also used by the tests on Windows, in place of the registry
+#[cfg(any(unix, test))]
+const JSON_FILES: [&str; 2] = ["macchina_m2.json", "macchina_a0.json"];
+
+#[cfg(all(windows, not(test)))]
+const REG_PATHS: [&str; 2] = [
+    "SOFTWARE\\WOW6432Node\\PassThruSupport.04.04\\Macchina-Passthru-M2",
+    "SOFTWARE\\WOW6432Node\\PassThruSupport.04.04\\Macchina-Passthru-A0"
+];
+
+/// Picks which config to use. The same driver binary is installed for every adapter,
+/// so prefer the first one whose port actually exists, and that is not already open.
+/// If every adapter is open, the first is picked, which fails to open as it is in use
+fn pick_config(configs: &[DeviceConfig], port_exists: &dyn Fn(&str) -> bool, in_use: &dyn Fn(&str) -> bool) -> Option<DeviceConfig> {
+    let free: Vec<&DeviceConfig> = configs.iter().filter(|c| !in_use(&c.port)).collect();
+    free.iter().find(|c| port_exists(&c.port)).or_else(|| free.first()).copied().or_else(|| configs.first()).cloned()
+}
+
+/// Works out which adapters to try opening, in order, for the name given to PassThruOpen.
+/// A port which is not in the config is opened with the default settings
+pub fn select_configs(selector: &Selector, configs: &[DeviceConfig], port_exists: &dyn Fn(&str) -> bool, in_use: &dyn Fn(&str) -> bool) -> Vec<DeviceConfig> {
+    match selector {
+        Selector::Default => pick_config(configs, port_exists, in_use).into_iter().collect(),
+        Selector::Port { path, model } => {
+            let mut cfg = configs.iter().find(|c| &c.port == path).cloned().unwrap_or_else(|| DeviceConfig {
+                port: path.clone(),
+                model: None,
+                settings: Settings::default()
+            });
+            cfg.model = model.or(cfg.model);
+            vec![cfg]
+        },
+        // Only the adapter itself knows its serial number
+        Selector::Serial(_) => configs.iter().filter(|c| port_exists(&c.port) && !in_use(&c.port)).cloned().collect()
+    }
+}
+
+/// Folder the JSON configs are read from. The tests write their own, away from any the user has
+#[cfg(any(unix, test))]
+pub fn json_dir() -> std::path::PathBuf {
+    if cfg!(test) {
+        std::env::temp_dir().join(format!("macchina-passthru-{}", std::process::id()))
+    } else {
+        shellexpand::tilde("~/.passthru").to_string().into()
+    }
+}
+
+#[cfg(any(unix, test))]
+fn device_configs() -> std::result::Result<Vec<DeviceConfig>, String> {
+    let mut configs = Vec::new();
+    for file in JSON_FILES.iter() {
+        let path = json_dir().join(file);
+        if let Ok(content) = std::fs::read_to_string(&path) {
+            let mut v = serde_json::from_str::<serde_json::Value>(content.as_str()).map_err(|e| format!("{} is not valid JSON: {}", path.display(), e))?;
+            if let Some(port) = v["COM-PORT"].as_str().map(String::from) {
+                if let Some(log) = v["LOG_PATH"].as_str() {
+                    v["LOG_PATH"] = shellexpand::tilde(log).to_string().into();
+                }
+                configs.push(DeviceConfig {
+                    port,
+                    model: v["DEVICE"].as_str().and_then(DeviceModel::from_name),
+                    settings: Settings::from_json(&v).map_err(|e| format!("Invalid setting in {}: {}", path.display(), e))?
+                })
+            }
+        }
+    }
+    Ok(configs)
+}
+
+#[cfg(all(windows, not(test)))]
+fn device_configs() -> std::result::Result<Vec<DeviceConfig>, String> {
+    let mut configs = Vec::new();
+    for path in REG_PATHS.iter() {
+        if let Ok(reg) = RegKey::predef(HKEY_LOCAL_MACHINE).open_subkey(path) {
+            logger::log_info(format!("Found regkey {}", path));
+            if let Ok(port) = reg.get_value::<String, _>("COM-PORT") {
+                logger::log_info(format!("Com port is {}", port));
+                let model = reg.get_value::<String, _>("DEVICE").ok().and_then(|m| DeviceModel::from_name(&m));
+                // Settings are checked the same way as the JSON, DWORDs being numbers
+                let mut values = serde_json::Map::new();
+                for key in crate::config::KEYS.iter().chain(crate::config::PROTOCOL_KEYS.iter().map(|(k, _)| k)) {
+                    if let Ok(x) = reg.get_value::<u32, _>(key) {
+                        values.insert(key.to_string(), x.into());
+                    } else if let Ok(x) = reg.get_value::<String, _>(key) {
+                        values.insert(key.to_string(), x.into());
+                    }
+                }
+                let settings = Settings::from_json(&serde_json::Value::Object(values)).map_err(|e| format!("Invalid setting in {}: {}", path, e))?;
+                configs.push(DeviceConfig { port, model, settings })
+            }
+        }
+    }
+    Ok(configs)
+}
+
+/// Message ID used when probing. Never handed out by `PendingRequests`, so a late answer to
+/// a probe cannot be taken as the response to a request once the port is connected
+pub const PROBE_MSG_ID: u8 = 0xFE;
+const PROBE_TIMEOUT_MS: u128 = 500;
+
+/// Asks the adapter on a port for its firmware version, which says which model it is.
+/// The port is opened with the link settings of `expected`, the adapter it is thought to be
+pub fn probe_device_model(path: &str, expected: DeviceModel) -> Option<DeviceModel> {
+    let mut port = transport::open(path, expected, &Settings::default()).ok()?;
+    let mut req = CommMsg::new(MsgType::GetFwVersion);
+    req.msg_id = PROBE_MSG_ID;
+    port.write_all(&req.to_slice()).ok()?;
+
+    let mut decoder = FrameDecoder::new();
+    let mut tmp = [0u8; 1024];
+    let start = std::time::Instant::now();
+    while start.elapsed().as_millis() < PROBE_TIMEOUT_MS {
+        if let Ok(read) = port.read(&mut tmp) {
+            decoder.push(&tmp[..read]);
+        }
+        while let Some(msg) = decoder.next_msg() {
+            // Response args are [Status, Version]
+            if msg.msg_id == PROBE_MSG_ID && msg.msg_type == MsgType::GetFwVersion && msg.args.first() == Some(&0) {
+                return DeviceModel::from_fw_version(&String::from_utf8_lossy(&msg.args[1..]));
+            }
         }
     }
     None
 }
 
 pub type PTResult<T> = std::result::Result<T, PassthruError>;
-pub fn run_on_m2<T, F: FnOnce(&MacchinaM2) -> PTResult<T>>(op: F) -> PTResult<T> {
-    match M2.read() {
+/// Runs `op` on the connection to an open adapter
+pub fn run_on_m2<T, F: FnOnce(&MacchinaM2) -> PTResult<T>>(device_id: u32, op: F) -> PTResult<T> {
+    let device = devices::get(device_id)?;
+    let conn = device.connection.read();
+    match conn {
         Ok(d) => {
             match d.as_ref() {
-                Some(dev) => op(dev),
+                Some(dev) => {
+                    dev.check_link()?;
+                    op(dev)
+                },
                 None => Err(PassthruError::ERR_DEVICE_NOT_CONNECTED)
             }
         },
@@ -98,54 +281,77 @@ pub fn run_on_m2<T, F: FnOnce(&MacchinaM2) -> PTResult<T>>(op: F) -> PTResult<T>
     }
 }
 
-const MAX_BUFFER_SIZE: usize = 16;
+/// How much the reader thread takes from the link in one go
+const READ_CHUNK_SIZE: usize = COMM_MSG_SIZE * 2;
 
 impl MacchinaM2 {
-    pub fn open_connection() -> Result<Self> {
-        match get_comm_port() {
-            Some(s) => MacchinaM2::open_conn(s.as_str()),
-            None => Err(Error::new(ErrorKind::NotFound, "Cannot find COM-PORT attribute"))
+    /// Finds the configs of the adapters `selector` could be, which are not already open.
+    /// If no configured port can be used, the serial ports are searched for adapters.
+    /// Fails if a config cannot be read, or has a bad setting
+    pub fn find_adapters(selector: &Selector) -> std::result::Result<Vec<DeviceConfig>, String> {
+        let mut configs = device_configs()?;
+        if let Some(path) = configs.iter().find_map(|c| c.settings.log_path.clone()) {
+            logger::set_log_path(path);
         }
+        crate::discovery::add_discovered(selector, &mut configs, &crate::discovery::SystemPorts, &transport::port_exists, &devices::port_in_use);
+        Ok(select_configs(selector, &configs, &transport::port_exists, &devices::port_in_use))
     }
 
-
-    fn open_conn(port: &str) -> Result<Self> {
-        let mut port = match serialport::new(port, 500000).open() {
-            Ok(mut p) => {
-                p.set_flow_control(FlowControl::Hardware).expect("Fatal. Could not setup hardware flow control");
-
-                #[cfg(feature = "A0")]
-                {
-                    p.set_flow_control(FlowControl::None).expect("Fatal. Could not setup hardware flow control");
-                    // A0 uses real Serial, but it can handle 2M/s easily.
-                    p.set_baud_rate(2000000).expect("Fatal. Could not setup A0 baud rate");                
-                }
-                p.set_timeout(std::time::Duration::from_millis(10)).expect("Fatal. Could not set Serial timeout");
-                p.clear(ClearBuffer::All).expect("Fatal. Could not clear Serial buffers");
-                p
-            },
-            Err(e) => {return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("Error opening port {}", e.to_string())));}
+    pub fn open_connection(device_id: u32, cfg: &DeviceConfig) -> Result<Self> {
+        let model = match cfg.model {
+            Some(m) => m,
+            // Nothing says which it is. The A0 link settings work with both, as the M2's native USB ignores them
+            None => match probe_device_model(&cfg.port, DeviceModel::A0) {
+                Some(m) => {
+                    logger::log_info(format!("Adapter on {} identified as {:?}", cfg.port, m));
+                    m
+                },
+                None => return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("Adapter on {} did not respond to probe. Set DEVICE in the config", cfg.port)))
+            }
         };
+        let dev = MacchinaM2::open_conn(device_id, &cfg.port, model, cfg.settings.clone())?;
+        dev.check_model();
+        Ok(dev)
+    }
+
+    pub fn open_conn(device_id: u32, port: &str, model: DeviceModel, settings: Settings) -> Result<Self> {
+        let link = transport::open(port, model, &settings)?;
+        let path = port.to_string();
+        let link_settings = settings.clone();
+        let reopen: Reopen = Arc::new(move || transport::open(&path, model, &link_settings));
+        MacchinaM2::open_reconnecting(device_id, link, model, Some(reopen), settings)
+    }
 
-        #[cfg(all(windows, feature = "A0"))]
-        {
-            // Ok so windows is strange with A0....even with DTR off it seems to reset ESP32
-            // So wait for 1 second for reset, then clear any extra data in the buffer
-            std::thread::sleep(std::time::Duration::from_millis(2000));
-            port.clear(ClearBuffer::All).expect("Could not clear port!");
+    /// Warns if the firmware says it is a different adapter than the one configured
+    fn check_model(&self) {
+        if let M2Resp::Ok(args) = self.write_and_read_ptcmd(&mut CommMsg::new(MsgType::GetFwVersion), self.settings.timeouts.command_ms) {
+            let version = String::from_utf8_lossy(&args).to_string();
+            logger::log_info(format!("Firmware version {}", version));
+            match DeviceModel::from_fw_version(&version) {
+                Some(m) if m != self.model => log_warn(format!("Configured for {:?}, but firmware reports {:?}", self.model, m)),
+                _ => {}
+            }
         }
+    }
+
+    /// Starts the CommMsg reader, writer and channel dispatch threads over
+    /// an already opened link to the adapter, without keepalives
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn open_transport(device_id: u32, port: Box<dyn Transport>, model: DeviceModel) -> Result<Self> {
+        let settings = Settings { heartbeat: Heartbeat { interval_ms: 0, ..Default::default() }, ..Default::default() };
+        MacchinaM2::open_reconnecting(device_id, port, model, None, settings)
+    }
+
+    /// Same as `open_transport`, but if the link is lost `reopen` is used to get a new
+    /// one, and the channels and filters that were open are set up on the adapter again.
+    /// Rx data is handed to the channels of `device_id`
+    pub fn open_reconnecting(device_id: u32, mut port: Box<dyn Transport>, model: DeviceModel, reopen: Option<Reopen>, settings: Settings) -> Result<Self> {
         // For data going from Caller -> M2
         let (send_tx, send_rx) : (Sender<CommMsg>, Receiver<CommMsg>) = channel();
 
         // For data going from Caller <- M2
-        let mut senders: Vec<Sender<CommMsg>> = Vec::new();
-        let mut receivers: Vec<Receiver<CommMsg>> = Vec::new();
-
-        for _ in 0..100 {
-            let (recv_tx, recv_rx) : (Sender<CommMsg>, Receiver<CommMsg>) = channel();
-            senders.push(recv_tx);
-            receivers.push(recv_rx);
-        }
+        let pending = Arc::new(PendingRequests::new());
+        let pending_t = pending.clone();
 
         let (chan_tx, chan_rx) : (Sender<CommMsg>, Receiver<CommMsg>) = channel();
 
@@ -154,20 +360,35 @@ impl MacchinaM2 {
         let is_running_t = is_running.clone();
         let is_running_tw = is_running.clone();
         let is_running_ts = is_running.clone();
+        let is_connected = Arc::new(AtomicBool::new(true));
+        let is_connected_t = is_connected.clone();
+        let link_error = Arc::new(Mutex::new(String::new()));
+        let link_error_t = link_error.clone();
+        let is_responsive = Arc::new(AtomicBool::new(true));
+        let settings_t = settings.clone();
         // Since UNIX has a 4KB Page size, I want to store more data,
         // Use a 16KB Buffer
-        let mut port_write = port.try_clone().unwrap();
+        let mut port_write = port.try_clone()?;
 
         // This thread is responsible for writing data to the M2's
         // serial port.
-        spawn(move||{
+        let writer = spawn(move||{
             logger::log_debug_str("M2 serial writer thread starting!");
-            while is_running_tw.load(Ordering::Relaxed) {
-                // Any messages to write?
-                if let Ok(m) = send_rx.recv() {
-                    if let Err(e) = port_write.write_all(&m.to_slice()) {
-                        log_warn(format!("Could not write TxPayload to M2 {}", e));
-                    }
+            loop {
+                // Any messages to write? Once stopped, the queue is emptied before exiting,
+                // so the goodbye sent by `stop` still goes out
+                match send_rx.recv_timeout(THREAD_POLL_INTERVAL) {
+                    Ok(m) => {
+                        if let Err(e) = port_write.write_all(&m.to_slice()) {
+                            log_warn(format!("Could not write TxPayload to M2 {}", e));
+                        }
+                    },
+                    Err(RecvTimeoutError::Timeout) => {
+                        if !is_running_tw.load(Ordering::Relaxed) {
+                            break;
+                        }
+                    },
+                    Err(RecvTimeoutError::Disconnected) => break // MacchinaM2 has been dropped
                 }
             }
             logger::log_debug_str("M2 serial writer thread exiting");
@@ -176,152 +397,310 @@ impl MacchinaM2 {
         // This thread is responsible for pushing data to channel queues,
         // This prevents the serial reader thread from being blocked,
         // which could result in data being lost!
-        spawn(move || {
+        let dispatcher = spawn(move || {
             logger::log_debug_str("M2 channel sender thread starting!");
-            let mut activity;
             while is_running_ts.load(Ordering::Relaxed) {
-                activity = false;
-                if let Ok(msg) = chan_rx.try_recv() {
-                    activity = true;
-                    ChannelComm::receive_channel_data(&msg)
-                }
-                if !activity {
-                    std::thread::sleep(std::time::Duration::from_micros(10));
+                match chan_rx.recv_timeout(THREAD_POLL_INTERVAL) {
+                    Ok(msg) => ChannelComm::receive_channel_data(device_id, &msg),
+                    Err(RecvTimeoutError::Timeout) => {},
+                    Err(RecvTimeoutError::Disconnected) => break // Reader thread has exited
                 }
             }
             logger::log_debug_str("M2 channel sender thread exiting!");
         });
 
         // This thread is responsible for reading serial data from the M2
-        // Since each packet is 4096 bytes, its imperative that this
-        // thread does NOT block, or else data will be lost by the OS's
-        // serial buffer.
-        spawn(move || {
-
-            let mut is_reading = false;
+        // its imperative that this thread does NOT block, or else data
+        // will be lost by the OS's serial buffer.
+        let reader = spawn(move || {
             logger::log_debug_str("M2 serial reader thread starting!");
-            let msg = CommMsg::new_with_args(MsgType::StatusMsg, &[0x01]);
+            // M2 firmware which understands the flag stops padding its frames, older firmware ignores it
+            let msg = CommMsg::new_with_args(MsgType::StatusMsg, &[STATUS_HELLO, HELLO_FLAG_UNPADDED]);
             if port.write_all(&msg.to_slice()).is_err() {
                 logger::log_error_str("Timeout writing init struct!");
                 is_running_t.store(false, Ordering::Relaxed);
                 return;
             }
 
-            let mut read_count = 0;
-            let mut read_target = 0;
-            let mut read_buffer: [u8; COMM_MSG_SIZE * MAX_BUFFER_SIZE] = [0x00; COMM_MSG_SIZE * MAX_BUFFER_SIZE];
-            let mut activity: bool;
-            let mut _loop_count: u128 = 0;
+            let mut decoder = FrameDecoder::new();
+            let mut read_buffer = vec![0u8; READ_CHUNK_SIZE];
+            let mut link_lost: Option<String> = None;
             while is_running_t.load(Ordering::Relaxed) {
-                #[cfg(feature = "M2")]
-                {
-                    let incoming = port.read(&mut read_buffer[read_count..]).unwrap_or(0);
-                    read_count += incoming;
-                    activity = incoming > 0;
-                    //if incoming > 0 {
-                    //    log_debug(format!("READ {} bytes. {} in buffer", incoming, read_count));
-                    //}
-                    while read_count >= COMM_MSG_SIZE {
-                        let msg = CommMsg::from_vec(&read_buffer[0..COMM_MSG_SIZE]);
-                        unsafe {
-                            std::ptr::copy(&read_buffer[COMM_MSG_SIZE], &mut read_buffer[0], COMM_MSG_SIZE*(MAX_BUFFER_SIZE-1));
-                        }
-                        read_count -= COMM_MSG_SIZE;
-                        match msg.msg_type {
-                            MsgType::LogMsg => log_m2_msg(String::from_utf8(msg.args).unwrap()),
-                            MsgType::ReceiveChannelData => {
-                                if chan_tx.send(msg).is_err() {
-                                    log_error_str("Could not write data to channel thread receiver!");
-                                }
-                            },
-                            _ => {
-                                if msg.msg_id != 0 && msg.msg_id < 100 {
-                                    if let Err(e) = senders[(msg.msg_id-1) as usize].send(msg) {
-                                        // Shouldn't happen, log it if it does
-                                        log_error(format!("Could not push COMM_MSG to receive queue: {}", e))
-                                    }
-                                } else {
-                                    log_error(format!("Invalid message ID {} - Type: {:?}", msg.msg_id, msg.msg_type))
-                                }
-                            }
-                        }
+                let incoming = match port.read(&mut read_buffer) {
+                    // Serial ports time out rather than returning nothing, so this is the end of the stream
+                    Ok(0) => {
+                        link_lost = Some("Link closed".into());
+                        break;
                     }
-                    if !activity {
-                        std::thread::sleep(std::time::Duration::from_micros(10));
+                    Ok(x) => x,
+                    Err(e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted => 0,
+                    Err(e) => {
+                        link_lost = Some(e.to_string());
+                        break;
                     }
+                };
+                if incoming == 0 {
+                    std::thread::sleep(std::time::Duration::from_micros(10));
+                    continue;
                 }
-                #[cfg(feature = "A0")]
-                {
-                    let read = port.bytes_to_read().unwrap_or(0);
-                    if read >= 2 && !is_reading {
-                        let mut tmp: [u8; 2] = [0; 2];
-                        port.read_exact(&mut tmp);
-                        read_target = u16::from_le_bytes(tmp) as usize;
-                        read_count = 0;
-                        is_reading = true;
-                    } else if is_reading && read > 0 {
-                        let max_read = std::cmp::min(read_target - read_count, read as usize);
-                        port.read_exact(&mut read_buffer[read_count..read_count+max_read]);
-                        read_count += max_read;
-                        if read_count == read_target {
-                            is_reading = false;
-                            // Complete payload!
-                            let mut msg = CommMsg::new_with_args(MsgType::from_u8(&read_buffer[1]), &read_buffer[2..read_target as usize]);
-                            msg.msg_id = read_buffer[0];
-                            match msg.msg_type {
-                                MsgType::LogMsg => log_m2_msg(String::from_utf8(msg.args).unwrap()),
-                                MsgType::ReceiveChannelData => {
-                                    if chan_tx.send(msg).is_err() {
-                                        log_error_str("Could not write data to channel thread receiver!");
-                                    }
-                                },
-                                _ => {
-                                    if msg.msg_id != 0 && msg.msg_id < 100 {
-                                        if let Err(e) = senders[(msg.msg_id-1) as usize].send(msg) {
-                                            // Shouldn't happen, log it if it does
-                                            log_error(format!("Could not push COMM_MSG to receive queue: {}", e))
-                                        }
-                                    } else {
-                                        log_error(format!("Invalid message ID {} - Type: {:?}", msg.msg_id, msg.msg_type))
-                                    }
-                                }
+                decoder.push(&read_buffer[0..incoming]);
+                while let Some(msg) = decoder.next_msg() {
+                    match msg.msg_type {
+                        MsgType::LogMsg => log_m2_msg(String::from_utf8_lossy(&msg.args).to_string()),
+                        MsgType::ReceiveChannelData => {
+                            if chan_tx.send(msg).is_err() {
+                                log_error_str("Could not write data to channel thread receiver!");
+                            }
+                        },
+                        _ => {
+                            if msg.msg_id != 0 {
+                                pending_t.complete(msg);
+                            } else {
+                                log_error(format!("Invalid message ID {} - Type: {:?}", msg.msg_id, msg.msg_type))
                             }
-
-
                         }
-                    } else {
-                        std::thread::sleep(std::time::Duration::from_micros(10));
                     }
                 }
+                let discarded = decoder.take_discarded();
+                if discarded > 0 {
+                    log_warn(format!("Lost sync with adapter, discarded {} bytes", discarded));
+                }
             }
-            let msg = CommMsg::new_with_args(MsgType::StatusMsg, &[0x00]);
-            if let Err(e) = port.write_all(&msg.to_slice()) {
-                log_warn(format!("Could not write exit message to M2 {}", e));
+            if let Some(reason) = link_lost {
+                let error = format!("Lost connection to adapter: {}", reason);
+                log_error(error.clone());
+                *link_error_t.lock().unwrap() = error.clone();
+                is_connected_t.store(false, Ordering::Relaxed);
+                set_error_string(error);
+                // Nobody is going to answer the requests in flight
+                pending_t.fail_all();
+                if let Some(reopen) = reopen {
+                    MacchinaM2::reconnect(device_id, reopen, model, settings_t, is_running_t);
+                }
             }
             logger::log_debug_str("M2 serial reader thread exiting");
         });
-        std::thread::sleep(std::time::Duration::from_millis(50));
-        if !is_running.load(Ordering::Relaxed) {
-            return Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "Error initializing M2"));
-        }
-
-        let m = MacchinaM2 {
+        let watchdog = MacchinaM2::start_watchdog(
+            send_tx.clone(),
+            pending.clone(),
+            settings.heartbeat,
+            is_running.clone(),
+            is_connected.clone(),
+            is_responsive.clone()
+        );
+
+        let mut m = MacchinaM2 {
+            model,
             is_running,
+            is_connected,
+            link_error,
+            is_responsive,
             tx_send_queue: send_tx,
-            rx_recv_queue: receivers
+            pending,
+            capabilities: None,
+            settings,
+            threads: vec![writer, dispatcher, reader, watchdog]
         };
+        std::thread::sleep(Duration::from_millis(50));
+        if !m.is_running.load(Ordering::Relaxed) {
+            m.stop();
+            return Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "Error initializing M2"));
+        }
         Ok(m)
     }
 
-    /// Writes a CommMsg to the M2, and does not retrieve a response
-    /// from the M2
-    pub fn write_comm_struct(&self, mut s: CommMsg) -> PTResult<()> {
-        s.msg_id = 0x00; // Tell M2 it doesn't have to respond to request
-        match self.tx_send_queue.send(s) {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                set_error_string(format!("Error sending data to M2 TxChannel {}", e));
-                Err(PassthruError::ERR_FAILED)
+    /// Sends a keepalive every heartbeat interval. If too many go unanswered, requests
+    /// waiting on the adapter are failed, and new ones fail straight away, until it answers again
+    fn start_watchdog(
+        tx_send_queue: Sender<CommMsg>,
+        pending: Arc<PendingRequests>,
+        heartbeat: Heartbeat,
+        is_running: Arc<AtomicBool>,
+        is_connected: Arc<AtomicBool>,
+        is_responsive: Arc<AtomicBool>
+    ) -> JoinHandle<()> {
+        spawn(move || {
+            logger::log_debug_str("M2 watchdog thread starting!");
+            let mut misses: u32 = 0;
+            let hb = heartbeat;
+            // Stops with the link, the next connection has its own watchdog
+            while hb.interval_ms != 0 && is_running.load(Ordering::Relaxed) && is_connected.load(Ordering::Relaxed) {
+                let interval = std::time::Duration::from_millis(hb.interval_ms);
+                let start = std::time::Instant::now();
+                let answered = match pending.register(MsgType::StatusMsg, interval) {
+                    Some((id, receiver)) => {
+                        let mut msg = CommMsg::new_with_args(MsgType::StatusMsg, &[STATUS_KEEPALIVE]);
+                        msg.msg_id = id;
+                        if tx_send_queue.send(msg).is_err() {
+                            break;
+                        }
+                        let res = receiver.recv_timeout(interval);
+                        if res.is_err() {
+                            pending.cancel(id);
+                        }
+                        res.is_ok()
+                    },
+                    None => false
+                };
+                if !is_running.load(Ordering::Relaxed) {
+                    // Woken up by `stop`
+                    break;
+                }
+                if answered {
+                    if misses >= hb.max_misses {
+                        log_info_str("Adapter is responding again");
+                    }
+                    misses = 0;
+                    is_responsive.store(true, Ordering::Relaxed);
+                    sleep_while_running(&is_running, interval.saturating_sub(start.elapsed()));
+                    continue;
+                }
+                misses += 1;
+                log_warn(format!("Adapter missed keepalive ({}/{})", misses, hb.max_misses));
+                if misses == hb.max_misses {
+                    log_error(format!("Adapter has not answered {} keepalives, treating it as not responding", misses));
+                    is_responsive.store(false, Ordering::Relaxed);
+                    pending.fail_all();
+                }
+            }
+            logger::log_debug_str("M2 watchdog thread exiting");
+        })
+    }
+
+    /// Keeps trying to reopen a lost link, until it works or the device is closed.
+    /// Once the link is back, the channels and filters are set up again and the
+    /// new connection takes the place of the old one in the device
+    fn reconnect(device_id: u32, reopen: Reopen, model: DeviceModel, settings: Settings, is_running: Arc<AtomicBool>) {
+        log_info_str("Waiting for adapter to be reconnected");
+        while is_running.load(Ordering::Relaxed) {
+            sleep_while_running(&is_running, Duration::from_millis(RECONNECT_INTERVAL_MS));
+            if !is_running.load(Ordering::Relaxed) {
+                break;
+            }
+            let link = match reopen() {
+                Ok(l) => l,
+                Err(_) => continue
+            };
+            let mut dev = match MacchinaM2::open_reconnecting(device_id, link, model, Some(reopen.clone()), settings.clone()) {
+                Ok(d) => d,
+                Err(e) => {
+                    log_warn(format!("Adapter link reopened, but could not start: {}", e));
+                    continue;
+                }
+            };
+            // Could have been plugged back in with different firmware
+            if dev.query_capabilities().is_err() {
+                log_warn(format!("Adapter reconnected, but was refused: {}", crate::passthru_drv::LAST_ERROR_STR.lock().unwrap()));
+                continue;
+            }
+            // Dropping the new connection closes it, if the device was closed whilst reconnecting
+            let device = match devices::get(device_id) {
+                Ok(d) => d,
+                Err(_) => return
+            };
+            if let Err(e) = device.channels.restore(&dev) {
+                log_warn(format!("Could not restore channels after reconnecting: {:?}", e));
+            }
+            let mut guard = device.connection.write().unwrap();
+            // Device could have been closed whilst reconnecting
+            let still_open = matches!(guard.as_ref(), Some(old) if Arc::ptr_eq(&old.is_running, &is_running));
+            if still_open && is_running.load(Ordering::Relaxed) {
+                log_info_str("Adapter reconnected");
+                // Old connection is dropped from this, its own reader thread, so it cannot join it
+                *guard = Some(dev);
+            } else {
+                drop(guard);
+                let mut dev = dev;
+                dev.stop();
+            }
+            return;
+        }
+    }
+
+    /// Asks the firmware what it supports, failing if it speaks an incompatible protocol version
+    pub fn query_capabilities(&mut self) -> PTResult<()> {
+        let caps = match self.write_and_read_ptcmd(&mut CommMsg::new(MsgType::GetCapabilities), self.settings.timeouts.command_ms) {
+            M2Resp::Ok(args) => match Capabilities::from_args(&args) {
+                Some(c) => c,
+                None => {
+                    set_error_string(format!("Adapter capability response is too short ({} bytes)", args.len()));
+                    return Err(PassthruError::ERR_FAILED);
+                }
+            },
+            M2Resp::Err { status: PassthruError::ERR_TIMEOUT, .. } => {
+                // Firmware from before the capability query ignores it
+                set_error_string("Adapter did not report its capabilities, its firmware is too old for this driver".into());
+                return Err(PassthruError::ERR_FAILED);
+            },
+            M2Resp::Err { status, string } => {
+                set_error_string(format!("Adapter capability query failed: {}", string));
+                return Err(status);
+            }
+        };
+        if let Err(e) = caps.check_version() {
+            log_error(e.clone());
+            set_error_string(e);
+            return Err(PassthruError::ERR_FAILED);
+        }
+        logger::log_info(format!("Adapter capabilities: {:?}", caps));
+        self.capabilities = Some(caps);
+        Ok(())
+    }
+
+    pub fn capabilities(&self) -> Option<&Capabilities> {
+        self.capabilities.as_ref()
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Asks the adapter which board it is
+    pub fn read_device_info(&self) -> PTResult<DeviceInfo> {
+        match self.write_and_read_ptcmd(&mut CommMsg::new(MsgType::GetDeviceInfo), self.settings.timeouts.command_ms) {
+            M2Resp::Ok(args) => DeviceInfo::from_args(&args).ok_or_else(|| {
+                set_error_string("Adapter device info response is malformed".into());
+                PassthruError::ERR_FAILED
+            }),
+            M2Resp::Err { status, string } => {
+                set_error_string(format!("Adapter device info query failed: {}", string));
+                Err(status)
+            }
+        }
+    }
+
+    /// Returns false if the link to the adapter has been lost
+    pub fn is_connected(&self) -> bool {
+        self.is_connected.load(Ordering::Relaxed)
+    }
+
+    /// Describes why the link to the adapter was lost
+    pub fn link_error(&self) -> String {
+        self.link_error.lock().unwrap().clone()
+    }
+
+    pub fn link_status(&self) -> LinkStatus {
+        if !self.is_connected() {
+            LinkStatus::Disconnected
+        } else if !self.is_responsive.load(Ordering::Relaxed) {
+            LinkStatus::NotResponding
+        } else {
+            LinkStatus::Ok
+        }
+    }
+
+    /// Fails with ERR_DEVICE_NOT_CONNECTED, and sets the error string to say why,
+    /// if the adapter cannot currently be talked to
+    pub fn check_link(&self) -> PTResult<()> {
+        match self.link_status() {
+            LinkStatus::Ok => Ok(()),
+            LinkStatus::Disconnected => {
+                set_error_string(self.link_error());
+                Err(PassthruError::ERR_DEVICE_NOT_CONNECTED)
+            },
+            LinkStatus::NotResponding => {
+                set_error_string("Adapter is not responding to keepalives".into());
+                Err(PassthruError::ERR_DEVICE_NOT_CONNECTED)
             }
         }
     }
@@ -372,34 +751,95 @@ impl MacchinaM2 {
 
     /// Writes a message to the M2 unit, and expects a designated response back from the unit
     pub fn write_and_read(&self, msg: &mut CommMsg, timeout_ms: u128) -> PTResult<CommMsg> {
-        let query_id = get_id(); // Set a unique ID, M2 is now forced to respond
+        self.check_link()?;
+        let timeout = std::time::Duration::from_millis(timeout_ms as u64);
+        // Set a unique ID, M2 is now forced to respond
+        let (query_id, receiver) = match self.pending.register(msg.msg_type, timeout) {
+            Some(x) => x,
+            None => {
+                set_error_string("Too many requests waiting on the M2".to_string());
+                return Err(PassthruError::ERR_FAILED);
+            }
+        };
         msg.msg_id = query_id;
 
         logger::log_debug(format!("Write data: {}", &msg));
         if let Err(e) = self.tx_send_queue.send(msg.clone()) {
             log_error(format!("Error writing comm msg to queue {}", e));
+            self.pending.cancel(query_id);
             return Err(PassthruError::ERR_FAILED);
         }
 
         let start_time = std::time::Instant::now(); // This is just for logging and serves no other purpose
         
         // Wait for our response message to appear within the Rx queue
-        if let Ok(msg) = self.rx_recv_queue[query_id as usize -1].recv_timeout(std::time::Duration::from_millis(timeout_ms as u64)) {
-            // For debugging, just log how long the CMD took to do a round trip (Req -> M2 -> Resp)
-            log_debug(format!("Command took {}us to execute", start_time.elapsed().as_micros()));
-            return Ok(msg); // Return our message
+        match receiver.recv_timeout(timeout) {
+            Ok(msg) => {
+                // For debugging, just log how long the CMD took to do a round trip (Req -> M2 -> Resp)
+                log_debug(format!("Command took {}us to execute", start_time.elapsed().as_micros()));
+                Ok(msg) // Return our message
+            },
+            Err(RecvTimeoutError::Disconnected) => {
+                // Link went down, or the adapter stopped responding, whilst waiting
+                self.check_link()?;
+                Err(PassthruError::ERR_DEVICE_NOT_CONNECTED)
+            },
+            Err(RecvTimeoutError::Timeout) => {
+                // Any response that turns up now is an orphan
+                self.pending.cancel(query_id);
+                Err(PassthruError::ERR_TIMEOUT) // M2 timeout!
+            }
         }
-        Err(PassthruError::ERR_TIMEOUT) // M2 timeout!
     }
 
+    #[cfg(test)]
+    pub fn pending(&self) -> &PendingRequests {
+        &self.pending
+    }
+
+    /// Says goodbye to the adapter, and waits for every thread to exit, which closes the port.
+    /// A thread of this connection calling it is not waited on
     pub fn stop(&mut self) {
+        if self.threads.is_empty() {
+            return; // Already stopped
+        }
+        if self.is_connected() {
+            // Writer sends everything queued before it exits
+            let msg = CommMsg::new_with_args(MsgType::StatusMsg, &[STATUS_GOODBYE]);
+            if let Err(e) = self.tx_send_queue.send(msg) {
+                log_warn(format!("Could not queue exit message to M2 {}", e));
+            }
+        }
         self.is_running.store(false, Ordering::Relaxed);
+        // Wakes the watchdog, if it is waiting on a keepalive
+        self.pending.fail_all();
+        let current = std::thread::current().id();
+        for handle in self.threads.drain(..) {
+            if handle.thread().id() != current && handle.join().is_err() {
+                log_error_str("M2 worker thread panicked");
+            }
+        }
+        logger::log_debug_str("M2 connection stopped");
+    }
+}
+
+impl Drop for MacchinaM2 {
+    fn drop(&mut self) {
+        self.stop();
     }
 }
 
 
-const COMM_MSG_SIZE: usize = 8192;
-const COMM_MSG_ARG_SIZE: usize = COMM_MSG_SIZE - 4;
+pub const COMM_MSG_SIZE: usize = 8192;
+pub const COMM_MSG_ARG_SIZE: usize = COMM_MSG_SIZE - 4;
+
+// StatusMsg args are [Status, Flags]. Flags are only sent with the hello
+pub const STATUS_GOODBYE: u8 = 0x00;
+pub const STATUS_HELLO: u8 = 0x01;
+/// Answered by the adapter with an empty response, without resetting anything
+pub const STATUS_KEEPALIVE: u8 = 0x02;
+/// Hello flag, asks the adapter to send frames without padding them to COMM_MSG_SIZE
+pub const HELLO_FLAG_UNPADDED: u8 = 0x01;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 /// MsgTypes definitions
@@ -419,12 +859,14 @@ pub enum MsgType {
     InitLinChannel = 0x11,
     StatusMsg = 0xAA,
     GetFwVersion = 0xAB,
+    GetCapabilities = 0xAC,
+    GetDeviceInfo = 0xAD,
     #[cfg(test)]
     TestMessage = 0xFF
 }
 
 impl MsgType {
-    fn from_u8(s: &u8) -> MsgType {
+    pub fn from_u8(s: &u8) -> MsgType {
         match s {
             0x01 => MsgType::LogMsg,
             0x02 => MsgType::OpenChannel,
@@ -439,6 +881,8 @@ impl MsgType {
             0x11 => MsgType::InitLinChannel,
             0xAA => MsgType::StatusMsg,
             0xAB => MsgType::GetFwVersion,
+            0xAC => MsgType::GetCapabilities,
+            0xAD => MsgType::GetDeviceInfo,
             #[cfg(test)]
             0xFF => MsgType::TestMessage,
             _ => {
@@ -466,15 +910,6 @@ impl std::fmt::Display for CommMsg {
 }
 
 impl CommMsg {
-    pub fn from_vec(buf: &[u8]) -> Self {
-        let size = LittleEndian::read_u16(&buf[2..4]) as usize;
-        CommMsg {
-            msg_id: buf[0],
-            msg_type: MsgType::from_u8(&buf[1]),
-            args: Vec::from(&buf[4..size+4]),
-        }
-    }
-
     pub fn new(msg_type: MsgType) -> Self {
         CommMsg {
             msg_type,
@@ -506,12 +941,8 @@ impl CommMsg {
         self.msg_type = msg_type;
     }
 
+    /// Encodes the message as a frame to send over the link
     pub fn to_slice(&self) -> Vec<u8> {
-        let mut params: Vec<u8> = Vec::with_capacity(self.args.len() + 4);
-        params.write_u16::<LittleEndian>(self.args.len() as u16+2).unwrap(); // 0,1
-        params.push(self.msg_id); // 2
-        params.push(self.msg_type as u8); // 3
-        params.extend_from_slice(&self.args);
-        params
+        crate::frame::encode(self.msg_id, self.msg_type, &self.args)
     }
 }
\ No newline at end of file
@@ -0,0 +1,186 @@
+// Settings of the driver, read from the same JSON file (or registry key on Windows) as COM-PORT.
+// Every one is optional, and falls back to the value the driver has always used.
+//
+// Values are checked when the adapter is opened. A bad one fails PassThruOpen with ERR_FAILED,
+// and PassThruGetLastError names the setting, rather than the driver quietly using the default.
+//
+// The protocol flags of the J2534 manifest (CAN, ISO15765...) are what applications are told the
+// adapter can do, so a protocol turned off there is refused, even if the firmware has it
+
+use std::path::PathBuf;
+use serde_json::Value;
+use j2534_rust::{PassthruError, Protocol};
+use crate::capabilities::Capabilities;
+use crate::comm::{DeviceModel, Heartbeat};
+use crate::passthru_drv::set_error_string;
+
+/// Names of the settings, as they appear in the config
+#[cfg_attr(not(windows), allow(dead_code))]
+pub const KEYS: [&str; 10] = [
+    "BAUD_RATE",
+    "PORT_TIMEOUT_MS",
+    "MAX_QUEUE_MSGS",
+    "HEARTBEAT_INTERVAL_MS",
+    "HEARTBEAT_MISSES",
+    "QUICK_CMD_TIMEOUT_MS",
+    "CMD_TIMEOUT_MS",
+    "TX_TIMEOUT_MS",
+    "INIT_TIMEOUT_MS",
+    "LOG_PATH",
+];
+
+/// Protocol flags of the manifest
+pub const PROTOCOL_KEYS: [(&str, Protocol); 10] = [
+    ("J1850VPW", Protocol::J1850VPW),
+    ("J1850PWM", Protocol::J1850PWM),
+    ("ISO9141", Protocol::ISO9141),
+    ("ISO14230", Protocol::ISO14230),
+    ("CAN", Protocol::CAN),
+    ("ISO15765", Protocol::ISO15765),
+    ("SCI_A_ENGINE", Protocol::SCI_A_ENGINE),
+    ("SCI_A_TRANS", Protocol::SCI_A_TRANS),
+    ("SCI_B_ENGINE", Protocol::SCI_B_ENGINE),
+    ("SCI_B_TRANS", Protocol::SCI_B_TRANS),
+];
+
+/// How long to wait for the adapter to answer each kind of command
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Timeouts {
+    /// Opening a channel, removing a filter, and getting or setting channel config
+    pub quick_ms: u128,
+    /// Setting a filter, closing a channel, and querying the adapter
+    pub command_ms: u128,
+    /// Sending a message which the adapter has to confirm
+    pub tx_ms: u128,
+    /// Five baud and fast init of a K-Line ECU
+    pub init_ms: u128,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self { quick_ms: 100, command_ms: 250, tx_ms: 1000, init_ms: 10000 }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Settings {
+    /// None to use the rate of the model, 500000 for the M2, 2000000 for the A0
+    pub baud_rate: Option<u32>,
+    /// Longest a read of the serial port blocks for
+    pub port_timeout_ms: u64,
+    /// Messages each channel buffers until the application reads them. Any more are dropped
+    pub max_queue_msgs: usize,
+    pub heartbeat: Heartbeat,
+    pub timeouts: Timeouts,
+    /// None to log to the default location
+    pub log_path: Option<PathBuf>,
+    /// Bit N set for each protocol ID N the manifest says the adapter has
+    pub protocols_claimed: u32,
+    /// Bit N set for each protocol ID N the manifest turns off. Ones it does not mention are left to the firmware
+    pub protocols_disabled: u32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            baud_rate: None,
+            port_timeout_ms: 10,
+            max_queue_msgs: 500,
+            heartbeat: Heartbeat::default(),
+            timeouts: Timeouts::default(),
+            log_path: None,
+            protocols_claimed: 0,
+            protocols_disabled: 0,
+        }
+    }
+}
+
+/// Reads a whole number setting, checking it is in `min..=max`
+fn read_num(v: &Value, key: &str, min: u64, max: u64, default: u64) -> Result<u64, String> {
+    match &v[key] {
+        Value::Null => Ok(default),
+        Value::Number(n) => match n.as_u64() {
+            Some(x) if x >= min && x <= max => Ok(x),
+            _ => Err(format!("{} must be a whole number from {} to {}, not {}", key, min, max, n))
+        },
+        other => Err(format!("{} must be a whole number from {} to {}, not {}", key, min, max, other))
+    }
+}
+
+/// Reads the protocol flags, returning the protocols which are on, and the ones which are off.
+/// The registry has no booleans, so 1 and 0 are taken as well
+fn read_protocols(v: &Value) -> Result<(u32, u32), String> {
+    let (mut claimed, mut disabled) = (0, 0);
+    for (key, protocol) in PROTOCOL_KEYS.iter() {
+        let bit = 1 << *protocol as u32;
+        match &v[*key] {
+            Value::Null => {},
+            Value::Bool(true) => claimed |= bit,
+            Value::Bool(false) => disabled |= bit,
+            Value::Number(n) if n.as_u64() == Some(1) => claimed |= bit,
+            Value::Number(n) if n.as_u64() == Some(0) => disabled |= bit,
+            other => return Err(format!("{} must be true or false, not {}", key, other))
+        }
+    }
+    Ok((claimed, disabled))
+}
+
+impl Settings {
+    /// Reads the settings from a config, using the default for any that are missing
+    pub fn from_json(v: &Value) -> Result<Self, String> {
+        let default = Settings::default();
+        let timeout = |key: &str, default: u128| read_num(v, key, 10, 60000, default as u64).map(|x| x as u128);
+        let (protocols_claimed, protocols_disabled) = read_protocols(v)?;
+        Ok(Self {
+            baud_rate: match v["BAUD_RATE"] {
+                Value::Null => None,
+                _ => Some(read_num(v, "BAUD_RATE", 9600, 4_000_000, 0)? as u32)
+            },
+            port_timeout_ms: read_num(v, "PORT_TIMEOUT_MS", 1, 1000, default.port_timeout_ms)?,
+            max_queue_msgs: read_num(v, "MAX_QUEUE_MSGS", 1, 100_000, default.max_queue_msgs as u64)? as usize,
+            heartbeat: Heartbeat {
+                interval_ms: read_num(v, "HEARTBEAT_INTERVAL_MS", 0, 60000, default.heartbeat.interval_ms)?,
+                max_misses: read_num(v, "HEARTBEAT_MISSES", 1, 100, default.heartbeat.max_misses as u64)? as u32
+            },
+            timeouts: Timeouts {
+                quick_ms: timeout("QUICK_CMD_TIMEOUT_MS", default.timeouts.quick_ms)?,
+                command_ms: timeout("CMD_TIMEOUT_MS", default.timeouts.command_ms)?,
+                tx_ms: timeout("TX_TIMEOUT_MS", default.timeouts.tx_ms)?,
+                init_ms: timeout("INIT_TIMEOUT_MS", default.timeouts.init_ms)?
+            },
+            log_path: match &v["LOG_PATH"] {
+                Value::Null => None,
+                Value::String(s) if !s.trim().is_empty() => Some(PathBuf::from(s)),
+                other => return Err(format!("LOG_PATH must be the path of a file, not {}", other))
+            },
+            protocols_claimed,
+            protocols_disabled
+        })
+    }
+
+    /// Fails with ERR_NOT_SUPPORTED if the manifest turns `protocol` off
+    pub fn check_protocol(&self, protocol: Protocol) -> Result<(), PassthruError> {
+        if self.protocols_disabled & (1 << protocol as u32) == 0 {
+            Ok(())
+        } else {
+            set_error_string(format!("{:?} is turned off in the driver config", protocol));
+            Err(PassthruError::ERR_NOT_SUPPORTED)
+        }
+    }
+
+    /// Protocols the manifest says the adapter has, which its firmware does not
+    pub fn unsupported_claims(&self, caps: &Capabilities) -> Vec<Protocol> {
+        PROTOCOL_KEYS.iter()
+            .map(|(_, p)| *p)
+            .filter(|p| self.protocols_claimed & (1 << *p as u32) != 0 && !caps.supports(*p))
+            .collect()
+    }
+
+    pub fn baud_rate_for(&self, model: DeviceModel) -> u32 {
+        self.baud_rate.unwrap_or(match model {
+            DeviceModel::M2 => 500000,
+            // A0 uses real Serial, but it can handle 2M/s easily
+            DeviceModel::A0 => 2000000
+        })
+    }
+}
@@ -0,0 +1,71 @@
+// Identity of the adapter, so one of several on the same bench can be told apart.
+//
+// Response args are [Uptime ms (4 bytes), Model, Board revision, Serial], the strings
+// each ending in a 0x00. The serial is the unique ID of the M2's MCU, or the MAC of the A0
+
+use byteorder::{ByteOrder, LittleEndian};
+use libc::c_char;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DeviceInfo {
+    pub model: String,
+    pub board_revision: String,
+    pub serial: String,
+    pub uptime_ms: u32,
+}
+
+impl DeviceInfo {
+    pub fn from_args(args: &[u8]) -> Option<Self> {
+        if args.len() < 4 {
+            return None;
+        }
+        let mut strings = args[4..].split(|b| *b == 0x00).map(|s| String::from_utf8_lossy(s).to_string());
+        Some(Self {
+            uptime_ms: LittleEndian::read_u32(&args[0..4]),
+            model: strings.next()?,
+            board_revision: strings.next()?,
+            serial: strings.next()?,
+        })
+    }
+
+    /// Encodes the response, as the firmware does
+    #[cfg(test)]
+    pub fn to_args(&self) -> Vec<u8> {
+        let mut args = self.uptime_ms.to_le_bytes().to_vec();
+        for s in [&self.model, &self.board_revision, &self.serial].iter() {
+            args.extend_from_slice(s.as_bytes());
+            args.push(0x00);
+        }
+        args
+    }
+}
+
+const INFO_STR_SIZE: usize = 64;
+
+/// Output of the GET_DEVICE_INFO IOCTL
+#[repr(C)]
+#[allow(non_camel_case_types)]
+pub struct SDEVICE_INFO {
+    pub model: [c_char; INFO_STR_SIZE],
+    pub board_revision: [c_char; INFO_STR_SIZE],
+    pub serial: [c_char; INFO_STR_SIZE],
+    pub uptime_ms: u32,
+}
+
+impl SDEVICE_INFO {
+    pub fn set(&mut self, info: &DeviceInfo) {
+        copy_c_str(&mut self.model, &info.model);
+        copy_c_str(&mut self.board_revision, &info.board_revision);
+        copy_c_str(&mut self.serial, &info.serial);
+        self.uptime_ms = info.uptime_ms;
+    }
+}
+
+/// Copies as much of `src` as fits, always leaving the null terminator
+fn copy_c_str(dst: &mut [c_char; INFO_STR_SIZE], src: &str) {
+    let len = std::cmp::min(src.len(), INFO_STR_SIZE - 1);
+    for (d, s) in dst.iter_mut().zip(src.as_bytes()[..len].iter()) {
+        *d = *s as c_char;
+    }
+    dst[len] = 0;
+}
@@ -0,0 +1,106 @@
+// Adapters open in this process. Each one has its own device ID, connection and set of
+// channels, so an application can have an M2 on one bus and an A0 on another.
+//
+// Device IDs are DEVICE_ID + the slot the adapter was given, so the first one opened is
+// DEVICE_ID as it always has been. Channel IDs are unique across every adapter, being
+// slot * CHANNEL_ID_STRIDE + the ID the adapter's channel table gave the channel
+
+use std::sync::{Arc, RwLock};
+use j2534_rust::PassthruError;
+use lazy_static::lazy_static;
+use crate::channels::ChannelTable;
+use crate::comm::{MacchinaM2, PTResult};
+use crate::periodic::Scheduler;
+use crate::passthru_drv::set_error_string;
+
+/// Device ID of the first adapter opened
+pub const DEVICE_ID: u32 = 0x1234;
+/// Most adapters that can be open at once
+pub const MAX_DEVICES: usize = 8;
+/// Channel IDs each adapter has to itself
+pub const CHANNEL_ID_STRIDE: u32 = 0x100;
+
+pub struct Device {
+    pub id: u32,
+    /// Port the adapter is on, so it is not opened twice
+    pub port: String,
+    /// None until opened. Replaced with a new connection if the adapter reconnects
+    pub connection: RwLock<Option<MacchinaM2>>,
+    pub channels: ChannelTable,
+    /// Sends the periodic messages of every channel
+    pub periodic: Scheduler,
+}
+
+lazy_static! {
+    static ref DEVICES: RwLock<[Option<Arc<Device>>; MAX_DEVICES]> = RwLock::new(Default::default());
+}
+
+fn slot_of(device_id: u32) -> Option<usize> {
+    device_id.checked_sub(DEVICE_ID).map(|s| s as usize).filter(|s| *s < MAX_DEVICES)
+}
+
+/// Takes a device ID for the adapter on `port`, before its connection is opened.
+/// Fails with ERR_DEVICE_IN_USE if the adapter is already open
+pub fn register(port: &str) -> PTResult<Arc<Device>> {
+    let mut devices = DEVICES.write().unwrap();
+    if devices.iter().flatten().any(|d| d.port == port) {
+        set_error_string(format!("Adapter on {} is already open", port));
+        return Err(PassthruError::ERR_DEVICE_IN_USE);
+    }
+    let slot = match devices.iter().position(|d| d.is_none()) {
+        Some(s) => s,
+        None => {
+            set_error_string(format!("Cannot open more than {} adapters at once", MAX_DEVICES));
+            return Err(PassthruError::ERR_FAILED);
+        }
+    };
+    let device = Arc::new(Device {
+        id: DEVICE_ID + slot as u32,
+        port: port.to_string(),
+        connection: RwLock::new(None),
+        channels: ChannelTable::default(),
+        periodic: Scheduler::default(),
+    });
+    devices[slot] = Some(device.clone());
+    Ok(device)
+}
+
+/// Gives up the device ID, returning the device so it can be closed
+pub fn remove(device_id: u32) -> Option<Arc<Device>> {
+    slot_of(device_id).and_then(|s| DEVICES.write().unwrap()[s].take())
+}
+
+pub fn get(device_id: u32) -> PTResult<Arc<Device>> {
+    slot_of(device_id).and_then(|s| DEVICES.read().unwrap()[s].clone()).ok_or(PassthruError::ERR_INVALID_DEVICE_ID)
+}
+
+/// Returns true if an open adapter is on `port`
+pub fn port_in_use(port: &str) -> bool {
+    DEVICES.read().unwrap().iter().flatten().any(|d| d.port == port)
+}
+
+/// Finds the adapter an IOCTL or PassThruReadVersion is for, given either its device ID
+/// or the ID of one of its channels. Applications written for a single adapter tend to
+/// pass anything, so if only one adapter is open, that is used
+pub fn from_handle(handle: u32) -> PTResult<Arc<Device>> {
+    let devices = DEVICES.read().unwrap();
+    let slot = slot_of(handle).or_else(|| Some((handle / CHANNEL_ID_STRIDE) as usize).filter(|s| *s < MAX_DEVICES));
+    if let Some(d) = slot.and_then(|s| devices[s].clone()) {
+        return Ok(d);
+    }
+    let mut open = devices.iter().flatten();
+    match (open.next(), open.next()) {
+        (Some(d), None) => Ok(d.clone()),
+        _ => Err(PassthruError::ERR_INVALID_DEVICE_ID)
+    }
+}
+
+/// Channel ID handed to the application, for the channel `local_id` in the table of an adapter
+pub fn channel_id(device_id: u32, local_id: u32) -> u32 {
+    (device_id - DEVICE_ID) * CHANNEL_ID_STRIDE + local_id
+}
+
+/// Splits a channel ID into the ID of its adapter, and the ID of the channel in its table
+pub fn split_channel_id(channel_id: u32) -> (u32, u32) {
+    (DEVICE_ID + channel_id / CHANNEL_ID_STRIDE, channel_id % CHANNEL_ID_STRIDE)
+}
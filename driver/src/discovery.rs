@@ -0,0 +1,100 @@
+// Finds adapters when the config does not say where they are, or the port it names has gone,
+// such as /dev/ttyACM0 coming back as /dev/ttyACM1 after a replug.
+//
+// Serial ports with the USB IDs the adapters enumerate with are asked for their firmware
+// version. The USB bridges are generic parts, so a matching ID is only a hint. Only ports
+// whose firmware answers as an M2 or A0 are used
+
+use crate::comm::{probe_device_model, DeviceConfig, DeviceModel};
+use crate::logger;
+use crate::selector::Selector;
+
+/// USB vendor and product IDs of each adapter
+const KNOWN_USB_IDS: [(u16, u16, DeviceModel); 3] = [
+    (0x2341, 0x003E, DeviceModel::M2), // SAM3X native USB, as the Arduino Due
+    (0x10C4, 0xEA60, DeviceModel::A0), // CP210x UART bridge
+    (0x1A86, 0x7523, DeviceModel::A0), // CH340 UART bridge
+];
+
+/// Serial port on the system
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PortInfo {
+    pub name: String,
+    /// Vendor and product ID, if the port is on USB
+    pub usb_id: Option<(u16, u16)>,
+}
+
+/// Lists serial ports, and asks what is on them
+pub trait PortEnumerator {
+    fn ports(&self) -> Vec<PortInfo>;
+    /// Returns the model of adapter on `port`, or None if nothing answers like one.
+    /// The port is opened as `expected`, the adapter its USB ID belongs to
+    fn probe(&self, port: &str, expected: DeviceModel) -> Option<DeviceModel>;
+}
+
+/// The serial ports of this machine
+pub struct SystemPorts;
+
+impl PortEnumerator for SystemPorts {
+    fn ports(&self) -> Vec<PortInfo> {
+        serialport::available_ports().unwrap_or_default().into_iter().map(|p| PortInfo {
+            usb_id: match p.port_type {
+                serialport::SerialPortType::UsbPort(usb) => Some((usb.vid, usb.pid)),
+                _ => None
+            },
+            name: p.port_name,
+        }).collect()
+    }
+
+    fn probe(&self, port: &str, expected: DeviceModel) -> Option<DeviceModel> {
+        probe_device_model(port, expected)
+    }
+}
+
+/// Model an adapter with this USB ID would be
+fn usb_model(usb_id: (u16, u16)) -> Option<DeviceModel> {
+    KNOWN_USB_IDS.iter().find(|(vid, pid, _)| (*vid, *pid) == usb_id).map(|(_, _, m)| *m)
+}
+
+/// Finds the adapters attached to ports which are not open already. Settings other than the port
+/// come from the config for the same model, if there is one
+pub fn discover(ports: &dyn PortEnumerator, configs: &[DeviceConfig], in_use: &dyn Fn(&str) -> bool) -> Vec<DeviceConfig> {
+    let mut found = Vec::new();
+    for port in ports.ports() {
+        let expected = match port.usb_id.and_then(usb_model) {
+            Some(m) => m,
+            None => continue
+        };
+        if in_use(&port.name) {
+            continue;
+        }
+        let model = match ports.probe(&port.name, expected) {
+            Some(m) => m,
+            None => {
+                logger::log_info(format!("{} looks like an {:?} but did not respond to probe", port.name, expected));
+                continue;
+            }
+        };
+        logger::log_info(format!("Found {:?} on {}", model, port.name));
+        let settings = configs.iter().find(|c| c.model == Some(model)).map(|c| c.settings.clone()).unwrap_or_default();
+        found.push(DeviceConfig { port: port.name, model: Some(model), settings });
+    }
+    found
+}
+
+/// Adds the adapters found by `discover` to `configs`, if none of the configured ports can be opened.
+/// A port given to PassThruOpen is used as is, so nothing is probed
+pub fn add_discovered(
+    selector: &Selector,
+    configs: &mut Vec<DeviceConfig>,
+    ports: &dyn PortEnumerator,
+    port_exists: &dyn Fn(&str) -> bool,
+    in_use: &dyn Fn(&str) -> bool
+) {
+    if matches!(selector, Selector::Port { .. }) || configs.iter().any(|c| port_exists(&c.port) && !in_use(&c.port)) {
+        return;
+    }
+    let found = discover(ports, configs, in_use);
+    // The port each adapter is really on comes first, before the stale ones
+    configs.splice(0..0, found);
+}
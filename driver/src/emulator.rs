@@ -0,0 +1,637 @@
+// In process emulation of the adapter firmware found in `firmware/`.
+// This speaks the same CommMsg protocol as the real M2 / A0, so the
+// whole driver can be exercised by the tests without any hardware attached.
+//
+// An emulator plugged into a port named `emulator:<name>` is found by the driver the same way
+// as an adapter on a serial port, by putting that port in the config or passing it to PassThruOpen
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::sync::{Arc, Mutex, atomic::AtomicBool, atomic::Ordering};
+use std::thread::spawn;
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use j2534_rust::{FilterType, IoctlParam, PassthruError, Protocol};
+use lazy_static::lazy_static;
+use crate::comm::*;
+use crate::frame::FrameDecoder;
+use crate::capabilities::{Capabilities, PROTOCOL_VERSION_MAJOR, PROTOCOL_VERSION_MINOR};
+use crate::device_info::DeviceInfo;
+use crate::transport::{memory, memory::MemoryTransport, Transport};
+
+lazy_static! {
+    /// Emulator plugged into each port. Kept until another is plugged into the same port
+    static ref PORTS: Mutex<HashMap<String, Arc<Emulator>>> = Mutex::new(HashMap::new());
+}
+
+/// Returns the emulator plugged into `port`
+pub fn on_port(port: &str) -> Option<Arc<Emulator>> {
+    PORTS.lock().unwrap().get(port).cloned()
+}
+
+/// Returns true if an emulator is plugged into `port`, and has not been unplugged
+pub fn port_exists(port: &str) -> bool {
+    on_port(port).is_some_and(|e| e.plugged_in.load(Ordering::Relaxed))
+}
+
+/// Opens the driver side of a link to the emulator on `port`, as if it was a serial port
+pub fn open_port(port: &str) -> std::io::Result<MemoryTransport> {
+    on_port(port).and_then(|e| e.open_link()).ok_or_else(|| Error::new(ErrorKind::NotFound, format!("No emulator plugged into {}", port)))
+}
+
+// Channel IDs, as defined in channel.h of the firmware
+const CAN_CHANNEL_ID: u32 = 0;
+const KLINE_CHANNEL_ID: u32 = 1;
+const CAN1_CHANNEL_ID: u32 = 4;
+
+/// Most filter mailboxes of any adapter
+const MAX_MAILBOX_COUNT: usize = 10;
+
+fn mailbox_count(model: DeviceModel) -> usize {
+    match model {
+        DeviceModel::M2 => 7,
+        DeviceModel::A0 => 10,
+    }
+}
+
+fn can_bus_count(model: DeviceModel) -> usize {
+    match model {
+        DeviceModel::M2 => 2,
+        DeviceModel::A0 => 1,
+    }
+}
+
+fn capabilities(model: DeviceModel, (version_major, version_minor): (u8, u8)) -> Capabilities {
+    let mut protocols = 1 << Protocol::CAN as u32 | 1 << Protocol::ISO15765 as u32;
+    if model == DeviceModel::M2 {
+        protocols |= 1 << Protocol::ISO9141 as u32;
+    }
+    Capabilities {
+        version_major,
+        version_minor,
+        // Tx args are [Channel ID, Tx flags, Data]
+        max_payload: (COMM_MSG_ARG_SIZE - 8) as u16,
+        protocols,
+        max_filters: mailbox_count(model) as u8,
+        can_buses: can_bus_count(model) as u8,
+    }
+}
+
+fn device_info(model: DeviceModel, started: std::time::Instant) -> DeviceInfo {
+    let (name, serial) = match model {
+        DeviceModel::M2 => ("Macchina M2", "4D32454D554C41544F52000000000001"),
+        DeviceModel::A0 => ("Macchina A0", "24:0A:C4:00:00:01"),
+    };
+    DeviceInfo {
+        model: name.into(),
+        board_revision: "1".into(),
+        serial: serial.into(),
+        uptime_ms: started.elapsed().as_millis() as u32,
+    }
+}
+
+fn firmware_version(model: DeviceModel) -> &'static str {
+    match model {
+        DeviceModel::M2 => "1.0.0_M2",
+        DeviceModel::A0 => "1.0.1_A0",
+    }
+}
+
+/// Rx status flag for a transmit confirmation
+pub const TX_MSG_TYPE: u32 = 0x00000001;
+
+/// Battery voltage the emulator reports by default, in mV
+pub const DEFAULT_BATT_MV: u32 = 12000;
+
+/// Encodes a CommMsg the way the firmware sends it to the driver.
+/// Unless told otherwise in the hello, the M2 sends an entire COMM_MSG struct,
+/// so the frame is followed by zero padding
+pub fn encode_frame(padded: bool, msg: &CommMsg) -> Vec<u8> {
+    let mut buf = msg.to_slice();
+    if padded && buf.len() < COMM_MSG_SIZE {
+        buf.resize(COMM_MSG_SIZE, 0x00);
+    }
+    buf
+}
+
+/// Frame that the firmware put on the vehicle bus
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxFrame {
+    pub channel_id: u32,
+    pub tx_flags: u32,
+    pub data: Vec<u8>,
+    /// When the frame was sent
+    pub at: std::time::Instant,
+}
+
+struct EmuChannel {
+    protocol: u32,
+    filters: [bool; MAX_MAILBOX_COUNT],
+    loopback: bool,
+    block_size: u32,
+    sep_time: u32,
+}
+
+impl EmuChannel {
+    fn new(protocol: u32) -> Self {
+        Self { protocol, filters: [false; MAX_MAILBOX_COUNT], loopback: false, block_size: 0, sep_time: 0 }
+    }
+}
+
+struct EmuState {
+    model: DeviceModel,
+    /// Pad frames to COMM_MSG_SIZE
+    padded: bool,
+    /// Behave like firmware from before the hello flags, which always pads on M2
+    legacy_framing: bool,
+    last_id: u8,
+    connected: bool,
+    /// Firmware has hung, so nothing gets an answer
+    hung: bool,
+    /// CommMsg protocol version reported, as (Major, Minor)
+    protocol_version: (u8, u8),
+    /// Responses kept back, whilst the firmware is holding them
+    held: Option<Vec<CommMsg>>,
+    /// When the emulated adapter powered on
+    started: std::time::Instant,
+    batt_mv: u32,
+    channels: HashMap<u32, EmuChannel>,
+    tx_frames: Vec<TxFrame>,
+}
+
+impl EmuState {
+    /// Back to how the firmware starts up after power on
+    fn reset(&mut self) {
+        self.started = std::time::Instant::now();
+        self.channels.clear();
+        self.connected = false;
+        self.padded = self.model == DeviceModel::M2;
+    }
+
+    fn ok(&self, op: MsgType, args: &[u8]) -> CommMsg {
+        let mut res = CommMsg::new_with_args(op, &[PassthruError::STATUS_NOERROR as u8]);
+        res.args.extend_from_slice(args);
+        res.msg_id = self.last_id;
+        res
+    }
+
+    fn err(&self, op: MsgType, status: PassthruError, txt: &str) -> CommMsg {
+        let mut res = CommMsg::new_with_args(op, &[status as u8]);
+        res.args.extend_from_slice(txt.as_bytes());
+        res.msg_id = self.last_id;
+        res
+    }
+
+    /// Processes a message from the driver, returning what the firmware would send back
+    fn handle(&mut self, msg: &CommMsg) -> Vec<CommMsg> {
+        if self.hung {
+            return Vec::new();
+        }
+        if msg.msg_id != 0 {
+            self.last_id = msg.msg_id;
+        }
+        match msg.msg_type {
+            MsgType::StatusMsg if msg.args.first() == Some(&STATUS_KEEPALIVE) => vec![self.ok(MsgType::StatusMsg, &[])],
+            MsgType::StatusMsg => {
+                // Both hello and goodbye reset the adapter back to idle
+                self.channels.clear();
+                self.connected = msg.args.first() == Some(&STATUS_HELLO);
+                let unpadded = self.connected && msg.args.get(1).is_some_and(|f| f & HELLO_FLAG_UNPADDED != 0);
+                self.padded = self.model == DeviceModel::M2 && (self.legacy_framing || !unpadded);
+                Vec::new()
+            },
+            MsgType::ReadBatt => vec![self.ok(MsgType::ReadBatt, &self.batt_mv.to_le_bytes())],
+            MsgType::GetFwVersion => vec![self.ok(MsgType::GetFwVersion, firmware_version(self.model).as_bytes())],
+            MsgType::GetCapabilities => vec![self.ok(MsgType::GetCapabilities, &capabilities(self.model, self.protocol_version).to_args())],
+            MsgType::GetDeviceInfo => vec![self.ok(MsgType::GetDeviceInfo, &device_info(self.model, self.started).to_args())],
+            MsgType::OpenChannel => vec![self.open_channel(&msg.args)],
+            MsgType::CloseChannel => vec![self.close_channel(&msg.args)],
+            MsgType::SetChannelFilter => vec![self.set_filter(&msg.args)],
+            MsgType::RemoveChannelFilter => vec![self.remove_filter(&msg.args)],
+            MsgType::TransmitChannelData => self.transmit(&msg.args, msg.msg_id != 0),
+            MsgType::IoctlSet => vec![self.ioctl_set(&msg.args)],
+            MsgType::IoctlGet => vec![self.ioctl_get(&msg.args)],
+            MsgType::InitLinChannel => vec![self.init_lin(&msg.args)],
+            _ => Vec::new()
+        }
+    }
+
+    fn open_channel(&mut self, args: &[u8]) -> CommMsg {
+        if args.len() != 16 {
+            return self.err(MsgType::OpenChannel, PassthruError::ERR_FAILED, &format!("Payload size for OpenChannel is incorrect. Want 16, got {}", args.len()));
+        }
+        let id = LittleEndian::read_u32(&args[0..4]);
+        let protocol = LittleEndian::read_u32(&args[4..8]);
+        let supported = match id {
+            CAN_CHANNEL_ID => true,
+            KLINE_CHANNEL_ID => self.model == DeviceModel::M2,
+            CAN1_CHANNEL_ID => can_bus_count(self.model) > 1,
+            _ => false
+        };
+        if !supported {
+            return self.err(MsgType::OpenChannel, PassthruError::ERR_FAILED, "Protocol unsupported");
+        }
+        if self.channels.contains_key(&id) {
+            return self.err(MsgType::OpenChannel, PassthruError::ERR_CHANNEL_IN_USE, "");
+        }
+        if id == KLINE_CHANNEL_ID && protocol != Protocol::ISO9141 as u32 {
+            return self.err(MsgType::OpenChannel, PassthruError::ERR_NOT_SUPPORTED, "");
+        }
+        self.channels.insert(id, EmuChannel::new(protocol));
+        self.ok(MsgType::OpenChannel, &[])
+    }
+
+    fn close_channel(&mut self, args: &[u8]) -> CommMsg {
+        if args.len() != 4 {
+            return self.err(MsgType::CloseChannel, PassthruError::ERR_FAILED, &format!("Payload size for CloseChannel is incorrect. Want 4, got {}", args.len()));
+        }
+        match self.channels.remove(&LittleEndian::read_u32(args)) {
+            Some(_) => self.ok(MsgType::CloseChannel, &[]),
+            None => self.err(MsgType::CloseChannel, PassthruError::ERR_INVALID_CHANNEL_ID, "")
+        }
+    }
+
+    fn set_filter(&mut self, args: &[u8]) -> CommMsg {
+        let channel_id = LittleEndian::read_u32(&args[0..4]);
+        let filter_id = LittleEndian::read_u32(&args[4..8]) as usize;
+        let filter_type = LittleEndian::read_u32(&args[8..12]);
+        let mask_size = LittleEndian::read_u32(&args[12..16]) as usize;
+        let pattern_size = LittleEndian::read_u32(&args[16..20]) as usize;
+        let fc_size = LittleEndian::read_u32(&args[20..24]) as usize;
+        if filter_type == FilterType::FLOW_CONTROL_FILTER as u32 && fc_size == 0 {
+            return self.err(MsgType::SetChannelFilter, PassthruError::ERR_NULL_PARAMETER, "ISO15765 FC filter is null");
+        }
+        let res = match self.channels.get(&channel_id) {
+            None => Err((PassthruError::ERR_INVALID_CHANNEL_ID, "Channel ID does not exist")),
+            Some(_) if channel_id == KLINE_CHANNEL_ID => Ok(()),
+            Some(c) if c.protocol == Protocol::ISO15765 as u32 => {
+                if filter_type != FilterType::FLOW_CONTROL_FILTER as u32 {
+                    Err((PassthruError::ERR_FAILED, "ISO15765 filter not valid type"))
+                } else if mask_size != 4 || pattern_size != 4 || fc_size != 4 {
+                    Err((PassthruError::ERR_FAILED, "Filter length not 4"))
+                } else {
+                    Ok(())
+                }
+            },
+            Some(_) => {
+                if filter_type == FilterType::FLOW_CONTROL_FILTER as u32 {
+                    Err((PassthruError::ERR_FAILED, "CAN Channel cannot use flow control filter"))
+                } else if mask_size > 4 || pattern_size > 4 {
+                    Err((PassthruError::ERR_FAILED, "Mask or pattern length too big"))
+                } else {
+                    Ok(())
+                }
+            }
+        };
+        if let Err((status, txt)) = res {
+            return self.err(MsgType::SetChannelFilter, status, txt);
+        }
+        if filter_id >= mailbox_count(self.model) {
+            return self.err(MsgType::SetChannelFilter, PassthruError::ERR_EXCEEDED_LIMIT, "");
+        }
+        let channel = self.channels.get_mut(&channel_id).unwrap();
+        if channel.filters[filter_id] {
+            return self.err(MsgType::SetChannelFilter, PassthruError::ERR_FAILED, "Filter ID in use");
+        }
+        channel.filters[filter_id] = true;
+        self.ok(MsgType::SetChannelFilter, &[])
+    }
+
+    fn remove_filter(&mut self, args: &[u8]) -> CommMsg {
+        if args.len() != 8 {
+            return self.err(MsgType::RemoveChannelFilter, PassthruError::ERR_FAILED, "Message size not valid");
+        }
+        let channel_id = LittleEndian::read_u32(&args[0..4]);
+        let filter_id = LittleEndian::read_u32(&args[4..8]) as usize;
+        match self.channels.get_mut(&channel_id) {
+            None => self.err(MsgType::RemoveChannelFilter, PassthruError::ERR_INVALID_CHANNEL_ID, "Channel null"),
+            Some(c) => {
+                if filter_id < mailbox_count(self.model) && c.filters[filter_id] {
+                    c.filters[filter_id] = false;
+                    self.ok(MsgType::RemoveChannelFilter, &[])
+                } else {
+                    self.err(MsgType::RemoveChannelFilter, PassthruError::ERR_INVALID_FILTER_ID, "")
+                }
+            }
+        }
+    }
+
+    fn transmit(&mut self, args: &[u8], respond: bool) -> Vec<CommMsg> {
+        let channel_id = LittleEndian::read_u32(&args[0..4]);
+        let tx_flags = LittleEndian::read_u32(&args[4..8]);
+        let data = &args[8..];
+        let mut res = Vec::new();
+        let (protocol, loopback) = match self.channels.get(&channel_id) {
+            Some(c) => (c.protocol, c.loopback),
+            None => {
+                if respond {
+                    res.push(self.err(MsgType::TransmitChannelData, PassthruError::ERR_INVALID_CHANNEL_ID, ""));
+                } else {
+                    res.push(CommMsg::new_with_args(MsgType::LogMsg, b"Cannot send, Channel null!"));
+                }
+                return res;
+            }
+        };
+        self.tx_frames.push(TxFrame { channel_id, tx_flags, data: data.to_vec(), at: std::time::Instant::now() });
+        if respond {
+            res.push(self.ok(MsgType::TransmitChannelData, &[]));
+        }
+        if protocol == Protocol::ISO15765 as u32 {
+            // Firmware confirms every ISO-TP send with the CAN ID of the payload.
+            // Multi frame sends complete straight away, as if the ECU had sent flow control
+            res.push(rx_data_msg(channel_id, TX_MSG_TYPE, &data[0..std::cmp::min(4, data.len())]));
+        } else if loopback {
+            res.push(rx_data_msg(channel_id, TX_MSG_TYPE, data));
+        }
+        res
+    }
+
+    fn ioctl_set(&mut self, args: &[u8]) -> CommMsg {
+        if args.len() != 9 {
+            return self.err(MsgType::IoctlSet, PassthruError::ERR_FAILED, "IOCTL set request invalid length");
+        }
+        let channel_id = args[0] as u32;
+        let ioctl_id = LittleEndian::read_u32(&args[1..5]);
+        let value = LittleEndian::read_u32(&args[5..9]);
+        let channel = match self.channels.get_mut(&channel_id) {
+            Some(c) => c,
+            None => return self.err(MsgType::IoctlSet, PassthruError::ERR_FAILED, "Channel is null!")
+        };
+        if channel_id == KLINE_CHANNEL_ID {
+            return self.ok(MsgType::IoctlSet, &[]);
+        }
+        if channel.protocol == Protocol::ISO15765 as u32 {
+            if ioctl_id == IoctlParam::ISO15765_STMIN as u32 {
+                channel.sep_time = value;
+            } else if ioctl_id == IoctlParam::ISO15765_BS as u32 {
+                channel.block_size = value;
+            } else {
+                return self.err(MsgType::IoctlSet, PassthruError::ERR_INVALID_IOCTL_ID, "ISO15765 invalid IOCTL ID");
+            }
+        } else if ioctl_id == IoctlParam::LOOPBACK as u32 {
+            channel.loopback = value != 0;
+        } else {
+            return self.err(MsgType::IoctlSet, PassthruError::ERR_FAILED, "CAN IOCTL set unimplemented");
+        }
+        self.ok(MsgType::IoctlSet, &[])
+    }
+
+    fn ioctl_get(&mut self, args: &[u8]) -> CommMsg {
+        if args.len() != 5 {
+            return self.err(MsgType::IoctlGet, PassthruError::ERR_FAILED, "IOCTL get request invalid length");
+        }
+        let channel_id = args[0] as u32;
+        let ioctl_id = LittleEndian::read_u32(&args[1..5]);
+        let channel = match self.channels.get(&channel_id) {
+            Some(c) => c,
+            None => return self.err(MsgType::IoctlGet, PassthruError::ERR_FAILED, "Channel is null!")
+        };
+        if channel_id == KLINE_CHANNEL_ID {
+            return self.err(MsgType::IoctlGet, PassthruError::ERR_FAILED, "ISO9141 IOCTL get unimplemented");
+        }
+        if channel.protocol != Protocol::ISO15765 as u32 {
+            return self.err(MsgType::IoctlGet, PassthruError::ERR_FAILED, "CAN IOCTL get unimplemented");
+        }
+        if ioctl_id == IoctlParam::ISO15765_STMIN as u32 {
+            self.ok(MsgType::IoctlGet, &channel.sep_time.to_le_bytes())
+        } else if ioctl_id == IoctlParam::ISO15765_BS as u32 {
+            self.ok(MsgType::IoctlGet, &channel.block_size.to_le_bytes())
+        } else {
+            self.err(MsgType::IoctlGet, PassthruError::ERR_INVALID_IOCTL_ID, "ISO15765 invalid IOCTL ID")
+        }
+    }
+
+    fn init_lin(&mut self, args: &[u8]) -> CommMsg {
+        let channel_id = LittleEndian::read_u32(&args[0..4]);
+        if channel_id != KLINE_CHANNEL_ID || !self.channels.contains_key(&channel_id) {
+            return self.err(MsgType::InitLinChannel, PassthruError::ERR_INVALID_CHANNEL_ID, "");
+        }
+        match args[4] {
+            // Fast init, reply as an ECU accepting StartCommunication would
+            1 => self.ok(MsgType::InitLinChannel, &[0xC1, 0xEF, 0x8F]),
+            _ => self.err(MsgType::InitLinChannel, PassthruError::ERR_FAILED, "Five baud init TODO")
+        }
+    }
+}
+
+fn rx_data_msg(channel_id: u32, rx_status: u32, data: &[u8]) -> CommMsg {
+    let mut args: Vec<u8> = vec![channel_id as u8];
+    args.write_u32::<LittleEndian>(rx_status).unwrap();
+    args.extend_from_slice(data);
+    CommMsg::new_with_args(MsgType::ReceiveChannelData, &args)
+}
+
+/// Emulated adapter. The firmware loop runs on its own thread
+/// until the emulator is dropped
+pub struct Emulator {
+    state: Arc<Mutex<EmuState>>,
+    /// Side of the link the firmware reads from
+    rx_link: Arc<Mutex<Box<dyn Transport>>>,
+    /// Side of the link the firmware writes to
+    link: Arc<Mutex<Box<dyn Transport>>>,
+    /// Firmware side of the in memory link, if it is one, so it can be unplugged
+    plug: Mutex<Option<MemoryTransport>>,
+    /// False whilst the emulator is unplugged
+    plugged_in: AtomicBool,
+    rate: Option<u32>,
+    is_running: Arc<AtomicBool>,
+}
+
+impl Emulator {
+    /// Starts the emulator, returning it along with the link the driver should use
+    pub fn start(model: DeviceModel) -> (Self, MemoryTransport) {
+        Emulator::start_with_rate(model, None)
+    }
+
+    /// Starts the emulator on a link limited to `rate` bytes per second
+    pub fn start_with_rate(model: DeviceModel, rate: Option<u32>) -> (Self, MemoryTransport) {
+        let (host, fw) = memory::pair_with_rate(rate);
+        let mut emulator = Emulator::start_on(Box::new(fw.clone()), model);
+        emulator.plug = Mutex::new(Some(fw));
+        emulator.rate = rate;
+        (emulator, host)
+    }
+
+    /// Starts the emulator on the firmware side of an existing link
+    pub fn start_on(link: Box<dyn Transport>, model: DeviceModel) -> Self {
+        let state = Arc::new(Mutex::new(EmuState {
+            model,
+            padded: model == DeviceModel::M2,
+            legacy_framing: false,
+            last_id: 0,
+            connected: false,
+            hung: false,
+            protocol_version: (PROTOCOL_VERSION_MAJOR, PROTOCOL_VERSION_MINOR),
+            held: None,
+            started: std::time::Instant::now(),
+            batt_mv: DEFAULT_BATT_MV,
+            channels: HashMap::new(),
+            tx_frames: Vec::new(),
+        }));
+        let is_running = Arc::new(AtomicBool::new(true));
+
+        let state_t = state.clone();
+        let is_running_t = is_running.clone();
+        let rx_link = Arc::new(Mutex::new(link.try_clone().expect("Could not clone emulator link")));
+        let rx_link_t = rx_link.clone();
+        // Responses and injected frames share one writer, so frames never interleave
+        let link = Arc::new(Mutex::new(link));
+        let link_t = link.clone();
+        spawn(move || {
+            let mut decoder = FrameDecoder::new();
+            let mut tmp = [0u8; 4096];
+            while is_running_t.load(Ordering::Relaxed) {
+                let res = rx_link_t.lock().unwrap().read(&mut tmp);
+                match res {
+                    Ok(read) => decoder.push(&tmp[..read]),
+                    Err(e) if e.kind() == ErrorKind::TimedOut => {},
+                    Err(_) => {
+                        // Unplugged, wait to be given a new link
+                        decoder = FrameDecoder::new();
+                        std::thread::sleep(std::time::Duration::from_millis(10));
+                    }
+                }
+                while let Some(msg) = decoder.next_msg() {
+                    let (responses, padded) = {
+                        let mut state = state_t.lock().unwrap();
+                        let responses = state.handle(&msg);
+                        match state.held.as_mut() {
+                            Some(held) => {
+                                held.extend(responses);
+                                (Vec::new(), state.padded)
+                            },
+                            None => (responses, state.padded)
+                        }
+                    };
+                    let mut writer = link_t.lock().unwrap();
+                    for res in responses {
+                        let _ = writer.write_all(&encode_frame(padded, &res));
+                    }
+                }
+            }
+        });
+        Self { state, rx_link, link, plug: Mutex::new(None), plugged_in: AtomicBool::new(true), rate: None, is_running }
+    }
+
+    /// Pulls the adapter out. The link breaks, and the firmware loses
+    /// everything the driver set up, as it is powered from USB
+    pub fn unplug(&self) {
+        self.plugged_in.store(false, Ordering::Relaxed);
+        if let Some(plug) = self.plug.lock().unwrap().as_ref() {
+            plug.close();
+        }
+        self.state.lock().unwrap().reset();
+    }
+
+    /// Plugs the adapter back in, after which its port can be opened again
+    pub fn plug_in(&self) {
+        self.plugged_in.store(true, Ordering::Relaxed);
+    }
+
+    /// Starts an emulator and plugs it into `port`, in place of any emulator already there.
+    /// Nothing is connected to it until the driver opens the port
+    pub fn plug_into(port: &str, model: DeviceModel) -> Arc<Emulator> {
+        let (emulator, _) = Emulator::start(model);
+        let emulator = Arc::new(emulator);
+        PORTS.lock().unwrap().insert(port.to_string(), emulator.clone());
+        emulator
+    }
+
+    /// Returns the driver side of a new link, as if the serial port was opened again.
+    /// Any link opened before is broken. None whilst the emulator is unplugged
+    fn open_link(&self) -> Option<MemoryTransport> {
+        let mut plug = self.plug.lock().unwrap();
+        if !self.plugged_in.load(Ordering::Relaxed) {
+            return None;
+        }
+        if let Some(old) = plug.as_ref() {
+            old.close();
+        }
+        let (host, fw) = memory::pair_with_rate(self.rate);
+        *self.rx_link.lock().unwrap() = Box::new(fw.clone());
+        *self.link.lock().unwrap() = Box::new(fw.clone());
+        *plug = Some(fw);
+        Some(host)
+    }
+
+    /// Pretends a frame arrived on the vehicle bus for a channel
+    pub fn inject_rx(&self, channel_id: u32, rx_status: u32, data: &[u8]) {
+        let padded = self.state.lock().unwrap().padded;
+        let _ = self.link.lock().unwrap().write_all(&encode_frame(padded, &rx_data_msg(channel_id, rx_status, data)));
+    }
+
+    /// Makes the emulator ignore the hello flags, like older firmware does.
+    /// Must be called before the driver says hello
+    pub fn set_legacy_framing(&self, legacy: bool) {
+        self.state.lock().unwrap().legacy_framing = legacy;
+    }
+
+    /// Returns true if the driver still has its end of the link open
+    pub fn driver_has_link_open(&self) -> bool {
+        self.plug.lock().unwrap().as_ref().is_some_and(|p| p.peer_is_open())
+    }
+
+    /// Sets the CommMsg protocol version the firmware reports
+    pub fn set_protocol_version(&self, major: u8, minor: u8) {
+        self.state.lock().unwrap().protocol_version = (major, minor);
+    }
+
+    /// Makes the firmware stop answering anything, whilst the link stays up
+    pub fn set_hung(&self, hung: bool) {
+        self.state.lock().unwrap().hung = hung;
+    }
+
+    /// Whilst holding, the firmware still acts on everything the driver sends, but keeps its
+    /// responses back. They are all sent, in order, once it stops
+    pub fn set_holding(&self, hold: bool) {
+        let (held, padded) = {
+            let mut state = self.state.lock().unwrap();
+            let held = match hold {
+                true => {
+                    state.held.get_or_insert_with(Vec::new);
+                    Vec::new()
+                },
+                false => state.held.take().unwrap_or_default()
+            };
+            (held, state.padded)
+        };
+        let mut writer = self.link.lock().unwrap();
+        for res in held {
+            let _ = writer.write_all(&encode_frame(padded, &res));
+        }
+    }
+
+    /// Returns true if frames sent to the driver are padded to COMM_MSG_SIZE
+    pub fn is_padding_frames(&self) -> bool {
+        self.state.lock().unwrap().padded
+    }
+
+    /// Returns every frame the firmware has transmitted so far
+    pub fn tx_frames(&self) -> Vec<TxFrame> {
+        self.state.lock().unwrap().tx_frames.clone()
+    }
+
+    /// Returns true if the driver has opened the channel
+    pub fn has_channel(&self, channel_id: u32) -> bool {
+        self.state.lock().unwrap().channels.contains_key(&channel_id)
+    }
+
+    /// Returns true if the channel is open, and has a filter in mailbox `filter_id`
+    pub fn has_filter(&self, channel_id: u32, filter_id: usize) -> bool {
+        self.state.lock().unwrap().channels.get(&channel_id).is_some_and(|c| c.filters[filter_id])
+    }
+
+    /// Returns true if the driver has said hello, and not yet goodbye
+    pub fn is_connected(&self) -> bool {
+        self.state.lock().unwrap().connected
+    }
+
+    pub fn set_batt_mv(&self, mv: u32) {
+        self.state.lock().unwrap().batt_mv = mv;
+    }
+}
+
+impl Drop for Emulator {
+    fn drop(&mut self) {
+        self.is_running.store(false, Ordering::Relaxed);
+    }
+}
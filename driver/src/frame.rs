@@ -0,0 +1,148 @@
+// Framing of CommMsgs on the link to the adapter, used in both directions.
+//
+// Each frame is [0xA5, 0x5A, Size (2 bytes), ID, Type, Args, CRC (2 bytes)].
+// Size covers ID, Type and Args, and the CRC covers everything after the sync marker.
+// If bytes are dropped or corrupted, the decoder throws away data until it finds the
+// next sync marker that starts a frame with a valid CRC, so the link recovers by itself.
+// A false marker can claim a size the data after it never makes up, so whilst waiting for
+// the rest of a frame, a valid frame which has already come in after its marker is taken instead
+
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use crate::comm::{CommMsg, MsgType, COMM_MSG_ARG_SIZE};
+
+pub const SYNC: [u8; 2] = [0xA5, 0x5A];
+/// Sync marker and size
+const HEADER_SIZE: usize = 4;
+const CRC_SIZE: usize = 2;
+/// Largest value of the size field, ID and Type plus a full COMM_MSG worth of args
+const MAX_FRAME_PAYLOAD: usize = COMM_MSG_ARG_SIZE + 2;
+
+/// CRC-16/CCITT-FALSE, same as `frame_crc` in the firmware
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for b in data {
+        crc ^= (*b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Builds the frame for a message
+pub fn encode(msg_id: u8, msg_type: MsgType, args: &[u8]) -> Vec<u8> {
+    let mut frame: Vec<u8> = Vec::with_capacity(args.len() + HEADER_SIZE + 2 + CRC_SIZE);
+    frame.extend_from_slice(&SYNC);
+    frame.write_u16::<LittleEndian>(args.len() as u16 + 2).unwrap();
+    frame.push(msg_id);
+    frame.push(msg_type as u8);
+    frame.extend_from_slice(args);
+    let crc = crc16(&frame[SYNC.len()..]);
+    frame.write_u16::<LittleEndian>(crc).unwrap();
+    frame
+}
+
+/// What the data from a sync marker on is
+enum Candidate {
+    /// Frame with a valid CRC, of this size
+    Valid(usize),
+    /// Cannot be a frame
+    Invalid,
+    /// Need more data to tell
+    Incomplete,
+}
+
+/// Turns the byte stream from the adapter back into CommMsgs
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    /// Bytes thrown away whilst looking for a frame, not counting zero padding
+    discarded: usize,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds data read from the link
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Returns the next complete message, or None if more data is needed
+    pub fn next_msg(&mut self) -> Option<CommMsg> {
+        loop {
+            match self.buffer.windows(SYNC.len()).position(|w| w == SYNC) {
+                Some(pos) => self.discard(pos),
+                None => {
+                    // Keep a trailing half of the sync marker, the rest of it may be in the next read
+                    let keep = if self.buffer.last() == Some(&SYNC[0]) { 1 } else { 0 };
+                    self.discard(self.buffer.len() - keep);
+                    return None;
+                }
+            }
+            let size = match self.candidate(0) {
+                Candidate::Valid(size) => size,
+                Candidate::Invalid => {
+                    // Not a real frame, look for the next marker
+                    self.discard(1);
+                    continue;
+                },
+                Candidate::Incomplete => match self.later_frame() {
+                    Some(pos) => {
+                        self.discard(pos);
+                        continue;
+                    },
+                    None => return None
+                }
+            };
+            let frame_len = HEADER_SIZE + size + CRC_SIZE;
+            let msg = CommMsg {
+                msg_id: self.buffer[HEADER_SIZE],
+                msg_type: MsgType::from_u8(&self.buffer[HEADER_SIZE + 1]),
+                args: Vec::from(&self.buffer[HEADER_SIZE + 2..HEADER_SIZE + size]),
+            };
+            self.buffer.drain(0..frame_len);
+            return Some(msg);
+        }
+    }
+
+    /// Checks the data from the sync marker at `pos` in the buffer
+    fn candidate(&self, pos: usize) -> Candidate {
+        let data = &self.buffer[pos..];
+        if data.len() < HEADER_SIZE {
+            return Candidate::Incomplete;
+        }
+        let size = LittleEndian::read_u16(&data[2..4]) as usize;
+        if !(2..=MAX_FRAME_PAYLOAD).contains(&size) {
+            return Candidate::Invalid;
+        }
+        let frame_len = HEADER_SIZE + size + CRC_SIZE;
+        if data.len() < frame_len {
+            return Candidate::Incomplete;
+        }
+        let crc = LittleEndian::read_u16(&data[HEADER_SIZE + size..frame_len]);
+        if crc != crc16(&data[SYNC.len()..HEADER_SIZE + size]) {
+            return Candidate::Invalid;
+        }
+        Candidate::Valid(size)
+    }
+
+    /// Finds a complete, valid frame after the one at the start of the buffer
+    fn later_frame(&self) -> Option<usize> {
+        (1..self.buffer.len().saturating_sub(1))
+            .filter(|pos| self.buffer[*pos..*pos + SYNC.len()] == SYNC)
+            .find(|pos| matches!(self.candidate(*pos), Candidate::Valid(_)))
+    }
+
+    /// Returns how many bytes were thrown away since the last call
+    pub fn take_discarded(&mut self) -> usize {
+        std::mem::take(&mut self.discarded)
+    }
+
+    fn discard(&mut self, count: usize) {
+        self.discarded += self.buffer[0..count].iter().filter(|b| **b != 0x00).count();
+        self.buffer.drain(0..count);
+    }
+}
@@ -0,0 +1,141 @@
+// Indications on CAN and ISO15765 channels, which apps use to follow a message through the adapter.
+//
+// The driver makes TX_DONE itself, once the adapter confirms a message has gone, followed by the
+// loopback copy if LOOPBACK is on. What the adapter sends is checked before it reaches the app:
+// its own Tx confirmations are dropped, and a first frame indication is cut down to the CAN ID.
+// A first frame indication warns the app a message is on its way, so one cannot be made up once
+// the message is in. A multi frame message the adapter did not send one for is logged instead
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use j2534_rust::{Protocol, PASSTHRU_MSG};
+use crate::rx_queue::RxQueue;
+use crate::logger::log_warn;
+
+/// RxStatus bits, from the J2534 spec
+pub const TX_MSG_TYPE: u32 = 0x00000001;
+pub const ISO15765_FIRST_FRAME: u32 = 0x00000002;
+pub const TX_DONE: u32 = 0x00000008;
+pub const ISO15765_PADDING_ERROR: u32 = 0x00000010;
+/// Also TxFlags bits, which are carried over to the RxStatus of TX_DONE and loopback messages
+pub const ISO15765_ADDR_TYPE: u32 = 0x00000080;
+pub const CAN_29BIT_ID: u32 = 0x00000100;
+
+/// Largest ISO15765 payload which fits in a single frame, with normal and extended addressing
+const SINGLE_FRAME_MAX: [usize; 2] = [7, 6];
+
+/// A reception the adapter gave up on never completes, so its first frame is forgotten after this long.
+/// Longer than a message of the largest size takes at the default flow control timings
+const FIRST_FRAME_EXPIRY: Duration = Duration::from_secs(10);
+
+fn has_indications(protocol: Protocol) -> bool {
+    matches!(protocol, Protocol::CAN | Protocol::ISO15765)
+}
+
+/// Bytes of CAN ID at the start of a message, with the extended address if there is one
+fn id_len(protocol: Protocol, flags: u32) -> usize {
+    if protocol == Protocol::ISO15765 && flags & ISO15765_ADDR_TYPE != 0 { 5 } else { 4 }
+}
+
+/// Puts what the app should see of a sent message in the Rx queue, once the adapter has confirmed it
+#[derive(Debug)]
+pub struct TxIndications {
+    protocol: Protocol,
+    rx_data: Arc<RxQueue>,
+    loopback: Arc<AtomicBool>,
+}
+
+impl TxIndications {
+    pub fn new(protocol: Protocol, rx_data: Arc<RxQueue>, loopback: Arc<AtomicBool>) -> Self {
+        Self { protocol, rx_data, loopback }
+    }
+
+    /// TX_DONE with the CAN ID of the message, then the whole message if LOOPBACK is on
+    pub fn sent(&self, fw_id: u32, msg: &PASSTHRU_MSG) {
+        if !has_indications(self.protocol) {
+            return;
+        }
+        let flags = msg.tx_flags & (ISO15765_ADDR_TYPE | CAN_29BIT_ID);
+        let mut tx_done = PASSTHRU_MSG {
+            protocol_id: msg.protocol_id,
+            rx_status: TX_MSG_TYPE | TX_DONE | flags,
+            data_size: std::cmp::min(id_len(self.protocol, flags), msg.data_size as usize) as u32,
+            ..Default::default()
+        };
+        tx_done.data[..tx_done.data_size as usize].copy_from_slice(&msg.data[..tx_done.data_size as usize]);
+        self.push(fw_id, tx_done);
+        if self.loopback.load(Ordering::Relaxed) {
+            self.push(fw_id, PASSTHRU_MSG { rx_status: TX_MSG_TYPE | flags, tx_flags: 0, ..*msg });
+        }
+    }
+
+    fn push(&self, fw_id: u32, msg: PASSTHRU_MSG) {
+        if let Err(1) = self.rx_data.push(msg) {
+            log_warn(format!("Rx queue in channel {} is full. Data has been lost!", fw_id));
+        }
+    }
+}
+
+/// Checks what the adapter sends on a channel before it goes to the app
+#[derive(Debug)]
+pub struct RxIndications {
+    protocol: Protocol,
+    /// CAN IDs a first frame indication has been passed on for, whose message has not come yet, and when
+    first_frames: Mutex<Vec<(Vec<u8>, Instant)>>,
+}
+
+impl RxIndications {
+    pub fn new(protocol: Protocol) -> Self {
+        Self { protocol, first_frames: Mutex::new(Vec::new()) }
+    }
+
+    /// # Returns
+    /// What to pass on, as RxStatus and data, if anything
+    pub fn check<'a>(&self, rx_status: u32, data: &'a [u8]) -> Option<(u32, &'a [u8])> {
+        if !has_indications(self.protocol) {
+            return Some((rx_status, data));
+        }
+        if rx_status & (TX_MSG_TYPE | TX_DONE) != 0 {
+            // Made by the driver instead, once the adapter confirms the send
+            return None;
+        }
+        let flags = rx_status & (ISO15765_ADDR_TYPE | CAN_29BIT_ID);
+        if self.protocol == Protocol::CAN {
+            return Some((flags, data));
+        }
+        let msg_status = flags | (rx_status & ISO15765_PADDING_ERROR);
+        let id_len = id_len(self.protocol, flags);
+        if data.len() < id_len {
+            log_warn(format!("Dropping ISO15765 message of {} bytes, too short for a CAN ID (RxStatus {:08X})", data.len(), rx_status));
+            return None;
+        }
+        let id = &data[..id_len];
+        let mut first_frames = self.first_frames.lock().unwrap();
+        first_frames.retain(|(_, at)| at.elapsed() < FIRST_FRAME_EXPIRY);
+        let pending = first_frames.iter().position(|(f, _)| f == id);
+        if rx_status & ISO15765_FIRST_FRAME != 0 {
+            // Starts again if the last reception from the ID was given up on
+            match pending {
+                Some(i) => first_frames[i].1 = Instant::now(),
+                None => first_frames.push((id.to_vec(), Instant::now()))
+            }
+            return Some((ISO15765_FIRST_FRAME | flags, id));
+        }
+        match pending {
+            Some(i) => {
+                first_frames.swap_remove(i);
+            },
+            None if data.len() - id_len > SINGLE_FRAME_MAX[id_len - 4] => {
+                log_warn(format!("Adapter sent no first frame indication for the {} byte ISO15765 message from {:02X?}", data.len(), id));
+            },
+            None => {}
+        }
+        Some((msg_status, data))
+    }
+
+    /// Forgets first frames whose message has not come. Used when the Rx buffer is cleared
+    pub fn clear(&self) {
+        self.first_frames.lock().unwrap().clear();
+    }
+}
@@ -1,19 +1,98 @@
 use std::convert::TryFrom;
 
 use j2534_rust::{IoctlParam, PASSTHRU_MSG, PassthruError, SBYTE_ARRAY, SConfigList};
-use crate::{channels, comm::*, logger::{log_debug, log_error_str, log_warn, log_warn_str}};
+use crate::{channels, devices, comm::*, logger::{log_debug, log_error_str, log_warn, log_warn_str}};
 use crate::logger::{log_error};
+use crate::device_info::SDEVICE_INFO;
 use byteorder::{LittleEndian, ByteOrder, WriteBytesExt};
 
+/// IOCTL IDs from here on are specific to this driver
+pub const TOOL_IOCTL_BASE: u32 = 0x20;
+/// Output: unsigned long, set to a `LinkStatus`
+pub const GET_DEVICE_STATUS: u32 = 0x20;
+/// Output: SDEVICE_INFO
+pub const GET_DEVICE_INFO: u32 = 0x21;
+/// Channel only. Output: SRX_DROPPED
+pub const GET_RX_DROPPED: u32 = 0x22;
+
+/// Output of the GET_RX_DROPPED IOCTL
+#[repr(C)]
+#[allow(non_camel_case_types)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct SRX_DROPPED {
+    /// Messages dropped since the channel was connected
+    pub total: u32,
+    /// Messages dropped since PassThruReadMsgs last returned ERR_BUFFER_OVERFLOW
+    pub unreported: u32,
+}
+
+/// Runs a tool specific IOCTL on the adapter `handle` is, or has a channel on
+pub fn tool_ioctl(handle: u32, ioctl_id: u32, output_ptr: *mut libc::c_void) -> PassthruError {
+    let device = match devices::from_handle(handle) {
+        Ok(d) => d,
+        Err(_) => return PassthruError::ERR_DEVICE_NOT_CONNECTED
+    };
+    match ioctl_id {
+        GET_DEVICE_STATUS => {
+            if output_ptr.is_null() {
+                log_error_str("Cannot read device status. Output ptr is null");
+                return PassthruError::ERR_NULL_PARAMETER
+            }
+            let status = device.connection.read().unwrap().as_ref().map(|dev| dev.link_status());
+            match status {
+                Some(s) => {
+                    unsafe { *(output_ptr as *mut u32) = s as u32 };
+                    PassthruError::STATUS_NOERROR
+                },
+                None => PassthruError::ERR_DEVICE_NOT_CONNECTED
+            }
+        },
+        GET_DEVICE_INFO => {
+            if output_ptr.is_null() {
+                log_error_str("Cannot read device info. Output ptr is null");
+                return PassthruError::ERR_NULL_PARAMETER
+            }
+            match run_on_m2(device.id, |dev| dev.read_device_info()) {
+                Ok(info) => {
+                    unsafe { (*(output_ptr as *mut SDEVICE_INFO)).set(&info) };
+                    PassthruError::STATUS_NOERROR
+                },
+                Err(e) => e
+            }
+        },
+        GET_RX_DROPPED => {
+            if output_ptr.is_null() {
+                log_error_str("Cannot read dropped Rx messages. Output ptr is null");
+                return PassthruError::ERR_NULL_PARAMETER
+            }
+            match channels::ChannelComm::rx_dropped(handle) {
+                Ok((total, unreported)) => {
+                    unsafe { *(output_ptr as *mut SRX_DROPPED) = SRX_DROPPED { total, unreported } };
+                    PassthruError::STATUS_NOERROR
+                },
+                Err(e) => e
+            }
+        },
+        _ => {
+            log_error(format!("Tool specific IOCTL {:08X} is invalid", ioctl_id));
+            PassthruError::ERR_INVALID_IOCTL_ID
+        }
+    }
+}
+
 
 /// Reads the battery voltage into an output pointer, storing the value as mV
 /// # Params
 /// * output_ptr - Output pointer to store batter voltage into
 
 static mut LAST_VBATT : u32 = 0;
-pub fn read_vbatt(output_ptr: *mut u32) -> PassthruError {
-    match run_on_m2(|dev| {
-        match dev.write_and_read_ptcmd(&mut CommMsg::new(MsgType::ReadBatt), 250) {
+pub fn read_vbatt(handle: u32, output_ptr: *mut u32) -> PassthruError {
+    let device_id = match devices::from_handle(handle) {
+        Ok(d) => d.id,
+        Err(_) => return PassthruError::ERR_DEVICE_NOT_CONNECTED
+    };
+    match run_on_m2(device_id, |dev| {
+        match dev.write_and_read_ptcmd(&mut CommMsg::new(MsgType::ReadBatt), dev.settings().timeouts.command_ms) {
             M2Resp::Ok(args) => {
                 if args.len() < 4 { // This should stop a panic from randomly occurring when M2 is under load
                     log_error("Error reading battery voltage - Args size was not correct, returning last known".into());
@@ -96,16 +175,20 @@ pub fn five_baud_init(channel_id: u32, input: &mut SBYTE_ARRAY, output: &mut SBY
     }
     log_error(format!("{:02X?}", req_bytes));
     //let res_bytes = unsafe { slice::from_raw_parts_mut(output.byte_ptr as *mut u8, input.num_of_bytes as usize) };
-    match run_on_m2(|dev| {
+    let (device_id, fw_id) = match channels::ChannelComm::firmware_channel(channel_id) {
+        Ok(x) => x,
+        Err(e) => return e
+    };
+    match run_on_m2(device_id, |dev| {
         let mut req_args: Vec<u8> = Vec::new();
         // Channel id (4 bytes)
-        req_args.write_u32::<LittleEndian>(channel_id).unwrap();
+        req_args.write_u32::<LittleEndian>(fw_id).unwrap();
         // Init type (1 byte)
         req_args.push(0);
         // Input data (X bytes)
         req_args.extend_from_slice(&req_bytes);
         let mut req = CommMsg::new_with_args(MsgType::InitLinChannel, &req_args);
-        match dev.write_and_read_ptcmd(&mut req, 10000) { // Long wait for this command!
+        match dev.write_and_read_ptcmd(&mut req, dev.settings().timeouts.init_ms) { // Long wait for this command!
             M2Resp::Ok(res) => Ok(res),
             M2Resp::Err { status, string } => {
                 log_error(format!("Error initializing LIN channel (Status {:?}): {}", status, string));
@@ -127,16 +210,20 @@ pub fn fast_init(channel_id: u32, input: &mut PASSTHRU_MSG, output: &mut PASSTHR
     log_error_str("Fast init requested");
     let req_bytes: &[u8] = &input.data[0..input.data_size as usize];
     log_debug(format!("Req: {:02X?}", req_bytes));
-    match run_on_m2(|dev| {
+    let (device_id, fw_id) = match channels::ChannelComm::firmware_channel(channel_id) {
+        Ok(x) => x,
+        Err(e) => return e
+    };
+    match run_on_m2(device_id, |dev| {
         let mut req_args: Vec<u8> = Vec::new();
         // Channel id (4 bytes)
-        req_args.write_u32::<LittleEndian>(channel_id).unwrap();
+        req_args.write_u32::<LittleEndian>(fw_id).unwrap();
         // Init type (1 byte)
         req_args.push(1);
         // Input data (X bytes)
         req_args.extend_from_slice(req_bytes);
         let mut req = CommMsg::new_with_args(MsgType::InitLinChannel, &req_args);
-        match dev.write_and_read_ptcmd(&mut req, 10000) { // Long wait for this command!
+        match dev.write_and_read_ptcmd(&mut req, dev.settings().timeouts.init_ms) { // Long wait for this command!
             M2Resp::Ok(res) => Ok(res),
             M2Resp::Err { status, string } => {
                 log_error(format!("Error initializing LIN channel (Status {:?}): {}", status, string));
@@ -165,10 +252,8 @@ pub fn clear_rx_buffer(channel_id: u32) -> PassthruError {
     channels::ChannelComm::clear_rx_buffer(channel_id)
 }
 
-#[allow(unused_variables)] // TODO
 pub fn clear_periodic_msgs(channel_id: u32) -> PassthruError {
-    log_warn_str("Clear periodic messages unimplemented");
-    PassthruError::STATUS_NOERROR
+    channels::ChannelComm::clear_periodic(channel_id)
 }
 
 pub fn clear_msg_filters(channel_id: u32) -> PassthruError {
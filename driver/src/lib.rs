@@ -7,9 +7,24 @@ mod comm;
 mod channels;
 mod ioctl;
 mod passthru_drv;
+mod transport;
+mod frame;
+mod pending;
+mod capabilities;
+mod device_info;
+mod devices;
+mod selector;
+mod discovery;
+mod config;
+mod periodic;
+mod tx_queue;
+mod rx_queue;
+mod indications;
 use logger::log_error_str;
 use passthru_drv::*;
 
+#[cfg(test)]
+mod emulator;
 #[cfg(test)]
 mod lib_tests;
 
@@ -23,8 +38,8 @@ pub extern "stdcall" fn DllMain(_module: u32, _reason: u32, _reserved: *mut std:
 
 #[no_mangle]
 #[allow(non_snake_case)]
-pub extern "stdcall" fn PassThruOpen(_name: *mut libc::c_void, device_id: *mut u32) -> i32 {
-    passthru_open(device_id) as i32
+pub extern "stdcall" fn PassThruOpen(pName: *const libc::c_void, pDeviceID: *mut u32) -> i32 {
+    passthru_open(pName as *const c_char, pDeviceID) as i32
 }
 
 #[no_mangle]
@@ -54,12 +69,12 @@ pub extern "stdcall" fn PassThruDisconnect(ChannelID: u32) -> i32 {
 #[no_mangle]
 #[allow(non_snake_case)]
 pub extern "stdcall" fn PassThruReadVersion(
-    _device_id: u32,
+    device_id: u32,
     fw_version_ptr: *mut c_char,
     dll_version_ptr: *mut c_char,
     api_version_ptr: *mut c_char,
 ) -> i32 {
-    passthru_read_version(fw_version_ptr, dll_version_ptr, api_version_ptr) as i32
+    passthru_read_version(device_id, fw_version_ptr, dll_version_ptr, api_version_ptr) as i32
 }
 
 #[no_mangle]
@@ -117,20 +132,20 @@ pub extern "stdcall" fn PassThruWriteMsgs(
 }
 
 #[no_mangle]
-#[allow(non_snake_case, unused_variables)]
+#[allow(non_snake_case)]
 pub extern "stdcall" fn PassThruStartPeriodicMsg(
     ChannelID: u32,
     pMsg: *const PASSTHRU_MSG,
-    pMsgID: *const u32,
+    pMsgID: *mut u32,
     TimeInterval: u32,
 ) -> i32 {
-    PassthruError::STATUS_NOERROR as i32
+    passthru_drv::start_periodic_msg(ChannelID, pMsg, pMsgID, TimeInterval) as i32
 }
 
 #[no_mangle]
-#[allow(non_snake_case, unused_variables)]
+#[allow(non_snake_case)]
 pub extern "stdcall" fn PassThruStopPeriodicMsg(ChannelID: u32, MsgID: u32) -> i32 {
-    PassthruError::STATUS_NOERROR as i32
+    passthru_drv::stop_periodic_msg(ChannelID, MsgID) as i32
 }
 
 #[no_mangle]
@@ -2,61 +2,1633 @@
 
 #[cfg(test)]
 mod tests {
-    use crate::{PassThruConnect, PassThruOpen, passthru_drv};
+    use crate::{capabilities, devices, indications, ioctl, passthru_drv};
+    use crate::device_info::{DeviceInfo, SDEVICE_INFO};
     use crate::comm::*;
-    use crate::channels::ChannelComm;
+    use crate::channels::{self, ChannelComm};
+    use crate::emulator::{self, Emulator};
     use j2534_rust::*;
-    use passthru_drv::{passthru_close, passthru_connect, passthru_open, set_channel_filter};
+    use passthru_drv::{passthru_close, passthru_connect, passthru_disconnect, passthru_ioctl, passthru_open, passthru_read_version, read_msgs, set_channel_filter, start_periodic_msg, stop_periodic_msg, write_msgs};
+    use crate::transport::memory;
+    use crate::frame::{self, FrameDecoder};
+    use crate::selector::Selector;
+    use crate::config::{Settings, Timeouts};
+    use crate::discovery::{self, PortEnumerator, PortInfo};
+    use crate::pending::PendingRequests;
+    use std::time::Duration;
+    #[cfg(target_os = "linux")]
+    use crate::transport::pty;
+    use std::io::{Read, Write};
+    use std::sync::{Arc, Mutex};
+    use byteorder::{ByteOrder, LittleEndian};
+    use lazy_static::lazy_static;
+
+    lazy_static! {
+        // Tests share the config and emulator ports, and expect the device they open to be the first one, so cannot run in parallel
+        static ref TEST_LOCK: Mutex<()> = Mutex::new(());
+    }
+
+    /// Port the emulator of most tests is plugged into, which the config points at
+    const TEST_PORT: &str = "emulator:1";
+    /// Port for a second emulator, not in the config unless a test puts it there
+    const SECOND_PORT: &str = "emulator:2";
+
+    fn lock_device() -> std::sync::MutexGuard<'static, ()> {
+        TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Writes the driver config, as the user would, with one file for each port and `settings` in each.
+    /// DEVICE is left out, so the driver probes the adapter to find out what it is
+    fn write_configs(ports: &[&str], settings: serde_json::Value) {
+        let dir = json_dir();
+        std::fs::create_dir_all(&dir).unwrap();
+        for (i, file) in ["macchina_m2.json", "macchina_a0.json"].iter().enumerate() {
+            match ports.get(i) {
+                Some(port) => {
+                    let mut cfg = settings.clone();
+                    cfg["COM-PORT"] = (*port).into();
+                    std::fs::write(dir.join(file), cfg.to_string()).unwrap();
+                },
+                None => {
+                    let _ = std::fs::remove_file(dir.join(file));
+                }
+            }
+        }
+    }
+
+    /// Plugs a fresh emulator into TEST_PORT, and points the config at it
+    fn plug_in(model: DeviceModel) -> Arc<Emulator> {
+        write_configs(&[TEST_PORT], serde_json::json!({}));
+        Emulator::plug_into(TEST_PORT, model)
+    }
+
+    /// Runs a test against the emulator, once pretending to be each adapter model
+    fn each_model<F: Fn(DeviceModel)>(test: F) {
+        let _lock = lock_device();
+        for model in [DeviceModel::M2, DeviceModel::A0].iter() {
+            plug_in(*model);
+            test(*model);
+        }
+    }
+
+    fn with_emulator<T, F: FnOnce(&Emulator) -> T>(op: F) -> T {
+        op(&emulator::on_port(TEST_PORT).expect("Emulator not plugged in"))
+    }
+
+    fn with_emulator_of<T, F: FnOnce(&Emulator) -> T>(device_id: u32, op: F) -> T {
+        let port = devices::get(device_id).expect("Device not open").port.clone();
+        op(&emulator::on_port(&port).expect("Emulator not plugged in"))
+    }
+
+    /// ID the firmware, and so the emulator, knows a channel by
+    fn fw_channel(channel_id: u32) -> u32 {
+        channels::ChannelComm::firmware_channel(channel_id).expect("Channel not open").1
+    }
+
+    /// Runs `op` on the connection of an open device, even if its link is down
+    fn with_connection<T, F: FnOnce(&MacchinaM2) -> T>(device_id: u32, op: F) -> T {
+        let device = devices::get(device_id).expect("Device not open");
+        let conn = device.connection.read().unwrap();
+        op(conn.as_ref().expect("Device has no connection"))
+    }
+
+    fn open_device() -> u32 {
+        let mut dev_idx: u32 = 0;
+        assert_eq!(passthru_open(std::ptr::null(), &mut dev_idx), PassthruError::STATUS_NOERROR);
+        dev_idx
+    }
+
+    fn can_msg(protocol: Protocol, data: &[u8]) -> PASSTHRU_MSG {
+        let mut msg = PASSTHRU_MSG {
+            protocol_id: protocol as u32,
+            data_size: data.len() as u32,
+            ..Default::default()
+        };
+        msg.data[..data.len()].copy_from_slice(data);
+        msg
+    }
 
     #[test]
     fn test_channel() {
-        let mut dev_idx: u32 = 0;
-        assert!(passthru_open(&mut dev_idx) == PassthruError::STATUS_NOERROR);
-        
-        let mut channel_idx: u32 = 0;
-        assert!(passthru_connect(dev_idx, Protocol::CAN as u32, 0, 500_000, &mut channel_idx) == PassthruError::STATUS_NOERROR);
+        each_model(|_| {
+            let dev_idx = open_device();
+
+            let mut channel_idx: u32 = 0;
+            assert!(passthru_connect(dev_idx, Protocol::CAN as u32, 0, 500_000, &mut channel_idx) == PassthruError::STATUS_NOERROR);
 
-        let mut filter_idx: u32 = 0;
-        let mut mask = PASSTHRU_MSG::default();
-        let mut ptn = PASSTHRU_MSG::default();
-        mask.protocol_id = Protocol::CAN as u32;
-        mask.data_size = 4;
-        mask.data[0] = 0xFF;
-        mask.data[1] = 0xFF;
-        mask.data[2] = 0xFF;
-        mask.data[3] = 0xFF;
+            let mut filter_idx: u32 = 0;
+            let mut mask = PASSTHRU_MSG::default();
+            let mut ptn = PASSTHRU_MSG::default();
+            mask.protocol_id = Protocol::CAN as u32;
+            mask.data_size = 4;
+            mask.data[0] = 0xFF;
+            mask.data[1] = 0xFF;
+            mask.data[2] = 0xFF;
+            mask.data[3] = 0xFF;
 
-        ptn.protocol_id = Protocol::CAN as u32;
-        ptn.data_size = 4;
-        ptn.data[0] = 0x00;
-        ptn.data[1] = 0x00;
-        ptn.data[2] = 0x03;
-        ptn.data[3] = 0x08;
+            ptn.protocol_id = Protocol::CAN as u32;
+            ptn.data_size = 4;
+            ptn.data[0] = 0x00;
+            ptn.data[1] = 0x00;
+            ptn.data[2] = 0x03;
+            ptn.data[3] = 0x08;
 
-        assert!(set_channel_filter(channel_idx, FilterType::PASS_FILTER, &mask, &ptn, std::ptr::null(), &mut filter_idx) == PassthruError::STATUS_NOERROR);
-        std::thread::sleep(std::time::Duration::from_millis(1000));
-        assert!(passthru_close(dev_idx) == PassthruError::STATUS_NOERROR);
+            assert!(set_channel_filter(channel_idx, FilterType::PASS_FILTER, &mask, &ptn, std::ptr::null(), &mut filter_idx) == PassthruError::STATUS_NOERROR);
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            assert!(passthru_close(dev_idx) == PassthruError::STATUS_NOERROR);
+        });
     }
 
-    //#[cfg(feature="A0")]
     #[test]
     pub fn test_large_tx_size() {
+        each_model(|_| {
+            let dev_idx = open_device();
+
+            let mut channel_idx: u32 = 0;
+            assert!(passthru_connect(dev_idx, Protocol::ISO15765 as u32, 0, 500_000, &mut channel_idx) == PassthruError::STATUS_NOERROR);
+
+            let ptmsg = PASSTHRU_MSG {
+                protocol_id: Protocol::ISO15765 as u32,
+                rx_status: 0x00,
+                tx_flags: 0x40,
+                timestamp: 0,
+                data_size: 4096+4,
+                extra_data_size: 0,
+                data: [0; 4128]
+            };
+
+            assert_eq!(ChannelComm::write_channel_data(channel_idx, std::slice::from_ref(&ptmsg), 1000), (1, Ok(())));
+            assert_eq!(with_emulator(|e| e.tx_frames())[0].data.len(), 4096+4);
+            assert!(passthru_close(dev_idx) == PassthruError::STATUS_NOERROR);
+        });
+    }
+
+    #[test]
+    fn test_open_twice() {
+        each_model(|_| {
+            let dev_idx = open_device();
+            assert!(with_emulator(|e| e.is_connected()));
+            let mut second_idx: u32 = 0;
+            assert_eq!(passthru_open(std::ptr::null(), &mut second_idx), PassthruError::ERR_DEVICE_IN_USE);
+            assert_eq!(passthru_close(dev_idx), PassthruError::STATUS_NOERROR);
+        });
+    }
+
+    #[test]
+    fn test_two_adapters() {
+        let _lock = lock_device();
+        Emulator::plug_into(TEST_PORT, DeviceModel::M2);
+        Emulator::plug_into(SECOND_PORT, DeviceModel::A0);
+        write_configs(&[TEST_PORT, SECOND_PORT], serde_json::json!({}));
+        let m2_idx = open_device();
+        let a0_idx = open_device();
+        assert_ne!(m2_idx, a0_idx);
+        let mut third_idx: u32 = 0;
+        assert_eq!(passthru_open(std::ptr::null(), &mut third_idx), PassthruError::ERR_DEVICE_IN_USE);
+
+        // Each adapter has its own CAN channel
+        let mut m2_chan: u32 = 0;
+        let mut a0_chan: u32 = 0;
+        assert_eq!(passthru_connect(m2_idx, Protocol::CAN as u32, 0, 500_000, &mut m2_chan), PassthruError::STATUS_NOERROR);
+        assert_eq!(passthru_connect(a0_idx, Protocol::CAN as u32, 0, 500_000, &mut a0_chan), PassthruError::STATUS_NOERROR);
+        assert_ne!(m2_chan, a0_chan);
+
+        let tx = can_msg(Protocol::CAN, &[0x00, 0x00, 0x07, 0xE0, 0x01, 0x3E]);
+        let mut num_msgs: u32 = 1;
+        assert_eq!(write_msgs(a0_chan, &tx, &mut num_msgs, 100), PassthruError::STATUS_NOERROR);
+        assert_eq!(with_emulator_of(a0_idx, |e| e.tx_frames()).len(), 1);
+        assert!(with_emulator_of(m2_idx, |e| e.tx_frames()).is_empty());
+        assert_eq!(passthru_ioctl(a0_chan, IoctlID::CLEAR_RX_BUFFER as u32, std::ptr::null_mut(), std::ptr::null_mut()), PassthruError::STATUS_NOERROR); // TX_DONE
+
+        // Frames received by one adapter only turn up on its channel
+        with_emulator_of(a0_idx, |e| e.inject_rx(fw_channel(a0_chan), 0, &[0x00, 0x00, 0x07, 0xE8, 0x01, 0x7E]));
+        let mut rx = PASSTHRU_MSG::default();
+        let mut num_msgs: u32 = 1;
+        assert_eq!(read_msgs(a0_chan, &mut rx, &mut num_msgs, 500), PassthruError::STATUS_NOERROR);
+        assert_eq!(&rx.data[..rx.data_size as usize], &[0x00, 0x00, 0x07, 0xE8, 0x01, 0x7E]);
+        let mut num_msgs: u32 = 1;
+        assert_eq!(read_msgs(m2_chan, &mut rx, &mut num_msgs, 0), PassthruError::ERR_BUFFER_EMPTY);
+
+        // Device level calls go to the adapter they are given
+        let mut fw = [0 as libc::c_char; 80];
+        let mut dll = [0 as libc::c_char; 80];
+        let mut api = [0 as libc::c_char; 80];
+        assert_eq!(passthru_read_version(a0_idx, fw.as_mut_ptr(), dll.as_mut_ptr(), api.as_mut_ptr()), PassthruError::STATUS_NOERROR);
+        assert!(c_str(&fw).ends_with("_A0"));
+        with_emulator_of(a0_idx, |e| e.set_batt_mv(13800));
+        for (handle, expected) in [(m2_idx, emulator::DEFAULT_BATT_MV), (a0_idx, 13800), (a0_chan, 13800)].iter() {
+            let mut vbatt: u32 = 0;
+            let res = passthru_ioctl(*handle, IoctlID::READ_VBATT as u32, std::ptr::null_mut(), &mut vbatt as *mut u32 as *mut libc::c_void);
+            assert_eq!(res, PassthruError::STATUS_NOERROR);
+            assert_eq!(vbatt, *expected);
+        }
+
+        // Closing one leaves the other open
+        assert_eq!(passthru_close(m2_idx), PassthruError::STATUS_NOERROR);
+        assert_eq!(passthru_close(m2_idx), PassthruError::ERR_INVALID_DEVICE_ID);
+        let mut num_msgs: u32 = 1;
+        assert_eq!(write_msgs(m2_chan, &tx, &mut num_msgs, 100), PassthruError::ERR_INVALID_CHANNEL_ID);
+        let mut num_msgs: u32 = 1;
+        assert_eq!(write_msgs(a0_chan, &tx, &mut num_msgs, 100), PassthruError::STATUS_NOERROR);
+        assert_eq!(passthru_close(a0_idx), PassthruError::STATUS_NOERROR);
+    }
+
+    #[test]
+    fn test_selector_parsing() {
+        assert_eq!(Selector::parse(""), Ok(Selector::Default));
+        assert_eq!(Selector::from_ptr(std::ptr::null()), Ok(Selector::Default));
+        for port in ["COM3", "com12", "\\\\.\\COM15", "/dev/ttyACM1"].iter() {
+            assert_eq!(Selector::parse(port), Ok(Selector::Port { path: port.to_string(), model: None }));
+        }
+        assert_eq!(Selector::parse("serial:///dev/ttyUSB0?device=a0"), Ok(Selector::Port { path: "/dev/ttyUSB0".into(), model: Some(DeviceModel::A0) }));
+        assert_eq!(Selector::parse("SERIAL://COM4"), Ok(Selector::Port { path: "COM4".into(), model: None }));
+        assert_eq!(Selector::parse("24:0A:C4:00:00:01"), Ok(Selector::Serial("24:0A:C4:00:00:01".into())));
+        assert_eq!(Selector::parse("COMPUTER"), Ok(Selector::Serial("COMPUTER".into())));
+        assert!(Selector::parse("tcp://192.168.0.2").is_err());
+        assert!(Selector::parse("serial://").is_err());
+        assert!(Selector::parse("serial://COM4?device=M3").is_err());
+        assert!(Selector::parse("serial://COM4?baud=9600").is_err());
+    }
+
+    #[test]
+    fn test_select_configs() {
+        let settings = Settings { heartbeat: Heartbeat { interval_ms: 500, max_misses: 2 }, ..Default::default() };
+        let cfg = |port: &str, model| DeviceConfig { port: port.into(), model, settings: settings.clone() };
+        let configs = vec![cfg("/dev/ttyACM0", Some(DeviceModel::M2)), cfg("/dev/ttyUSB0", Some(DeviceModel::A0))];
+        let ports = |sel: &Selector, exists: &dyn Fn(&str) -> bool, in_use: &dyn Fn(&str) -> bool| -> Vec<String> {
+            select_configs(sel, &configs, exists, in_use).into_iter().map(|c| c.port).collect()
+        };
+        let all = |_: &str| true;
+        let none = |_: &str| false;
+
+        // Config decides, skipping ports that are missing or open
+        assert_eq!(ports(&Selector::Default, &all, &none), vec!["/dev/ttyACM0"]);
+        assert_eq!(ports(&Selector::Default, &|p| p == "/dev/ttyUSB0", &none), vec!["/dev/ttyUSB0"]);
+        assert_eq!(ports(&Selector::Default, &all, &|p| p == "/dev/ttyACM0"), vec!["/dev/ttyUSB0"]);
+        assert_eq!(ports(&Selector::Default, &all, &all), vec!["/dev/ttyACM0"]);
+
+        // Port keeps its settings from the config, unless told otherwise
+        let sel = Selector::Port { path: "/dev/ttyUSB0".into(), model: None };
+        let picked = select_configs(&sel, &configs, &none, &none);
+        assert_eq!((picked[0].model, picked[0].settings.heartbeat.interval_ms), (Some(DeviceModel::A0), 500));
+        let sel = Selector::Port { path: "/dev/ttyUSB1".into(), model: Some(DeviceModel::M2) };
+        let picked = select_configs(&sel, &configs, &none, &none);
+        assert_eq!((picked[0].port.as_str(), picked[0].model, &picked[0].settings), ("/dev/ttyUSB1", Some(DeviceModel::M2), &Settings::default()));
+
+        // Every adapter that could have the serial number is tried
+        let sel = Selector::Serial("1234".into());
+        assert_eq!(ports(&sel, &all, &none), vec!["/dev/ttyACM0", "/dev/ttyUSB0"]);
+        assert_eq!(ports(&sel, &all, &|p| p == "/dev/ttyACM0"), vec!["/dev/ttyUSB0"]);
+    }
+
+    #[test]
+    fn test_settings_parsing() {
+        let parse = |json: &str| Settings::from_json(&serde_json::from_str(json).unwrap());
+        // Shipped configs only have the port and manifest keys
+        let shipped = parse(include_str!("../macchina_m2.json")).unwrap();
+        assert_eq!(shipped, Settings { protocols_claimed: 0x78, protocols_disabled: 0x786, ..Default::default() });
+        assert_eq!(shipped.baud_rate_for(DeviceModel::M2), 500000);
+        assert_eq!(shipped.baud_rate_for(DeviceModel::A0), 2000000);
+
+        let tuned = parse(r#"{
+            "COM-PORT": "/dev/ttyUSB0", "BAUD_RATE": 921600, "PORT_TIMEOUT_MS": 20, "MAX_QUEUE_MSGS": 2000,
+            "HEARTBEAT_INTERVAL_MS": 0, "HEARTBEAT_MISSES": 5, "TX_TIMEOUT_MS": 3000, "INIT_TIMEOUT_MS": 15000,
+            "LOG_PATH": "/tmp/macchina.log"
+        }"#).unwrap();
+        assert_eq!(tuned.baud_rate_for(DeviceModel::A0), 921600);
+        assert_eq!((tuned.port_timeout_ms, tuned.max_queue_msgs), (20, 2000));
+        assert_eq!(tuned.heartbeat, Heartbeat { interval_ms: 0, max_misses: 5 });
+        assert_eq!(tuned.timeouts, Timeouts { tx_ms: 3000, init_ms: 15000, ..Default::default() });
+        assert_eq!(tuned.log_path, Some("/tmp/macchina.log".into()));
+
+        for (json, error) in [
+            (r#"{"MAX_QUEUE_MSGS": 0}"#, "MAX_QUEUE_MSGS must be a whole number from 1 to 100000, not 0"),
+            (r#"{"BAUD_RATE": "fast"}"#, "BAUD_RATE must be a whole number from 9600 to 4000000, not \"fast\""),
+            (r#"{"CMD_TIMEOUT_MS": 2.5}"#, "CMD_TIMEOUT_MS must be a whole number from 10 to 60000, not 2.5"),
+            (r#"{"PORT_TIMEOUT_MS": -1}"#, "PORT_TIMEOUT_MS must be a whole number from 1 to 1000, not -1"),
+            (r#"{"LOG_PATH": ""}"#, "LOG_PATH must be the path of a file, not \"\""),
+            (r#"{"ISO9141": "yes"}"#, "ISO9141 must be true or false, not \"yes\""),
+            (r#"{"CAN": 2}"#, "CAN must be true or false, not 2"),
+        ].iter() {
+            assert_eq!(parse(json), Err(error.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_manifest_protocols() {
+        let _lock = lock_device();
+        let manifest = |json: &str| Settings::from_json(&serde_json::from_str(json).unwrap()).unwrap();
+        // Registry flags are DWORDs
+        assert_eq!(manifest(r#"{"CAN": 1, "ISO15765": 0}"#), manifest(r#"{"CAN": true, "ISO15765": false}"#));
+
+        for (json, expected) in [
+            (include_str!("../macchina_m2.json"), vec![Protocol::ISO14230]),
+            (include_str!("../macchina_a0.json"), vec![]),
+        ].iter() {
+            let (emu, link) = Emulator::start(DeviceModel::M2);
+            let device = devices::register("memory").unwrap();
+            let mut settings = manifest(json);
+            settings.heartbeat.interval_ms = 0;
+            let mut dev = MacchinaM2::open_reconnecting(device.id, Box::new(link), DeviceModel::M2, None, settings).unwrap();
+            dev.query_capabilities().unwrap();
+            // Emulated M2 has no ISO14230
+            assert_eq!(&dev.settings().unsupported_claims(dev.capabilities().unwrap()), expected);
+            *device.connection.write().unwrap() = Some(dev);
+
+            // Turned off in the manifest, though the firmware has it
+            let mut channel_idx: u32 = 0;
+            let iso9141 = if json.contains(r#""ISO9141": true"#) { PassthruError::STATUS_NOERROR } else { PassthruError::ERR_NOT_SUPPORTED };
+            assert_eq!(passthru_connect(device.id, Protocol::ISO9141 as u32, 0, 10400, &mut channel_idx), iso9141);
+            if iso9141 == PassthruError::ERR_NOT_SUPPORTED {
+                assert_eq!(last_error(), "ISO9141 is turned off in the driver config");
+                // Never asked of the firmware
+                assert!(!emu.has_channel(1));
+            }
+            // Turned off in the manifest, and the firmware does not have it
+            assert_eq!(passthru_connect(device.id, Protocol::J1850PWM as u32, 0, 41600, &mut channel_idx), PassthruError::ERR_NOT_SUPPORTED);
+            assert_eq!(last_error(), "J1850PWM is turned off in the driver config");
+            assert_eq!(passthru_connect(device.id, Protocol::CAN as u32, 0, 500_000, &mut channel_idx), PassthruError::STATUS_NOERROR);
+            assert_eq!(passthru_close(device.id), PassthruError::STATUS_NOERROR);
+        }
+    }
+
+    #[test]
+    fn test_rx_queue_limit() {
+        let _lock = lock_device();
+        let (emu, link) = Emulator::start(DeviceModel::M2);
+        let device = devices::register("memory").unwrap();
+        let settings = Settings { max_queue_msgs: 3, heartbeat: Heartbeat { interval_ms: 0, ..Default::default() }, ..Default::default() };
+        *device.connection.write().unwrap() = Some(MacchinaM2::open_reconnecting(device.id, Box::new(link), DeviceModel::M2, None, settings).unwrap());
+        let mut channel_idx: u32 = 0;
+        assert_eq!(passthru_connect(device.id, Protocol::CAN as u32, 0, 500_000, &mut channel_idx), PassthruError::STATUS_NOERROR);
+        for i in 0..5u8 {
+            emu.inject_rx(fw_channel(channel_idx), 0, &[0x00, 0x00, 0x07, 0xE8, i]);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+        let mut rx = [PASSTHRU_MSG::default(), PASSTHRU_MSG::default(), PASSTHRU_MSG::default(), PASSTHRU_MSG::default(), PASSTHRU_MSG::default()];
+        let mut num_msgs: u32 = 5;
+        let mut dropped = ioctl::SRX_DROPPED::default();
+        let get_dropped = |dropped: &mut ioctl::SRX_DROPPED| passthru_ioctl(channel_idx, ioctl::GET_RX_DROPPED, std::ptr::null_mut(), dropped as *mut ioctl::SRX_DROPPED as *mut libc::c_void);
+        assert_eq!(get_dropped(&mut dropped), PassthruError::STATUS_NOERROR);
+        assert_eq!(dropped, ioctl::SRX_DROPPED { total: 2, unreported: 2 });
+        // The overflow is reported along with what was kept, the oldest, as later ones are dropped
+        assert_eq!(read_msgs(channel_idx, rx.as_mut_ptr(), &mut num_msgs, 0), PassthruError::ERR_BUFFER_OVERFLOW);
+        assert_eq!(num_msgs, 3);
+        assert_eq!(rx[2].data[4], 2);
+        assert_eq!(get_dropped(&mut dropped), PassthruError::STATUS_NOERROR);
+        assert_eq!(dropped, ioctl::SRX_DROPPED { total: 2, unreported: 0 });
+        // Only reported once
+        num_msgs = 5;
+        assert_eq!(read_msgs(channel_idx, rx.as_mut_ptr(), &mut num_msgs, 0), PassthruError::ERR_BUFFER_EMPTY);
+        assert_eq!(num_msgs, 0);
+
+        // Cleared with the buffer, but still counted
+        for i in 0..4u8 {
+            emu.inject_rx(fw_channel(channel_idx), 0, &[0x00, 0x00, 0x07, 0xE8, i]);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(passthru_ioctl(channel_idx, IoctlID::CLEAR_RX_BUFFER as u32, std::ptr::null_mut(), std::ptr::null_mut()), PassthruError::STATUS_NOERROR);
+        assert_eq!(get_dropped(&mut dropped), PassthruError::STATUS_NOERROR);
+        assert_eq!(dropped, ioctl::SRX_DROPPED { total: 3, unreported: 0 });
+        num_msgs = 5;
+        assert_eq!(read_msgs(channel_idx, rx.as_mut_ptr(), &mut num_msgs, 0), PassthruError::ERR_BUFFER_EMPTY);
+        assert_eq!(passthru_ioctl(channel_idx, ioctl::GET_RX_DROPPED, std::ptr::null_mut(), std::ptr::null_mut()), PassthruError::ERR_NULL_PARAMETER);
+        assert_eq!(passthru_ioctl(device.id, ioctl::GET_RX_DROPPED, std::ptr::null_mut(), &mut dropped as *mut ioctl::SRX_DROPPED as *mut libc::c_void), PassthruError::ERR_INVALID_CHANNEL_ID);
+        assert_eq!(passthru_close(device.id), PassthruError::STATUS_NOERROR);
+    }
+
+    #[test]
+    fn test_blocking_read() {
+        each_model(|_| {
+            let dev_idx = open_device();
+            let mut channel_idx: u32 = 0;
+            assert_eq!(passthru_connect(dev_idx, Protocol::CAN as u32, 0, 500_000, &mut channel_idx), PassthruError::STATUS_NOERROR);
+            let fw_id = fw_channel(channel_idx);
+            let mut rx = [PASSTHRU_MSG::default(), PASSTHRU_MSG::default()];
+            let mut num_msgs: u32 = 2;
+            assert_eq!(read_msgs(channel_idx, rx.as_mut_ptr(), &mut num_msgs, 0), PassthruError::ERR_BUFFER_EMPTY);
+            assert_eq!(num_msgs, 0);
+
+            // Returns once both are in, long before the timeout
+            let start = std::time::Instant::now();
+            std::thread::scope(|s| {
+                s.spawn(|| {
+                    for i in 0..2u8 {
+                        std::thread::sleep(Duration::from_millis(50));
+                        with_emulator(|e| e.inject_rx(fw_id, 0, &[0x00, 0x00, 0x07, 0xE8, i]));
+                    }
+                });
+                num_msgs = 2;
+                assert_eq!(read_msgs(channel_idx, rx.as_mut_ptr(), &mut num_msgs, 5000), PassthruError::STATUS_NOERROR);
+            });
+            assert!(start.elapsed() < Duration::from_millis(1000), "Read took {:?}", start.elapsed());
+            assert_eq!((num_msgs, rx[0].data[4], rx[1].data[4]), (2, 0, 1));
+
+            // Times out with what did arrive
+            with_emulator(|e| e.inject_rx(fw_id, 0, &[0x00, 0x00, 0x07, 0xE8, 2]));
+            std::thread::sleep(Duration::from_millis(50));
+            num_msgs = 2;
+            assert_eq!(read_msgs(channel_idx, rx.as_mut_ptr(), &mut num_msgs, 100), PassthruError::ERR_TIMEOUT);
+            assert_eq!((num_msgs, rx[0].data[4]), (1, 2));
+            assert_eq!(passthru_close(dev_idx), PassthruError::STATUS_NOERROR);
+        });
+    }
+
+    /// Port list of a machine which has an M2 and an A0 plugged in, along with hardware that is not an adapter
+    struct FakePorts {
+        ports: Vec<(PortInfo, Option<DeviceModel>)>,
+        /// Each port probed, and the adapter it was probed as
+        probed: std::cell::RefCell<Vec<(String, DeviceModel)>>,
+    }
+
+    impl PortEnumerator for FakePorts {
+        fn ports(&self) -> Vec<PortInfo> {
+            self.ports.iter().map(|(p, _)| p.clone()).collect()
+        }
+
+        fn probe(&self, port: &str, expected: DeviceModel) -> Option<DeviceModel> {
+            self.probed.borrow_mut().push((port.to_string(), expected));
+            self.ports.iter().find(|(p, _)| p.name == port).and_then(|(_, m)| *m)
+        }
+    }
+
+    fn fake_ports() -> FakePorts {
+        let port = |name: &str, usb_id| PortInfo { name: name.into(), usb_id };
+        FakePorts { probed: Default::default(), ports: vec![
+            (port("/dev/ttyS0", None), None),
+            (port("/dev/ttyACM0", Some((0x2341, 0x003E))), None), // Arduino Due, not an M2
+            (port("/dev/ttyACM1", Some((0x2341, 0x003E))), Some(DeviceModel::M2)),
+            (port("/dev/ttyUSB0", Some((0x0403, 0x6001))), Some(DeviceModel::A0)), // Not probed, unknown ID
+            (port("/dev/ttyUSB1", Some((0x10C4, 0xEA60))), Some(DeviceModel::A0)),
+        ]}
+    }
+
+    #[test]
+    fn test_discover_adapters() {
+        let settings = Settings { heartbeat: Heartbeat { interval_ms: 500, max_misses: 2 }, ..Default::default() };
+        let cfg = |port: &str, model| DeviceConfig { port: port.into(), model, settings: settings.clone() };
+        let none = |_: &str| false;
+        let ports = fake_ports();
+        let found = discovery::discover(&ports, &[cfg("/dev/ttyACM0", Some(DeviceModel::M2))], &none);
+        let found: Vec<(&str, Option<DeviceModel>, u64)> = found.iter().map(|c| (c.port.as_str(), c.model, c.settings.heartbeat.interval_ms)).collect();
+        assert_eq!(found, vec![
+            ("/dev/ttyACM1", Some(DeviceModel::M2), 500), // Settings from the M2's config
+            ("/dev/ttyUSB1", Some(DeviceModel::A0), Heartbeat::default().interval_ms),
+        ]);
+        // Each port is opened as the adapter its USB ID belongs to, so an M2 does not wait out the A0's reset
+        assert_eq!(*ports.probed.borrow(), vec![
+            ("/dev/ttyACM0".to_string(), DeviceModel::M2),
+            ("/dev/ttyACM1".to_string(), DeviceModel::M2),
+            ("/dev/ttyUSB1".to_string(), DeviceModel::A0),
+        ]);
+        assert_eq!(discovery::discover(&fake_ports(), &[], &|p| p == "/dev/ttyACM1").len(), 1);
+    }
+
+    #[test]
+    fn test_discovery_fallback() {
+        let cfg = |port: &str| DeviceConfig { port: port.into(), model: Some(DeviceModel::M2), settings: Settings::default() };
+        let exists = |p: &str| p != "/dev/ttyACM0";
+        let none = |_: &str| false;
+        let find = |sel: &Selector, configs: &[DeviceConfig], in_use: &dyn Fn(&str) -> bool| -> Vec<String> {
+            let mut configs = configs.to_vec();
+            discovery::add_discovered(sel, &mut configs, &fake_ports(), &exists, in_use);
+            select_configs(sel, &configs, &exists, in_use).into_iter().map(|c| c.port).collect()
+        };
+
+        // Config port has gone, or there is no config at all
+        assert_eq!(find(&Selector::Default, &[cfg("/dev/ttyACM0")], &none), vec!["/dev/ttyACM1"]);
+        assert_eq!(find(&Selector::Default, &[], &none), vec!["/dev/ttyACM1"]);
+        assert_eq!(find(&Selector::Default, &[], &|p| p == "/dev/ttyACM1"), vec!["/dev/ttyUSB1"]);
+        assert_eq!(find(&Selector::Serial("1234".into()), &[], &none), vec!["/dev/ttyACM1", "/dev/ttyUSB1"]);
+
+        // Config port is there, so nothing is probed
+        assert_eq!(find(&Selector::Default, &[cfg("/dev/ttyUSB0")], &none), vec!["/dev/ttyUSB0"]);
+        let sel = Selector::Port { path: "/dev/ttyACM0".into(), model: None };
+        assert_eq!(find(&sel, &[], &none), vec!["/dev/ttyACM0"]);
+    }
+
+    #[test]
+    fn test_open_by_name() {
+        let _lock = lock_device();
+        plug_in(DeviceModel::M2);
+        Emulator::plug_into(SECOND_PORT, DeviceModel::A0);
+        let open_named = |name: &str| -> (PassthruError, u32) {
+            let name = std::ffi::CString::new(name).unwrap();
+            let mut dev_idx: u32 = 0;
+            (passthru_open(name.as_ptr(), &mut dev_idx), dev_idx)
+        };
+
+        // Port which is not in the config
+        let (res, a0_idx) = open_named("serial://emulator:2");
+        assert_eq!(res, PassthruError::STATUS_NOERROR);
+        let mut fw = [0 as libc::c_char; 80];
+        let mut dll = [0 as libc::c_char; 80];
+        let mut api = [0 as libc::c_char; 80];
+        assert_eq!(passthru_read_version(a0_idx, fw.as_mut_ptr(), dll.as_mut_ptr(), api.as_mut_ptr()), PassthruError::STATUS_NOERROR);
+        assert!(c_str(&fw).ends_with("_A0"));
+        assert_eq!(open_named("serial://emulator:2").0, PassthruError::ERR_DEVICE_IN_USE);
+        assert_eq!(passthru_close(a0_idx), PassthruError::STATUS_NOERROR);
+
+        // Serial number of an adapter which is not attached. The one which is gets closed again
+        assert_eq!(open_named("00112233").0, PassthruError::ERR_DEVICE_NOT_CONNECTED);
+        assert!(last_error().contains("00112233"), "{}", last_error());
+        assert!(devices::get(devices::DEVICE_ID).is_err());
+        assert!(!with_emulator(|e| e.driver_has_link_open()));
+
+        let (res, m2_idx) = open_named("4d32454d554c41544f52000000000001");
+        assert_eq!(res, PassthruError::STATUS_NOERROR);
+        assert_eq!(with_connection(m2_idx, |dev| dev.capabilities().unwrap().max_filters), 7);
+
+        assert_eq!(open_named("ftdi://0403:6001").0, PassthruError::ERR_FAILED);
+        assert!(last_error().contains("Unsupported transport"), "{}", last_error());
         let mut dev_idx: u32 = 0;
-        assert!(passthru_open(&mut dev_idx) == PassthruError::STATUS_NOERROR);
-        
+        assert_eq!(passthru_open(std::ptr::null(), std::ptr::null_mut()), PassthruError::ERR_NULL_PARAMETER);
+        assert_eq!(passthru_open(std::ptr::null(), &mut dev_idx), PassthruError::ERR_DEVICE_IN_USE);
+        assert_eq!(passthru_close(m2_idx), PassthruError::STATUS_NOERROR);
+    }
+
+    #[test]
+    fn test_repeated_open_close() {
+        each_model(|_| {
+            for sent in 1..=10 {
+                let dev_idx = open_device();
+                let mut channel_idx: u32 = 0;
+                assert_eq!(passthru_connect(dev_idx, Protocol::CAN as u32, 0, 500_000, &mut channel_idx), PassthruError::STATUS_NOERROR);
+                let tx = can_msg(Protocol::CAN, &[0x00, 0x00, 0x07, 0xE0, 0x01, 0x3E]);
+                let mut num_msgs: u32 = 1;
+                // Sent before closing, as closing drops anything still queued
+                assert_eq!(write_msgs(channel_idx, &tx, &mut num_msgs, 1000), PassthruError::STATUS_NOERROR);
+                let tx_queue = ChannelComm::tx_queue(channel_idx).unwrap();
+                assert_eq!(passthru_close(dev_idx), PassthruError::STATUS_NOERROR);
+                // Port is released, the goodbye was sent before it, and the Tx thread has exited, by the time close returns
+                assert!(!with_emulator(|e| e.driver_has_link_open()));
+                assert_eq!(tx_queue.strong_count(), 0);
+                assert!(wait_for(100, || !with_emulator(|e| e.is_connected())));
+                assert_eq!(with_emulator(|e| e.tx_frames()).len(), sent);
+            }
+        });
+    }
+
+    #[test]
+    fn test_channel_table() {
+        let _lock = lock_device();
+        plug_in(DeviceModel::M2);
+        let dev_idx = open_device();
+        let connect = move |protocol: Protocol, baud: u32| -> (PassthruError, u32) {
+            let mut channel_idx: u32 = 0;
+            (passthru_connect(dev_idx, protocol as u32, 0, baud, &mut channel_idx), channel_idx)
+        };
+
+        // CAN and K-Line are on their own resources
+        let (res, can) = connect(Protocol::CAN, 500_000);
+        assert_eq!(res, PassthruError::STATUS_NOERROR);
+        let (res, kline) = connect(Protocol::ISO9141, 10400);
+        assert_eq!(res, PassthruError::STATUS_NOERROR);
+        assert_ne!(can, kline);
+        assert_eq!((fw_channel(can), fw_channel(kline)), (channels::Resource::Can0.fw_id(), channels::Resource::Kline.fw_id()));
+        assert!(with_emulator(|e| e.has_channel(fw_channel(can)) && e.has_channel(fw_channel(kline))));
+
+        // ISO15765 needs the CAN controller, which is taken
+        assert_eq!(connect(Protocol::ISO15765, 500_000).0, PassthruError::ERR_CHANNEL_IN_USE);
+
+        // Closed channel's ID is not handed out again, and no longer works
+        assert_eq!(passthru_disconnect(can), PassthruError::STATUS_NOERROR);
+        assert!(!with_emulator(|e| e.has_channel(channels::Resource::Can0.fw_id())));
+        let (res, iso15765) = connect(Protocol::ISO15765, 500_000);
+        assert_eq!(res, PassthruError::STATUS_NOERROR);
+        assert!(iso15765 != can && iso15765 != kline);
+        assert_eq!(fw_channel(iso15765), channels::Resource::Can0.fw_id());
+        let mut tx = can_msg(Protocol::CAN, &[0x00, 0x00, 0x07, 0xDF, 0x01]);
+        let mut num_msgs: u32 = 1;
+        assert_eq!(write_msgs(can, &tx, &mut num_msgs, 100), PassthruError::ERR_INVALID_CHANNEL_ID);
+        assert_eq!(passthru_disconnect(can), PassthruError::ERR_INVALID_CHANNEL_ID);
+
+        // Rx goes to the channel now on the resource
+        with_emulator(|e| e.inject_rx(fw_channel(iso15765), 0, &[0x00, 0x00, 0x07, 0xE8, 0x01, 0x7E]));
+        let mut rx = PASSTHRU_MSG::default();
+        num_msgs = 1;
+        assert_eq!(read_msgs(iso15765, &mut rx, &mut num_msgs, 500), PassthruError::STATUS_NOERROR);
+        assert_eq!(rx.protocol_id, Protocol::ISO15765 as u32);
+        assert_eq!(read_msgs(kline, &mut rx, &mut num_msgs, 0), PassthruError::ERR_BUFFER_EMPTY);
+        tx.protocol_id = Protocol::ISO15765 as u32;
+        num_msgs = 1;
+        assert_eq!(write_msgs(iso15765, &tx, &mut num_msgs, 100), PassthruError::STATUS_NOERROR);
+
+        // Other channels keep receiving whilst the adapter is opening one
+        assert_eq!(passthru_disconnect(kline), PassthruError::STATUS_NOERROR);
+        assert_eq!(passthru_ioctl(iso15765, IoctlID::CLEAR_RX_BUFFER as u32, std::ptr::null_mut(), std::ptr::null_mut()), PassthruError::STATUS_NOERROR);
+        with_emulator(|e| e.set_holding(true));
+        let opening = std::thread::spawn(move || connect(Protocol::ISO9141, 10400));
+        assert!(wait_for(500, || with_emulator(|e| e.has_channel(channels::Resource::Kline.fw_id()))));
+        with_emulator(|e| e.inject_rx(fw_channel(iso15765), 0, &[0x00, 0x00, 0x07, 0xE8, 0x01, 0x7E]));
+        num_msgs = 1;
+        assert_eq!(read_msgs(iso15765, &mut rx, &mut num_msgs, 500), PassthruError::STATUS_NOERROR);
+        with_emulator(|e| e.set_holding(false));
+        assert_eq!(opening.join().unwrap().0, PassthruError::STATUS_NOERROR);
+        assert_eq!(passthru_close(dev_idx), PassthruError::STATUS_NOERROR);
+    }
+
+    #[test]
+    fn test_second_can_bus() {
+        const CAN_CH1: u32 = 0x9000;
+        const CAN_CH2: u32 = 0x9001;
+        const ISO15765_CH2: u32 = 0x9401;
+        each_model(|model| {
+            let dev_idx = open_device();
+            let connect = |protocol_id: u32| -> (PassthruError, u32) {
+                let mut channel_idx: u32 = 0;
+                (passthru_connect(dev_idx, protocol_id, 0, 500_000, &mut channel_idx), channel_idx)
+            };
+            let (res, ch1) = connect(CAN_CH1);
+            assert_eq!(res, PassthruError::STATUS_NOERROR);
+            assert_eq!(fw_channel(ch1), channels::Resource::Can0.fw_id());
+            // Plain CAN is the first bus
+            assert_eq!(connect(Protocol::CAN as u32).0, PassthruError::ERR_CHANNEL_IN_USE);
+            assert_eq!(connect(CAN_CH1 + 2).0, PassthruError::ERR_NOT_SUPPORTED);
+            if model == DeviceModel::A0 {
+                assert_eq!(connect(CAN_CH2).0, PassthruError::ERR_NOT_SUPPORTED);
+                assert!(last_error().contains("only has 1"), "{}", last_error());
+                assert_eq!(passthru_close(dev_idx), PassthruError::STATUS_NOERROR);
+                return;
+            }
+            let (res, ch2) = connect(CAN_CH2);
+            assert_eq!(res, PassthruError::STATUS_NOERROR);
+            assert_eq!(fw_channel(ch2), channels::Resource::Can1.fw_id());
+            assert_eq!(connect(ISO15765_CH2).0, PassthruError::ERR_CHANNEL_IN_USE);
+            assert!(with_emulator(|e| e.has_channel(fw_channel(ch1)) && e.has_channel(fw_channel(ch2))));
+
+            // Filters and Tx go to the bus of the channel
+            let mask = can_msg(Protocol::CAN, &[0xFF, 0xFF, 0xFF, 0xFF]);
+            let mut ptn = can_msg(Protocol::CAN, &[0x00, 0x00, 0x07, 0xE8]);
+            ptn.protocol_id = CAN_CH2;
+            let mut filter_idx: u32 = 99;
+            assert_eq!(set_channel_filter(ch2, FilterType::PASS_FILTER, &mask, &ptn, std::ptr::null(), &mut filter_idx), PassthruError::STATUS_NOERROR);
+            assert!(with_emulator(|e| e.has_filter(fw_channel(ch2), filter_idx as usize) && !e.has_filter(fw_channel(ch1), filter_idx as usize)));
+            let mut tx = can_msg(Protocol::CAN, &[0x00, 0x00, 0x07, 0xE0, 0x01, 0x3E]);
+            let mut num_msgs: u32 = 1;
+            assert_eq!(write_msgs(ch2, &tx, &mut num_msgs, 100), PassthruError::ERR_MSG_PROTOCOL_ID);
+            tx.protocol_id = CAN_CH2;
+            num_msgs = 1;
+            assert_eq!(write_msgs(ch2, &tx, &mut num_msgs, 100), PassthruError::STATUS_NOERROR);
+            let sent = with_emulator(|e| e.tx_frames());
+            assert_eq!(sent.last().map(|f| f.channel_id), Some(channels::Resource::Can1.fw_id()));
+
+            // Rx from each bus only reaches its own channel, tagged with the ID it was opened with
+            with_emulator(|e| e.inject_rx(fw_channel(ch2), 0, &[0x00, 0x00, 0x07, 0xE8, 0x01, 0x7E]));
+            let mut rx = PASSTHRU_MSG::default();
+            num_msgs = 1;
+            assert_eq!(read_msgs(ch2, &mut rx, &mut num_msgs, 500), PassthruError::STATUS_NOERROR);
+            assert_eq!((rx.protocol_id, rx.rx_status & indications::TX_DONE), (CAN_CH2, indications::TX_DONE));
+            assert_eq!(read_msgs(ch2, &mut rx, &mut num_msgs, 500), PassthruError::STATUS_NOERROR);
+            assert_eq!(rx.protocol_id, CAN_CH2);
+            assert_eq!(&rx.data[..rx.data_size as usize], &[0x00, 0x00, 0x07, 0xE8, 0x01, 0x7E]);
+            assert_eq!(read_msgs(ch1, &mut rx, &mut num_msgs, 0), PassthruError::ERR_BUFFER_EMPTY);
+
+            // Closing one bus leaves the other open
+            assert_eq!(passthru_disconnect(ch1), PassthruError::STATUS_NOERROR);
+            assert!(with_emulator(|e| !e.has_channel(channels::Resource::Can0.fw_id()) && e.has_channel(fw_channel(ch2))));
+            assert_eq!(passthru_close(dev_idx), PassthruError::STATUS_NOERROR);
+        });
+
+        // Firmware from before the CAN bus count has one
+        let caps = capabilities::Capabilities { version_major: 1, version_minor: 0, max_payload: 4096, protocols: 0, max_filters: 7, can_buses: 2 };
+        assert_eq!(capabilities::Capabilities::from_args(&caps.to_args()), Some(caps));
+        assert_eq!(capabilities::Capabilities::from_args(&caps.to_args()[..9]).map(|c| c.can_buses), Some(1));
+    }
+
+    #[test]
+    fn test_periodic_messages() {
+        let _lock = lock_device();
+        plug_in(DeviceModel::M2);
+        let dev_idx = open_device();
+        let mut channel_idx: u32 = 0;
+        assert_eq!(passthru_connect(dev_idx, Protocol::CAN as u32, 0, 500_000, &mut channel_idx), PassthruError::STATUS_NOERROR);
+        let tester_present = can_msg(Protocol::CAN, &[0x00, 0x00, 0x07, 0xDF, 0x02, 0x3E, 0x80]);
+        let sent = || with_emulator(|e| e.tx_frames());
+        let active = || devices::get(dev_idx).unwrap().periodic.active();
+
+        // Checked before anything is sent
+        let mut msg_id: u32 = 99;
+        assert_eq!(start_periodic_msg(channel_idx, &tester_present, &mut msg_id, 4), PassthruError::ERR_INVALID_TIME_INTERVAL);
+        assert_eq!(start_periodic_msg(channel_idx, &tester_present, &mut msg_id, 65536), PassthruError::ERR_INVALID_TIME_INTERVAL);
+        assert_eq!(start_periodic_msg(channel_idx, &tester_present, std::ptr::null_mut(), 100), PassthruError::ERR_NULL_PARAMETER);
+        assert_eq!(start_periodic_msg(channel_idx, &can_msg(Protocol::ISO15765, &[0x00, 0x00, 0x07, 0xDF, 0x3E]), &mut msg_id, 100), PassthruError::ERR_MSG_PROTOCOL_ID);
+        assert_eq!(start_periodic_msg(channel_idx + 1, &tester_present, &mut msg_id, 100), PassthruError::ERR_INVALID_CHANNEL_ID);
+        assert_eq!(active(), 0);
+
+        // Sent on time, without drifting
+        let started = std::time::Instant::now();
+        assert_eq!(start_periodic_msg(channel_idx, &tester_present, &mut msg_id, 20), PassthruError::STATUS_NOERROR);
+        assert_eq!(msg_id, 0);
+        std::thread::sleep(Duration::from_millis(1000));
+        assert_eq!(stop_periodic_msg(channel_idx, msg_id), PassthruError::STATUS_NOERROR);
+        let elapsed = started.elapsed().as_millis() as i64;
+        // Last one sent may still be on its way to the emulator
+        std::thread::sleep(Duration::from_millis(50));
+        let frames = sent();
+        // First is sent straight away
+        let expected = elapsed / 20 + 1;
+        assert!((frames.len() as i64 - expected).abs() <= 2, "Sent {} in {} ms every 20ms", frames.len(), elapsed);
+        // Spaced 20ms apart on average, so lateness does not add up. One send held up by a busy machine moves it by well under 1ms
+        let spacing = (frames.last().unwrap().at - frames[0].at).as_secs_f64() * 1000.0 / (frames.len() - 1) as f64;
+        assert!((spacing - 20.0).abs() < 1.0, "Sent every {:.2} ms", spacing);
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(sent().len(), frames.len());
+        assert_eq!(stop_periodic_msg(channel_idx, msg_id), PassthruError::ERR_INVALID_MSG_ID);
+
+        // 10 per channel
+        for id in 0..10 {
+            assert_eq!(start_periodic_msg(channel_idx, &tester_present, &mut msg_id, 1000), PassthruError::STATUS_NOERROR);
+            assert_eq!(msg_id, id);
+        }
+        assert_eq!(start_periodic_msg(channel_idx, &tester_present, &mut msg_id, 1000), PassthruError::ERR_EXCEEDED_LIMIT);
+        assert_eq!(stop_periodic_msg(channel_idx, 3), PassthruError::STATUS_NOERROR);
+        assert_eq!(start_periodic_msg(channel_idx, &tester_present, &mut msg_id, 1000), PassthruError::STATUS_NOERROR);
+        assert_eq!(msg_id, 3);
+        assert_eq!(passthru_ioctl(channel_idx, IoctlID::CLEAR_PERIODIC_MSGS as u32, std::ptr::null_mut(), std::ptr::null_mut()), PassthruError::STATUS_NOERROR);
+        assert_eq!(active(), 0);
+
+        // Stopped when the channel closes, as is the adapter's scheduler when it closes
+        assert_eq!(start_periodic_msg(channel_idx, &tester_present, &mut msg_id, 10), PassthruError::STATUS_NOERROR);
+        assert_eq!(passthru_disconnect(channel_idx), PassthruError::STATUS_NOERROR);
+        assert_eq!(active(), 0);
+        assert_eq!(passthru_connect(dev_idx, Protocol::CAN as u32, 0, 500_000, &mut channel_idx), PassthruError::STATUS_NOERROR);
+        assert_eq!(start_periodic_msg(channel_idx, &tester_present, &mut msg_id, 10), PassthruError::STATUS_NOERROR);
+        assert_eq!(passthru_close(dev_idx), PassthruError::STATUS_NOERROR);
+    }
+
+    #[test]
+    fn test_tx_queue() {
+        let _lock = lock_device();
+        let (emu, link) = Emulator::start(DeviceModel::M2);
+        let device = devices::register("memory").unwrap();
+        let settings = Settings { heartbeat: Heartbeat { interval_ms: 0, ..Default::default() }, ..Default::default() };
+        *device.connection.write().unwrap() = Some(MacchinaM2::open_reconnecting(device.id, Box::new(link), DeviceModel::M2, None, settings).unwrap());
         let mut channel_idx: u32 = 0;
-        assert!(passthru_connect(dev_idx, Protocol::ISO15765 as u32, 0, 500_000, &mut channel_idx) == PassthruError::STATUS_NOERROR);
-
-        let ptmsg = PASSTHRU_MSG {
-            protocol_id: Protocol::ISO15765 as u32,
-            rx_status: 0x00,
-            tx_flags: 0x40,
-            timestamp: 0,
-            data_size: 4096+4,
-            extra_data_size: 0,
-            data: [0; 4128]
+        assert_eq!(passthru_connect(device.id, Protocol::CAN as u32, 0, 500_000, &mut channel_idx), PassthruError::STATUS_NOERROR);
+        let msgs = |n: usize| -> Vec<PASSTHRU_MSG> { (0..n).map(|i| can_msg(Protocol::CAN, &[0x00, 0x00, 0x07, 0xE0, i as u8])).collect() };
+        let write = |msgs: &[PASSTHRU_MSG], timeout_ms: u32| {
+            let mut num_msgs = msgs.len() as u32;
+            let res = write_msgs(channel_idx, msgs.as_ptr(), &mut num_msgs, timeout_ms);
+            (num_msgs, res)
         };
+        let sent = || emu.tx_frames().iter().map(|f| f.data[4]).collect::<Vec<u8>>();
+
+        // Sent in order, up to the first bad message
+        let mut batch = msgs(3);
+        batch[2].protocol_id = Protocol::ISO15765 as u32;
+        assert_eq!(write(&batch, 1000), (2, PassthruError::ERR_MSG_PROTOCOL_ID));
+        assert_eq!(write(&msgs(3), 0), (3, PassthruError::STATUS_NOERROR));
+        assert!(wait_for(1000, || sent().len() == 5));
+        assert_eq!(sent(), vec![0, 1, 0, 1, 2]);
+
+        // Clearing whilst a write waits for its messages fails it, counting only those which were sent
+        emu.set_holding(true);
+        let writer = std::thread::spawn(move || {
+            let batch = msgs(3);
+            let mut num_msgs = batch.len() as u32;
+            let res = write_msgs(channel_idx, batch.as_ptr(), &mut num_msgs, 2000);
+            (num_msgs, res)
+        });
+        assert!(wait_for(500, || emu.tx_frames().len() == 6));
+        assert_eq!(passthru_ioctl(channel_idx, IoctlID::CLEAR_TX_BUFFER as u32, std::ptr::null_mut(), std::ptr::null_mut()), PassthruError::STATUS_NOERROR);
+        emu.set_holding(false);
+        assert_eq!(writer.join().unwrap(), (1, PassthruError::ERR_FAILED));
+        assert!(last_error().contains("cleared"), "{}", last_error());
+        assert_eq!(sent(), vec![0, 1, 0, 1, 2, 0]);
+
+        // Whilst the adapter is stuck on the first message, the rest wait in the queue
+        emu.set_hung(true);
+        assert_eq!(write(&msgs(2), 200), (0, PassthruError::ERR_TIMEOUT));
+        assert_eq!(passthru_ioctl(channel_idx, IoctlID::CLEAR_TX_BUFFER as u32, std::ptr::null_mut(), std::ptr::null_mut()), PassthruError::STATUS_NOERROR);
+        assert_eq!(write(&msgs(1100), 0), (1000, PassthruError::ERR_BUFFER_FULL));
+        assert_eq!(write(&msgs(1), 100), (0, PassthruError::ERR_TIMEOUT));
+
+        // Cleared messages are never sent
+        assert_eq!(passthru_ioctl(channel_idx, IoctlID::CLEAR_TX_BUFFER as u32, std::ptr::null_mut(), std::ptr::null_mut()), PassthruError::STATUS_NOERROR);
+        emu.set_hung(false);
+        // Once the message the adapter never answered times out, which takes up to the Tx timeout
+        let tx_ms = Timeouts::default().tx_ms as u32;
+        assert_eq!(write(&msgs(1), tx_ms * 3), (1, PassthruError::STATUS_NOERROR));
+        assert_eq!(sent(), vec![0, 1, 0, 1, 2, 0, 0]);
+        assert_eq!(passthru_close(device.id), PassthruError::STATUS_NOERROR);
+    }
+
+    #[test]
+    fn test_capabilities_checked_locally() {
+        each_model(|model| {
+            let dev_idx = open_device();
+            let mut channel_idx: u32 = 0;
+            assert_eq!(passthru_connect(dev_idx, Protocol::J1850VPW as u32, 0, 10400, &mut channel_idx), PassthruError::ERR_NOT_SUPPORTED);
+            assert!(last_error().contains("does not support"), "{}", last_error());
+            let iso9141 = passthru_connect(dev_idx, Protocol::ISO9141 as u32, 0, 10400, &mut channel_idx);
+            match model {
+                DeviceModel::M2 => {
+                    assert_eq!(iso9141, PassthruError::STATUS_NOERROR);
+                    assert_eq!(passthru_disconnect(channel_idx), PassthruError::STATUS_NOERROR);
+                },
+                DeviceModel::A0 => assert_eq!(iso9141, PassthruError::ERR_NOT_SUPPORTED)
+            }
+            assert_eq!(passthru_connect(dev_idx, 0x8000, 0, 500_000, &mut channel_idx), PassthruError::ERR_INVALID_PROTOCOL_ID);
 
-        ChannelComm::write_channel_data(channel_idx, &ptmsg, true);
+            // Out of mailboxes before running out of J2534 filter IDs
+            assert_eq!(passthru_connect(dev_idx, Protocol::CAN as u32, 0, 500_000, &mut channel_idx), PassthruError::STATUS_NOERROR);
+            let mask = can_msg(Protocol::CAN, &[0xFF, 0xFF, 0xFF, 0xFF]);
+            let max_filters = with_connection(dev_idx, |dev| dev.capabilities().unwrap().max_filters as u32);
+            assert_eq!(max_filters, match model { DeviceModel::M2 => 7, DeviceModel::A0 => 10 });
+            for id in 0..max_filters {
+                let ptn = can_msg(Protocol::CAN, &[0x00, 0x00, 0x07, id as u8]);
+                let mut filter_idx: u32 = 99;
+                assert_eq!(set_channel_filter(channel_idx, FilterType::PASS_FILTER, &mask, &ptn, std::ptr::null(), &mut filter_idx), PassthruError::STATUS_NOERROR);
+                assert_eq!(filter_idx, id);
+            }
+            let mut filter_idx: u32 = 99;
+            assert_eq!(set_channel_filter(channel_idx, FilterType::PASS_FILTER, &mask, &mask, std::ptr::null(), &mut filter_idx), PassthruError::ERR_EXCEEDED_LIMIT);
+            assert_eq!(passthru_close(dev_idx), PassthruError::STATUS_NOERROR);
+        });
+    }
+
+    #[test]
+    fn test_incompatible_firmware_refused() {
+        each_model(|_| {
+            // Newer minor version is fine
+            with_emulator(|e| e.set_protocol_version(capabilities::PROTOCOL_VERSION_MAJOR, capabilities::PROTOCOL_VERSION_MINOR + 1));
+            let dev_idx = open_device();
+            assert_eq!(passthru_close(dev_idx), PassthruError::STATUS_NOERROR);
+
+            for major in [capabilities::PROTOCOL_VERSION_MAJOR - 1, capabilities::PROTOCOL_VERSION_MAJOR + 1].iter() {
+                with_emulator(|e| e.set_protocol_version(*major, 0));
+                let mut dev_idx: u32 = 0;
+                assert_eq!(passthru_open(std::ptr::null(), &mut dev_idx), PassthruError::ERR_FAILED);
+                assert!(last_error().contains("protocol version"), "{}", last_error());
+                assert!(devices::get(devices::DEVICE_ID).is_err());
+                assert!(!with_emulator(|e| e.driver_has_link_open()));
+            }
+        });
+    }
+
+    fn c_str(s: &[libc::c_char]) -> String {
+        unsafe { std::ffi::CStr::from_ptr(s.as_ptr()) }.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_device_info() {
+        each_model(|model| {
+            let dev_idx = open_device();
+            std::thread::sleep(Duration::from_millis(20));
+            let mut info: SDEVICE_INFO = unsafe { std::mem::zeroed() };
+            let res = passthru_ioctl(dev_idx, ioctl::GET_DEVICE_INFO, std::ptr::null_mut(), &mut info as *mut SDEVICE_INFO as *mut libc::c_void);
+            assert_eq!(res, PassthruError::STATUS_NOERROR);
+            match model {
+                DeviceModel::M2 => {
+                    assert_eq!(c_str(&info.model), "Macchina M2");
+                    assert_eq!(c_str(&info.serial).len(), 32);
+                },
+                DeviceModel::A0 => {
+                    assert_eq!(c_str(&info.model), "Macchina A0");
+                    assert_eq!(c_str(&info.serial), "24:0A:C4:00:00:01");
+                }
+            }
+            assert_eq!(c_str(&info.board_revision), "1");
+            assert!(info.uptime_ms >= 20);
+            let res = passthru_ioctl(dev_idx, ioctl::GET_DEVICE_INFO, std::ptr::null_mut(), std::ptr::null_mut());
+            assert_eq!(res, PassthruError::ERR_NULL_PARAMETER);
+            assert_eq!(passthru_close(dev_idx), PassthruError::STATUS_NOERROR);
+        });
+    }
+
+    #[test]
+    fn test_device_info_parsing() {
+        let info = DeviceInfo { model: "Macchina M2".into(), board_revision: "4.2".into(), serial: "ABCD".into(), uptime_ms: 1234 };
+        assert_eq!(DeviceInfo::from_args(&info.to_args()), Some(info));
+        // Serial missing
+        assert_eq!(DeviceInfo::from_args(&[0, 0, 0, 0, b'M', 0, b'1']), None);
+        assert_eq!(DeviceInfo::from_args(&[0, 0]), None);
+
+        // Strings longer than the output are cut short, but still terminated
+        let long = DeviceInfo { model: "M".repeat(100), board_revision: String::new(), serial: "S".into(), uptime_ms: 0 };
+        let mut out: SDEVICE_INFO = unsafe { std::mem::zeroed() };
+        out.set(&long);
+        assert_eq!(c_str(&out.model), "M".repeat(63));
+        assert_eq!(c_str(&out.serial), "S");
+    }
+
+    #[test]
+    fn test_read_version_and_vbatt() {
+        each_model(|_| {
+            let dev_idx = open_device();
+
+            let mut fw = [0 as libc::c_char; 80];
+            let mut dll = [0 as libc::c_char; 80];
+            let mut api = [0 as libc::c_char; 80];
+            assert_eq!(passthru_read_version(dev_idx, fw.as_mut_ptr(), dll.as_mut_ptr(), api.as_mut_ptr()), PassthruError::STATUS_NOERROR);
+            let fw_str = unsafe { std::ffi::CStr::from_ptr(fw.as_ptr()) }.to_str().unwrap().to_string();
+            assert!(fw_str.starts_with("1.0."));
+
+            with_emulator(|e| e.set_batt_mv(13800));
+            let mut vbatt: u32 = 0;
+            let res = passthru_ioctl(0, IoctlID::READ_VBATT as u32, std::ptr::null_mut(), &mut vbatt as *mut u32 as *mut libc::c_void);
+            assert_eq!(res, PassthruError::STATUS_NOERROR);
+            assert_eq!(vbatt, 13800);
+            assert_eq!(passthru_close(dev_idx), PassthruError::STATUS_NOERROR);
+        });
+    }
+
+    #[test]
+    fn test_can_write_and_read() {
+        each_model(|_| {
+            let dev_idx = open_device();
+            let mut channel_idx: u32 = 0;
+            assert_eq!(passthru_connect(dev_idx, Protocol::CAN as u32, 0, 500_000, &mut channel_idx), PassthruError::STATUS_NOERROR);
+
+            // Tx, with and without a response from the adapter
+            let tx = can_msg(Protocol::CAN, &[0x00, 0x00, 0x07, 0xE0, 0x02, 0x10, 0x03]);
+            for timeout in [0, 100].iter() {
+                let mut num_msgs: u32 = 1;
+                assert_eq!(write_msgs(channel_idx, &tx, &mut num_msgs, *timeout), PassthruError::STATUS_NOERROR);
+                assert_eq!(num_msgs, 1);
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            let frames = with_emulator(|e| e.tx_frames());
+            assert_eq!(frames.len(), 2);
+            assert_eq!(frames[0].data, vec![0x00, 0x00, 0x07, 0xE0, 0x02, 0x10, 0x03]);
+
+            // Each confirmed with TX_DONE, but nothing received yet
+            let mut rx = [PASSTHRU_MSG::default(); 2];
+            let mut num_msgs: u32 = 2;
+            assert_eq!(read_msgs(channel_idx, rx.as_mut_ptr(), &mut num_msgs, 0), PassthruError::STATUS_NOERROR);
+            for msg in rx.iter() {
+                assert_eq!((msg.rx_status, &msg.data[..msg.data_size as usize]), (indications::TX_MSG_TYPE | indications::TX_DONE, &[0x00, 0x00, 0x07, 0xE0][..]));
+            }
+            let mut num_msgs: u32 = 1;
+            assert_eq!(read_msgs(channel_idx, rx.as_mut_ptr(), &mut num_msgs, 0), PassthruError::ERR_BUFFER_EMPTY);
+
+            // Rx from the bus
+            with_emulator(|e| {
+                e.inject_rx(fw_channel(channel_idx), 0, &[0x00, 0x00, 0x07, 0xE8, 0x02, 0x50, 0x03]);
+                e.inject_rx(fw_channel(channel_idx), 0, &[0x00, 0x00, 0x07, 0xE8, 0x01, 0x7E]);
+            });
+            let mut num_msgs: u32 = 2;
+            assert_eq!(read_msgs(channel_idx, rx.as_mut_ptr(), &mut num_msgs, 500), PassthruError::STATUS_NOERROR);
+            assert_eq!(num_msgs, 2);
+            assert_eq!(&rx[0].data[..rx[0].data_size as usize], &[0x00, 0x00, 0x07, 0xE8, 0x02, 0x50, 0x03]);
+            assert_eq!(&rx[1].data[..rx[1].data_size as usize], &[0x00, 0x00, 0x07, 0xE8, 0x01, 0x7E]);
+            assert_eq!(rx[0].protocol_id, Protocol::CAN as u32);
+
+            assert_eq!(passthru_disconnect(channel_idx), PassthruError::STATUS_NOERROR);
+            assert_eq!(passthru_disconnect(channel_idx), PassthruError::ERR_INVALID_CHANNEL_ID);
+            assert_eq!(passthru_close(dev_idx), PassthruError::STATUS_NOERROR);
+        });
+    }
+
+    #[test]
+    fn test_iso15765_config_and_filters() {
+        each_model(|_| {
+            let dev_idx = open_device();
+            let mut channel_idx: u32 = 0;
+            assert_eq!(passthru_connect(dev_idx, Protocol::ISO15765 as u32, 0, 500_000, &mut channel_idx), PassthruError::STATUS_NOERROR);
+            // CAN shares the same physical bus
+            let mut other_idx: u32 = 0;
+            assert_eq!(passthru_connect(dev_idx, Protocol::CAN as u32, 0, 500_000, &mut other_idx), PassthruError::ERR_CHANNEL_IN_USE);
+
+            let mask = can_msg(Protocol::ISO15765, &[0xFF, 0xFF, 0xFF, 0xFF]);
+            let ptn = can_msg(Protocol::ISO15765, &[0x00, 0x00, 0x07, 0xE8]);
+            let fc = can_msg(Protocol::ISO15765, &[0x00, 0x00, 0x07, 0xE0]);
+            let mut filter_idx: u32 = 99;
+            assert_eq!(set_channel_filter(channel_idx, FilterType::FLOW_CONTROL_FILTER, &mask, &ptn, &fc, &mut filter_idx), PassthruError::STATUS_NOERROR);
+            assert_eq!(filter_idx, 0);
+            assert_eq!(set_channel_filter(channel_idx, FilterType::PASS_FILTER, &mask, &ptn, std::ptr::null(), &mut filter_idx), PassthruError::ERR_FAILED);
+            assert_eq!(passthru_drv::del_channel_filter(channel_idx, 0), PassthruError::STATUS_NOERROR);
+
+            let mut params = [SConfig { parameter: IoctlParam::ISO15765_STMIN as u32, value: 20 }];
+            let mut list = SConfigList { num_of_params: 1, config_ptr: params.as_mut_ptr() };
+            let input = &mut list as *mut SConfigList as *mut libc::c_void;
+            assert_eq!(passthru_ioctl(channel_idx, IoctlID::SET_CONFIG as u32, input, std::ptr::null_mut()), PassthruError::STATUS_NOERROR);
+            params[0].value = 0;
+            assert_eq!(passthru_ioctl(channel_idx, IoctlID::GET_CONFIG as u32, input, std::ptr::null_mut()), PassthruError::STATUS_NOERROR);
+            assert_eq!(params[0].value, 20);
+            assert_eq!(passthru_close(dev_idx), PassthruError::STATUS_NOERROR);
+        });
+    }
+
+    #[test]
+    fn test_loopback() {
+        each_model(|_| {
+            let dev_idx = open_device();
+            let mut channel_idx: u32 = 0;
+            assert_eq!(passthru_connect(dev_idx, Protocol::CAN as u32, 0, 500_000, &mut channel_idx), PassthruError::STATUS_NOERROR);
+            let fw_id = fw_channel(channel_idx);
+            let loopback = |channel_idx: u32, ioctl_id: IoctlID, value: &mut u32| {
+                let mut params = [SConfig { parameter: IoctlParam::LOOPBACK as u32, value: *value }];
+                let mut list = SConfigList { num_of_params: 1, config_ptr: params.as_mut_ptr() };
+                let res = passthru_ioctl(channel_idx, ioctl_id as u32, &mut list as *mut SConfigList as *mut libc::c_void, std::ptr::null_mut());
+                *value = params[0].value;
+                res
+            };
+            let mut value: u32 = 99;
+
+            // Kept by the driver, so answered even when the adapter would not
+            with_emulator(|e| e.set_hung(true));
+            assert_eq!(loopback(channel_idx, IoctlID::GET_CONFIG, &mut value), PassthruError::STATUS_NOERROR);
+            assert_eq!(value, 0);
+            assert_eq!(loopback(channel_idx, IoctlID::SET_CONFIG, &mut 2), PassthruError::ERR_INVALID_IOCTL_VALUE);
+            assert_eq!(loopback(channel_idx, IoctlID::SET_CONFIG, &mut 1), PassthruError::STATUS_NOERROR);
+            assert_eq!(loopback(channel_idx, IoctlID::GET_CONFIG, &mut value), PassthruError::STATUS_NOERROR);
+            assert_eq!(value, 1);
+            with_emulator(|e| e.set_hung(false));
+
+            // Echo is in order with what was received around it
+            with_emulator(|e| e.inject_rx(fw_id, 0, &[0x00, 0x00, 0x07, 0xE8, 0x01]));
+            std::thread::sleep(Duration::from_millis(20));
+            let mut tx = can_msg(Protocol::CAN, &[0x00, 0x00, 0x07, 0xE0, 0x02]);
+            tx.tx_flags = 0x100; // CAN_29BIT_ID
+            let mut num_msgs: u32 = 1;
+            assert_eq!(write_msgs(channel_idx, &tx, &mut num_msgs, 1000), PassthruError::STATUS_NOERROR);
+            with_emulator(|e| e.inject_rx(fw_id, 0, &[0x00, 0x00, 0x07, 0xE8, 0x03]));
+            let mut rx = [PASSTHRU_MSG::default(); 5];
+            num_msgs = 5;
+            assert_eq!(read_msgs(channel_idx, rx.as_mut_ptr(), &mut num_msgs, 200), PassthruError::ERR_TIMEOUT);
+            assert_eq!(num_msgs, 4);
+            let got: Vec<(u8, u32)> = rx[..4].iter().map(|m| (m.data[4], m.rx_status)).collect();
+            let tx_done = indications::TX_MSG_TYPE | indications::TX_DONE;
+            assert_eq!(got, vec![(1, 0), (0, tx_done | 0x100), (2, indications::TX_MSG_TYPE | 0x100), (3, 0)]);
+            assert_eq!((rx[2].data_size, rx[2].protocol_id, rx[2].tx_flags), (5, Protocol::CAN as u32, 0));
+            assert!(rx.windows(2).take(3).all(|m| m[0].timestamp <= m[1].timestamp));
+
+            // Off again, leaving only TX_DONE
+            assert_eq!(loopback(channel_idx, IoctlID::SET_CONFIG, &mut 0), PassthruError::STATUS_NOERROR);
+            num_msgs = 1;
+            assert_eq!(write_msgs(channel_idx, &tx, &mut num_msgs, 1000), PassthruError::STATUS_NOERROR);
+            num_msgs = 2;
+            assert_eq!(read_msgs(channel_idx, rx.as_mut_ptr(), &mut num_msgs, 50), PassthruError::ERR_TIMEOUT);
+            assert_eq!((num_msgs, rx[0].rx_status), (1, tx_done | 0x100));
+            assert_eq!(passthru_disconnect(channel_idx), PassthruError::STATUS_NOERROR);
+
+            // ISO15765 echoes the whole message, not only the frames
+            assert_eq!(passthru_connect(dev_idx, Protocol::ISO15765 as u32, 0, 500_000, &mut channel_idx), PassthruError::STATUS_NOERROR);
+            assert_eq!(loopback(channel_idx, IoctlID::SET_CONFIG, &mut 1), PassthruError::STATUS_NOERROR);
+            let payload: Vec<u8> = [0x00, 0x00, 0x07, 0xE0].iter().copied().chain(0..20).collect();
+            let tx = can_msg(Protocol::ISO15765, &payload);
+            num_msgs = 1;
+            assert_eq!(write_msgs(channel_idx, &tx, &mut num_msgs, 1000), PassthruError::STATUS_NOERROR);
+            num_msgs = 4;
+            read_msgs(channel_idx, rx.as_mut_ptr(), &mut num_msgs, 100);
+            let echoes: Vec<&PASSTHRU_MSG> = rx[..num_msgs as usize].iter().filter(|m| m.data_size as usize == payload.len()).collect();
+            assert_eq!(echoes.len(), 1);
+            assert_eq!((echoes[0].rx_status, &echoes[0].data[..payload.len()]), (indications::TX_MSG_TYPE, payload.as_slice()));
+            assert_eq!(passthru_close(dev_idx), PassthruError::STATUS_NOERROR);
+        });
+    }
+
+    #[test]
+    fn test_iso15765_indications() {
+        each_model(|_| {
+            use indications::{CAN_29BIT_ID, ISO15765_ADDR_TYPE, ISO15765_FIRST_FRAME, TX_DONE, TX_MSG_TYPE};
+            let dev_idx = open_device();
+            let mut channel_idx: u32 = 0;
+            assert_eq!(passthru_connect(dev_idx, Protocol::ISO15765 as u32, 0, 500_000, &mut channel_idx), PassthruError::STATUS_NOERROR);
+            let fw_id = fw_channel(channel_idx);
+            let mut params = [SConfig { parameter: IoctlParam::LOOPBACK as u32, value: 1 }];
+            let mut list = SConfigList { num_of_params: 1, config_ptr: params.as_mut_ptr() };
+            assert_eq!(passthru_ioctl(channel_idx, IoctlID::SET_CONFIG as u32, &mut list as *mut SConfigList as *mut libc::c_void, std::ptr::null_mut()), PassthruError::STATUS_NOERROR);
+            let read_all = || {
+                let mut rx = [PASSTHRU_MSG::default(); 10];
+                let mut num_msgs: u32 = 10;
+                read_msgs(channel_idx, rx.as_mut_ptr(), &mut num_msgs, 100);
+                assert!(rx[..num_msgs as usize].windows(2).all(|m| m[0].timestamp <= m[1].timestamp));
+                rx[..num_msgs as usize].iter().map(|m| (m.rx_status, m.data[..m.data_size as usize].to_vec())).collect::<Vec<_>>()
+            };
+            let request_id = vec![0x00, 0x00, 0x07, 0xE0];
+            let response_id = vec![0x00, 0x00, 0x07, 0xE8];
+
+            // One TX_DONE per message, single or multi frame, with only the CAN ID, and before the loopback.
+            // The adapter's own confirmations are not passed on
+            for len in [3, 40].iter() {
+                let payload: Vec<u8> = request_id.iter().copied().chain(0..*len).collect();
+                let mut num_msgs: u32 = 1;
+                assert_eq!(write_msgs(channel_idx, &can_msg(Protocol::ISO15765, &payload), &mut num_msgs, 1000), PassthruError::STATUS_NOERROR);
+                // The ECU answering straight away comes after both
+                let response: Vec<u8> = response_id.iter().copied().chain([0x7E, 0x00].iter().copied()).collect();
+                with_emulator(|e| e.inject_rx(fw_id, 0, &response));
+                assert_eq!(read_all(), vec![(TX_MSG_TYPE | TX_DONE, request_id.clone()), (TX_MSG_TYPE, payload), (0, response)]);
+            }
+
+            // A first frame indication comes before the message it starts, and holds only the CAN ID
+            let long: Vec<u8> = response_id.iter().copied().chain(0..20).collect();
+            with_emulator(|e| {
+                e.inject_rx(fw_id, ISO15765_FIRST_FRAME, &long[..10]);
+                e.inject_rx(fw_id, 0, &long);
+            });
+            assert_eq!(read_all(), vec![(ISO15765_FIRST_FRAME, response_id.clone()), (0, long.clone())]);
+
+            // Never made up once the message is in, as it would no longer warn of anything
+            let short: Vec<u8> = response_id.iter().copied().chain(0..7).collect();
+            with_emulator(|e| {
+                e.inject_rx(fw_id, 0, &long);
+                e.inject_rx(fw_id, 0, &short);
+            });
+            assert_eq!(read_all(), vec![(0, long.clone()), (0, short)]);
+
+            // A reception given up on is followed by another first frame, which starts it again
+            with_emulator(|e| {
+                e.inject_rx(fw_id, ISO15765_FIRST_FRAME, &long[..10]);
+                e.inject_rx(fw_id, ISO15765_FIRST_FRAME, &long[..10]);
+                e.inject_rx(fw_id, 0, &long);
+            });
+            assert_eq!(read_all(), vec![(ISO15765_FIRST_FRAME, response_id.clone()), (ISO15765_FIRST_FRAME, response_id.clone()), (0, long.clone())]);
+
+            // With extended addressing the CAN ID includes the address byte
+            let ext_id = vec![0x00, 0x00, 0x07, 0xE8, 0xF1];
+            let ext: Vec<u8> = ext_id.iter().copied().chain(0..7).collect();
+            let flags = ISO15765_ADDR_TYPE | CAN_29BIT_ID;
+            with_emulator(|e| {
+                e.inject_rx(fw_id, ISO15765_FIRST_FRAME | flags, &ext);
+                e.inject_rx(fw_id, flags, &ext);
+            });
+            assert_eq!(read_all(), vec![(ISO15765_FIRST_FRAME | flags, ext_id), (flags, ext)]);
+
+            // Indications the adapter should not send are dropped
+            with_emulator(|e| {
+                e.inject_rx(fw_id, TX_MSG_TYPE, &request_id);
+                e.inject_rx(fw_id, TX_DONE, &request_id);
+                e.inject_rx(fw_id, ISO15765_FIRST_FRAME, &[0x07, 0xE8]);
+            });
+            assert_eq!(read_all(), vec![]);
+            assert_eq!(passthru_close(dev_idx), PassthruError::STATUS_NOERROR);
+        });
+    }
+
+    fn last_error() -> String {
+        passthru_drv::LAST_ERROR_STR.lock().unwrap().clone()
+    }
+
+    fn wait_for<F: Fn() -> bool>(timeout_ms: u64, cond: F) -> bool {
+        let start = std::time::Instant::now();
+        while !cond() {
+            if start.elapsed() > Duration::from_millis(timeout_ms) {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+
+    #[test]
+    fn test_reconnect_after_unplug() {
+        each_model(|_| {
+            let dev_idx = open_device();
+            let mut channel_idx: u32 = 0;
+            assert_eq!(passthru_connect(dev_idx, Protocol::ISO15765 as u32, 0, 500_000, &mut channel_idx), PassthruError::STATUS_NOERROR);
+            let mask = can_msg(Protocol::ISO15765, &[0xFF, 0xFF, 0xFF, 0xFF]);
+            let ptn = can_msg(Protocol::ISO15765, &[0x00, 0x00, 0x07, 0xE8]);
+            let fc = can_msg(Protocol::ISO15765, &[0x00, 0x00, 0x07, 0xE0]);
+            let mut filter_idx: u32 = 99;
+            assert_eq!(set_channel_filter(channel_idx, FilterType::FLOW_CONTROL_FILTER, &mask, &ptn, &fc, &mut filter_idx), PassthruError::STATUS_NOERROR);
+            let mut params = [SConfig { parameter: IoctlParam::ISO15765_STMIN as u32, value: 20 }];
+            let mut list = SConfigList { num_of_params: 1, config_ptr: params.as_mut_ptr() };
+            let input = &mut list as *mut SConfigList as *mut libc::c_void;
+            assert_eq!(passthru_ioctl(channel_idx, IoctlID::SET_CONFIG as u32, input, std::ptr::null_mut()), PassthruError::STATUS_NOERROR);
+
+            with_emulator(|e| e.unplug());
+            assert!(wait_for(500, || device_status() == LinkStatus::Disconnected as u32));
+            let mut vbatt: u32 = 0;
+            let res = passthru_ioctl(0, IoctlID::READ_VBATT as u32, std::ptr::null_mut(), &mut vbatt as *mut u32 as *mut libc::c_void);
+            assert_eq!(res, PassthruError::ERR_DEVICE_NOT_CONNECTED);
+            assert!(last_error().contains("Lost connection to adapter"), "{}", last_error());
+            let tx = can_msg(Protocol::ISO15765, &[0x00, 0x00, 0x07, 0xE0, 0x3E, 0x00]);
+            let mut num_msgs: u32 = 1;
+            assert_eq!(write_msgs(channel_idx, &tx, &mut num_msgs, 0), PassthruError::ERR_DEVICE_NOT_CONNECTED);
+
+            // Channel, filter and config are set up again on the adapter once it is back
+            with_emulator(|e| e.plug_in());
+            assert!(wait_for(2000, || with_connection(dev_idx, |dev| dev.is_connected())));
+            assert!(with_emulator(|e| e.is_connected() && e.has_filter(fw_channel(channel_idx), filter_idx as usize)));
+            params[0].value = 0;
+            assert_eq!(passthru_ioctl(channel_idx, IoctlID::GET_CONFIG as u32, input, std::ptr::null_mut()), PassthruError::STATUS_NOERROR);
+            assert_eq!(params[0].value, 20);
+            let mut num_msgs: u32 = 1;
+            assert_eq!(write_msgs(channel_idx, &tx, &mut num_msgs, 100), PassthruError::STATUS_NOERROR);
+            assert_eq!(with_emulator(|e| e.tx_frames()).last().unwrap().data, vec![0x00, 0x00, 0x07, 0xE0, 0x3E, 0x00]);
+            assert_eq!(passthru_close(dev_idx), PassthruError::STATUS_NOERROR);
+        });
+    }
+
+    fn device_status() -> u32 {
+        let mut status: u32 = 99;
+        let res = passthru_ioctl(0, ioctl::GET_DEVICE_STATUS, std::ptr::null_mut(), &mut status as *mut u32 as *mut libc::c_void);
+        assert_eq!(res, PassthruError::STATUS_NOERROR);
+        status
+    }
+
+    #[test]
+    fn test_watchdog_detects_hung_adapter() {
+        each_model(|_| {
+            write_configs(&[TEST_PORT], serde_json::json!({ "HEARTBEAT_INTERVAL_MS": 20, "HEARTBEAT_MISSES": 3 }));
+            let dev_idx = open_device();
+            assert_eq!(device_status(), LinkStatus::Ok as u32);
+
+            // Request waiting on a hung adapter fails once the keepalives are missed, well before its own timeout
+            with_emulator(|e| e.set_hung(true));
+            let start = std::time::Instant::now();
+            let mut vbatt: u32 = 0;
+            let res = passthru_ioctl(0, IoctlID::READ_VBATT as u32, std::ptr::null_mut(), &mut vbatt as *mut u32 as *mut libc::c_void);
+            assert_eq!(res, PassthruError::ERR_DEVICE_NOT_CONNECTED);
+            assert!(start.elapsed() < Duration::from_millis(200), "Took {:?}", start.elapsed());
+            assert_eq!(device_status(), LinkStatus::NotResponding as u32);
+            assert!(last_error().contains("not responding"), "{}", last_error());
+
+            // Fails straight away whilst still hung
+            let start = std::time::Instant::now();
+            let res = passthru_ioctl(0, IoctlID::READ_VBATT as u32, std::ptr::null_mut(), &mut vbatt as *mut u32 as *mut libc::c_void);
+            assert_eq!(res, PassthruError::ERR_DEVICE_NOT_CONNECTED);
+            assert!(start.elapsed() < Duration::from_millis(20));
+
+            with_emulator(|e| e.set_hung(false));
+            assert!(wait_for(500, || device_status() == LinkStatus::Ok as u32));
+            let res = passthru_ioctl(0, IoctlID::READ_VBATT as u32, std::ptr::null_mut(), &mut vbatt as *mut u32 as *mut libc::c_void);
+            assert_eq!(res, PassthruError::STATUS_NOERROR);
+            assert_eq!(passthru_close(dev_idx), PassthruError::STATUS_NOERROR);
+        });
+    }
+
+    #[test]
+    fn test_transport_round_trip() {
+        let (host, mut fw) = memory::pair();
+        // Not a registered device, nothing is received for channels here
+        let dev = MacchinaM2::open_transport(0, Box::new(host), DeviceModel::A0).unwrap();
+
+        // Minimal firmware, answers every ReadBatt request with 12V
+        std::thread::spawn(move || {
+            let mut decoder = FrameDecoder::new();
+            let mut buf = [0u8; 256];
+            loop {
+                if let Ok(read) = fw.read(&mut buf) {
+                    decoder.push(&buf[..read]);
+                }
+                while let Some(msg) = decoder.next_msg() {
+                    if msg.msg_id != 0 && msg.msg_type == MsgType::ReadBatt {
+                        let mut resp = CommMsg::new_with_args(MsgType::ReadBatt, &[0x00, 0xE0, 0x2E, 0x00, 0x00]);
+                        resp.msg_id = msg.msg_id;
+                        fw.write_all(&emulator::encode_frame(false, &resp)).unwrap();
+                    }
+                }
+            }
+        });
+
+        match dev.write_and_read_ptcmd(&mut CommMsg::new(MsgType::ReadBatt), 250) {
+            M2Resp::Ok(args) => assert_eq!(LittleEndian::read_u32(&args), 12000),
+            M2Resp::Err { status, string } => panic!("ReadBatt failed {:?}: {}", status, string)
+        }
+    }
+
+    fn decode_all(decoder: &mut FrameDecoder) -> Vec<CommMsg> {
+        std::iter::from_fn(|| decoder.next_msg()).collect()
+    }
+
+    fn test_msg(msg_id: u8, args: &[u8]) -> CommMsg {
+        let mut msg = CommMsg::new_with_args(MsgType::ReceiveChannelData, args);
+        msg.msg_id = msg_id;
+        msg
+    }
+
+    #[test]
+    fn test_frame_round_trip() {
+        let msgs: Vec<CommMsg> = (0..10u8).map(|i| test_msg(i, &vec![i; i as usize * 100])).collect();
+        let stream: Vec<u8> = msgs.iter().flat_map(|m| m.to_slice()).collect();
+
+        // Whole stream at once, then one byte at a time
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&stream);
+        assert_eq!(decode_all(&mut decoder), msgs);
+        let mut decoded = Vec::new();
+        for b in stream.iter() {
+            decoder.push(&[*b]);
+            decoded.append(&mut decode_all(&mut decoder));
+        }
+        assert_eq!(decoded, msgs);
+        assert_eq!(decoder.take_discarded(), 0);
+    }
+
+    #[test]
+    fn test_frame_m2_padding() {
+        let msgs = vec![test_msg(1, &[0x01, 0x02]), test_msg(2, &[]), test_msg(3, &[0xFF; 300])];
+        let mut decoder = FrameDecoder::new();
+        for m in msgs.iter() {
+            decoder.push(&emulator::encode_frame(true, m));
+        }
+        assert_eq!(decode_all(&mut decoder), msgs);
+        // Padding is expected, so does not count as lost data
+        assert_eq!(decoder.take_discarded(), 0);
+    }
+
+    #[test]
+    fn test_frame_resync() {
+        let first = test_msg(1, &[0x10, 0x20, 0x30]);
+        let second = test_msg(2, &[0x40, 0x50]);
+        let third = test_msg(3, &[0x60]);
+
+        let mut decoder = FrameDecoder::new();
+        // Garbage, including a false sync marker, before a frame
+        decoder.push(&[0x12, 0xA5, 0x5A, 0xFF, 0xFF, 0x34, 0xA5]);
+        decoder.push(&first.to_slice());
+        // Frame with a byte dropped from the middle
+        let mut broken = second.to_slice();
+        broken.remove(7);
+        decoder.push(&broken);
+        // Frame with a corrupted byte
+        let mut corrupt = second.to_slice();
+        corrupt[6] ^= 0x01;
+        decoder.push(&corrupt);
+        decoder.push(&third.to_slice());
+
+        assert_eq!(decode_all(&mut decoder), vec![first, third]);
+        assert!(decoder.take_discarded() > 0);
+    }
+
+    #[test]
+    fn test_frame_false_header() {
+        let first = test_msg(1, &[0x10, 0x20, 0x30]);
+        let second = test_msg(2, &[0x40, 0x50]);
+
+        let mut decoder = FrameDecoder::new();
+        // False sync marker claiming 2048 bytes, which never come
+        decoder.push(&[0xA5, 0x5A, 0x00, 0x08, 0x12]);
+        assert_eq!(decoder.next_msg(), None);
+        // Frames after it are not held back
+        decoder.push(&first.to_slice());
+        assert_eq!(decoder.next_msg(), Some(first));
+        assert!(decoder.take_discarded() > 0);
+        decoder.push(&second.to_slice());
+        assert_eq!(decoder.next_msg(), Some(second));
+        assert_eq!(decoder.next_msg(), None);
+    }
+
+    #[test]
+    fn test_frame_format() {
+        let mut msg = CommMsg::new_with_args(MsgType::ReadBatt, &[0x01]);
+        msg.msg_id = 5;
+        let bytes = msg.to_slice();
+        assert_eq!(&bytes[0..7], &[0xA5, 0x5A, 0x03, 0x00, 0x05, 0x08, 0x01]);
+        assert_eq!(LittleEndian::read_u16(&bytes[7..9]), frame::crc16(&bytes[2..7]));
+        // CRC-16/CCITT-FALSE check value
+        assert_eq!(frame::crc16(b"123456789"), 0x29B1);
+    }
+
+    fn batt_resp(msg_id: u8, mv: u32) -> CommMsg {
+        let mut args = vec![0x00];
+        args.extend_from_slice(&mv.to_le_bytes());
+        let mut resp = CommMsg::new_with_args(MsgType::ReadBatt, &args);
+        resp.msg_id = msg_id;
+        resp
+    }
+
+    #[test]
+    fn test_pending_requests() {
+        let pending = PendingRequests::new();
+        let (id, rx) = pending.register(MsgType::ReadBatt, Duration::from_millis(500)).unwrap();
+        assert_ne!(id, 0);
+
+        // Response of the wrong type is not handed over
+        let mut wrong = CommMsg::new_with_args(MsgType::GetFwVersion, &[0x00]);
+        wrong.msg_id = id;
+        assert!(!pending.complete(wrong));
+        assert!(rx.try_recv().is_err());
+
+        assert!(pending.complete(batt_resp(id, 12000)));
+        assert_eq!(rx.try_recv().unwrap(), batt_resp(id, 12000));
+        assert_eq!(pending.waiting_count(), 0);
+
+        // Response after the request gave up is an orphan
+        let (id, rx) = pending.register(MsgType::ReadBatt, Duration::from_millis(500)).unwrap();
+        pending.cancel(id);
+        assert!(!pending.complete(batt_resp(id, 12000)));
+        assert!(rx.try_recv().is_err());
+
+        // Every waiting request has its own ID, and IDs run out rather than being shared
+        let held: Vec<_> = (0..254).map(|_| pending.register(MsgType::ReadBatt, Duration::from_millis(50)).unwrap()).collect();
+        let mut ids: Vec<u8> = held.iter().map(|(id, _)| *id).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), 254);
+        assert!(!ids.contains(&PROBE_MSG_ID));
+        assert!(pending.register(MsgType::ReadBatt, Duration::from_millis(50)).is_none());
+
+        // Until their deadline passes
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(pending.register(MsgType::ReadBatt, Duration::from_millis(50)).is_some());
+        assert_eq!(pending.waiting_count(), 1);
+    }
+
+    #[test]
+    fn test_late_response_is_dropped() {
+        let (host, mut fw) = memory::pair();
+        let dev = MacchinaM2::open_transport(0, Box::new(host), DeviceModel::A0).unwrap();
+
+        // Firmware which is slow to answer the first ReadBatt. Answers with the request number
+        std::thread::spawn(move || {
+            let mut decoder = FrameDecoder::new();
+            let mut buf = [0u8; 256];
+            let mut count = 0;
+            loop {
+                if let Ok(read) = fw.read(&mut buf) {
+                    decoder.push(&buf[..read]);
+                }
+                while let Some(msg) = decoder.next_msg() {
+                    if msg.msg_id != 0 && msg.msg_type == MsgType::ReadBatt {
+                        count += 1;
+                        if count == 1 {
+                            std::thread::sleep(Duration::from_millis(100));
+                        }
+                        fw.write_all(&emulator::encode_frame(false, &batt_resp(msg.msg_id, count))).unwrap();
+                    }
+                }
+            }
+        });
+
+        assert_eq!(dev.write_and_read(&mut CommMsg::new(MsgType::ReadBatt), 20), Err(PassthruError::ERR_TIMEOUT));
+        assert_eq!(dev.pending().waiting_count(), 0);
+        match dev.write_and_read_ptcmd(&mut CommMsg::new(MsgType::ReadBatt), 500) {
+            M2Resp::Ok(args) => assert_eq!(LittleEndian::read_u32(&args), 2),
+            M2Resp::Err { status, string } => panic!("ReadBatt failed {:?}: {}", status, string)
+        }
+    }
+
+    #[test]
+    fn test_concurrent_requests() {
+        each_model(|_| {
+            let dev_idx = open_device();
+            let threads: Vec<_> = (0..8).map(|_| std::thread::spawn(|| {
+                for _ in 0..50 {
+                    let mut vbatt: u32 = 0;
+                    let res = passthru_ioctl(0, IoctlID::READ_VBATT as u32, std::ptr::null_mut(), &mut vbatt as *mut u32 as *mut libc::c_void);
+                    assert_eq!(res, PassthruError::STATUS_NOERROR);
+                    assert_eq!(vbatt, emulator::DEFAULT_BATT_MV);
+                }
+            })).collect();
+            for t in threads {
+                t.join().unwrap();
+            }
+            assert_eq!(passthru_close(dev_idx), PassthruError::STATUS_NOERROR);
+        });
+    }
+
+    /// Opens the driver on a new emulator, without going through `passthru_open`
+    fn open_on_emulator(model: DeviceModel, legacy_framing: bool, link_rate: Option<u32>) -> Emulator {
+        let (emu, link) = Emulator::start_with_rate(model, link_rate);
+        emu.set_legacy_framing(legacy_framing);
+        let device = devices::register("memory").unwrap();
+        *device.connection.write().unwrap() = Some(MacchinaM2::open_transport(device.id, Box::new(link), model).unwrap());
+        emu
+    }
+
+    #[test]
+    fn test_m2_framing_negotiation() {
+        let _lock = lock_device();
+        for legacy in [false, true].iter() {
+            let emu = open_on_emulator(DeviceModel::M2, *legacy, None);
+            assert_eq!(emu.is_padding_frames(), *legacy);
+            let mut channel_idx: u32 = 0;
+            assert_eq!(passthru_connect(devices::DEVICE_ID, Protocol::CAN as u32, 0, 500_000, &mut channel_idx), PassthruError::STATUS_NOERROR);
+            emu.inject_rx(fw_channel(channel_idx), 0, &[0x00, 0x00, 0x07, 0xE8, 0x01]);
+            let mut rx = PASSTHRU_MSG::default();
+            let mut num_msgs: u32 = 1;
+            assert_eq!(read_msgs(channel_idx, &mut rx, &mut num_msgs, 500), PassthruError::STATUS_NOERROR);
+            assert_eq!(&rx.data[..rx.data_size as usize], &[0x00, 0x00, 0x07, 0xE8, 0x01]);
+            assert_eq!(passthru_close(devices::DEVICE_ID), PassthruError::STATUS_NOERROR);
+            // Goodbye puts the adapter back to its default, it is sent as the reader thread exits
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            assert!(!emu.is_connected());
+            assert!(emu.is_padding_frames());
+        }
+    }
+
+    /// Rx throughput of the M2 link, with padded and variable length frames.
+    /// The link is limited to roughly the rate the M2's native USB serial manages in practice.
+    /// Run with `cargo test --release -- --ignored --nocapture bench_m2_rx_frames`
+    #[test]
+    #[ignore]
+    fn bench_m2_rx_frames() {
+        const LINK_RATE: u32 = 1_000_000;
+        const BATCH: u32 = 250;
+        const BATCHES: u32 = 4;
+        let _lock = lock_device();
+        for legacy in [true, false].iter() {
+            let emu = open_on_emulator(DeviceModel::M2, *legacy, Some(LINK_RATE));
+            let mut channel_idx: u32 = 0;
+            assert_eq!(passthru_connect(devices::DEVICE_ID, Protocol::CAN as u32, 0, 500_000, &mut channel_idx), PassthruError::STATUS_NOERROR);
+            let mut rx = vec![PASSTHRU_MSG::default(); BATCH as usize];
+            let start = std::time::Instant::now();
+            for _ in 0..BATCHES {
+                for i in 0..BATCH {
+                    emu.inject_rx(fw_channel(channel_idx), 0, &[0x00, 0x00, 0x07, 0xE8, 0x08, i as u8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+                }
+                let mut read = 0;
+                while read < BATCH {
+                    let mut num_msgs = BATCH - read;
+                    read_msgs(channel_idx, rx.as_mut_ptr(), &mut num_msgs, 1000);
+                    read += num_msgs;
+                }
+            }
+            let elapsed = start.elapsed().as_secs_f64();
+            println!("M2 {} frames: {:.0} frames/sec", if *legacy { "padded" } else { "variable length" }, (BATCH * BATCHES) as f64 / elapsed);
+            assert_eq!(passthru_close(devices::DEVICE_ID), PassthruError::STATUS_NOERROR);
+        }
+    }
+
+    /// Time from a frame arriving at the emulator to a blocked PassThruReadMsgs returning it, as an ECU response would
+    /// Run with `cargo test --release -- --ignored --nocapture bench_rx_latency`
+    #[test]
+    #[ignore]
+    fn bench_rx_latency() {
+        const READS: usize = 500;
+        let _lock = lock_device();
+        let emu = open_on_emulator(DeviceModel::M2, false, None);
+        let mut channel_idx: u32 = 0;
+        assert_eq!(passthru_connect(devices::DEVICE_ID, Protocol::CAN as u32, 0, 500_000, &mut channel_idx), PassthruError::STATUS_NOERROR);
+        let fw_id = fw_channel(channel_idx);
+        let mut latencies: Vec<Duration> = std::thread::scope(|s| {
+            let (sent_tx, sent_rx) = std::sync::mpsc::channel();
+            let emu = &emu;
+            s.spawn(move || {
+                for i in 0..READS {
+                    // Give the reader time to block first
+                    std::thread::sleep(Duration::from_millis(1));
+                    let _ = sent_tx.send(std::time::Instant::now());
+                    emu.inject_rx(fw_id, 0, &[0x00, 0x00, 0x07, 0xE8, 0x02, 0x7E, i as u8]);
+                }
+            });
+            (0..READS).map(|_| {
+                let mut rx = PASSTHRU_MSG::default();
+                let mut num_msgs: u32 = 1;
+                assert_eq!(read_msgs(channel_idx, &mut rx, &mut num_msgs, 1000), PassthruError::STATUS_NOERROR);
+                sent_rx.recv().unwrap().elapsed()
+            }).collect()
+        });
+        latencies.sort();
+        let mean = latencies.iter().sum::<Duration>() / READS as u32;
+        println!("Rx latency: mean {} us, median {} us, 99th percentile {} us", mean.as_micros(), latencies[READS / 2].as_micros(), latencies[READS * 99 / 100].as_micros());
+        assert_eq!(passthru_close(devices::DEVICE_ID), PassthruError::STATUS_NOERROR);
+    }
+
+    /// Runs the driver over a real serial port, with the emulator on the other end of a pseudo terminal.
+    /// This goes through the same reader and writer threads as a physical adapter would
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_pty_serial_link() {
+        let _lock = lock_device();
+        for model in [DeviceModel::M2, DeviceModel::A0].iter() {
+            let (master, slave_path) = pty::open().unwrap();
+            let emu = Emulator::start_on(Box::new(master), *model);
+            let device = devices::register(&slave_path).unwrap();
+            *device.connection.write().unwrap() = Some(MacchinaM2::open_conn(device.id, &slave_path, *model, Settings::default()).unwrap());
+            assert!(emu.is_connected());
+
+            let mut channel_idx: u32 = 0;
+            assert_eq!(passthru_connect(devices::DEVICE_ID, Protocol::ISO15765 as u32, 0, 500_000, &mut channel_idx), PassthruError::STATUS_NOERROR);
+
+            // Large payload, more than the pty buffers in one go
+            let mut tx = can_msg(Protocol::ISO15765, &[0x00, 0x00, 0x07, 0xE0]);
+            tx.data_size = 4096+4;
+            let mut num_msgs: u32 = 1;
+            assert_eq!(write_msgs(channel_idx, &tx, &mut num_msgs, 1000), PassthruError::STATUS_NOERROR);
+            assert_eq!(emu.tx_frames()[0].data.len(), 4096+4);
+
+            // Burst of Rx frames, each must arrive intact and in order
+            let mut rx = vec![PASSTHRU_MSG::default(); 65];
+            let mut num_msgs: u32 = 1;
+            assert_eq!(read_msgs(channel_idx, rx.as_mut_ptr(), &mut num_msgs, 500), PassthruError::STATUS_NOERROR); // Tx confirmation
+            for i in 0..64u8 {
+                emu.inject_rx(fw_channel(channel_idx), 0, &[0x00, 0x00, 0x07, 0xE8, 0x03, 0x62, i]);
+            }
+            let mut num_msgs: u32 = 64;
+            assert_eq!(read_msgs(channel_idx, rx.as_mut_ptr(), &mut num_msgs, 2000), PassthruError::STATUS_NOERROR);
+            for (i, msg) in rx.iter().take(64).enumerate() {
+                assert_eq!(&msg.data[..msg.data_size as usize], &[0x00, 0x00, 0x07, 0xE8, 0x03, 0x62, i as u8]);
+            }
+            assert_eq!(passthru_close(devices::DEVICE_ID), PassthruError::STATUS_NOERROR);
+        }
+    }
+
+    /// When the config does not say which adapter is attached, the driver asks it
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_probe_device_model() {
+        for model in [DeviceModel::M2, DeviceModel::A0].iter() {
+            let (master, slave_path) = pty::open().unwrap();
+            let _emu = Emulator::start_on(Box::new(master), *model);
+            assert_eq!(probe_device_model(&slave_path, *model), Some(*model));
+        }
+        let (_master, slave_path) = pty::open().unwrap();
+        assert_eq!(probe_device_model(&slave_path, DeviceModel::A0), None);
+    }
+
+    #[test]
+    fn test_device_model_names() {
+        assert_eq!(DeviceModel::from_name("m2"), Some(DeviceModel::M2));
+        assert_eq!(DeviceModel::from_name("A0"), Some(DeviceModel::A0));
+        assert_eq!(DeviceModel::from_name("M3"), None);
+        assert_eq!(DeviceModel::from_fw_version("1.0.0_M2"), Some(DeviceModel::M2));
+        assert_eq!(DeviceModel::from_fw_version("1.0.1_A0"), Some(DeviceModel::A0));
+        assert_eq!(DeviceModel::from_fw_version("1.0.1"), None);
     }
-}
\ No newline at end of file
+}
@@ -7,12 +7,18 @@ use std::sync::mpsc::{channel, Sender, Receiver};
 
 #[cfg(windows)]
 lazy_static!{
-    static ref LOG_PATH: PathBuf = PathBuf::from(r"C:\Program Files (x86)\macchina\passthru\macchina_log.txt");
+    static ref LOG_PATH: RwLock<PathBuf> = RwLock::new(PathBuf::from(r"C:\Program Files (x86)\macchina\passthru\macchina_log.txt"));
 }
 
 #[cfg(unix)]
 lazy_static! {
-    static ref LOG_PATH: PathBuf = PathBuf::from(r"macchina_log.txt");
+    static ref LOG_PATH: RwLock<PathBuf> = RwLock::new(PathBuf::from(r"macchina_log.txt"));
+}
+
+/// Logs to `path` from now on, as set by LOG_PATH in the config
+#[cfg_attr(test, allow(dead_code))]
+pub fn set_log_path(path: PathBuf) {
+    *LOG_PATH.write().unwrap() = path;
 }
 
 lazy_static! {
@@ -86,7 +92,8 @@ impl Logger {
     #[cfg(not(test))]
     // Not test mode - Write to file
     fn write_to_file(txt: String) {
-        let p: &std::path::Path = LOG_PATH.as_path();
+        let path = LOG_PATH.read().unwrap().clone();
+        let p: &std::path::Path = path.as_path();
 
         if !p.exists() {
             if let Err(x) = File::create(p) {
@@ -94,12 +101,17 @@ impl Logger {
             }
         }
         println!("{}", txt);
-        let mut ops = std::fs::OpenOptions::new()
+        let mut ops = match std::fs::OpenOptions::new()
             .write(true)
             .append(true)
             .create(false)
-            .open(p)
-            .unwrap();
+            .open(p) {
+            Ok(f) => f,
+            Err(e) => {
+                eprintln!("LOG FILE OPEN ERROR! [{}]", e);
+                return;
+            }
+        };
 
         if let Err(e) = writeln!(ops, "{}", txt) {
             eprintln!("WRITE ERROR! [{}] - '{}'", e, txt);
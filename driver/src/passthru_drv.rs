@@ -1,11 +1,12 @@
 use libc::{c_char};
-use std::{ffi::CString, time::Instant};
+use std::ffi::CString;
 use j2534_rust::*;
-use crate::{channels, ioctl, logger};
+use crate::{channels, devices, ioctl, logger};
+use crate::selector::Selector;
 use crate::comm::*;
 use lazy_static::lazy_static;
 use std::sync::Mutex;
-use crate::channels::ChannelComm;
+use crate::channels::{ChannelComm, ProtocolId};
 use crate::logger::*;
 use std::ptr::write;
 
@@ -26,9 +27,6 @@ pub fn set_error_string(input: String) {
     *state = input;
 }
 
-/// Our device ID that will be returned back to the application (0x1234)
-const DEVICE_ID: u32 = 0x1234;
-
 fn copy_str_unsafe(dst: *mut c_char, src: &str) -> bool {
     if dst.is_null() {
         logger::log_info(format!("Error copying '{}' - Source ptr is null", src));
@@ -50,13 +48,18 @@ fn copy_str_unsafe(dst: *mut c_char, src: &str) -> bool {
 /// Copies the API_VERSION, DLL_VERSION and FW_VERSION
 /// back to the pointers set by the source application
 pub fn passthru_read_version(
+    device_id: u32,
     fw_version_ptr: *mut c_char,
     dll_version_ptr: *mut c_char,
     api_version_ptr: *mut c_char
 ) -> PassthruError {
-    let fw_version = run_on_m2(|dev| {
+    let device_id = match devices::from_handle(device_id) {
+        Ok(d) => d.id,
+        Err(e) => return e
+    };
+    let fw_version = run_on_m2(device_id, |dev| {
         let mut msg = CommMsg::new(MsgType::GetFwVersion);
-        match dev.write_and_read_ptcmd(&mut msg, 250) {
+        match dev.write_and_read_ptcmd(&mut msg, dev.settings().timeouts.command_ms) {
             M2Resp::Ok(args) => { Ok(String::from_utf8(args).unwrap()) },
             M2Resp::Err{status, string} => {
                 log_warn(format!("M2 failed to respond to FW_VERSION request: {}", string));
@@ -93,60 +96,141 @@ pub fn passthru_get_last_error(dest: *mut c_char) -> PassthruError {
 }
 
 
-pub fn passthru_open(device_id: *mut u32) -> PassthruError {
+/// Opens an adapter
+/// # Params
+/// * name - Which adapter to open (See `selector.rs`), or null for the one in the config
+/// * device_id - Pointer to write the device ID of the opened adapter to
+pub fn passthru_open(name: *const c_char, device_id: *mut u32) -> PassthruError {
     logger::log_info_str("PassthruOpen called");
-    // Check if the device is already loaded
-    if M2.read().unwrap().is_some() {
-        PassthruError::ERR_DEVICE_IN_USE
-    } else {
-        // Try to open a connection
-        match MacchinaM2::open_connection() {
-            Ok(dev) => {
-                // Device loaded OK!
-                if let Ok(ptr) = M2.write().as_deref_mut() {
-                    *ptr = Some(dev);
-                    unsafe { write(device_id, DEVICE_ID) };
-                    PassthruError::STATUS_NOERROR
-                } else {
-                    // Something happened trying to write to the static reference of the M2
-                    set_error_string("Failed to obtain write access to M2".into());
-                    PassthruError::ERR_FAILED
+    if device_id.is_null() {
+        return PassthruError::ERR_NULL_PARAMETER;
+    }
+    let selector = match Selector::from_ptr(name) {
+        Ok(s) => s,
+        Err(e) => {
+            logger::log_error(e.clone());
+            set_error_string(e);
+            return PassthruError::ERR_FAILED
+        }
+    };
+    let configs = match MacchinaM2::find_adapters(&selector) {
+        Ok(c) => c,
+        Err(e) => {
+            logger::log_error(e.clone());
+            set_error_string(e);
+            return PassthruError::ERR_FAILED
+        }
+    };
+    if configs.is_empty() {
+        set_error_string("Cannot find an adapter. Plug one in, or set COM-PORT in the config".into());
+        return PassthruError::ERR_DEVICE_NOT_CONNECTED
+    }
+    let mut res = PassthruError::ERR_DEVICE_NOT_CONNECTED;
+    for cfg in configs.iter() {
+        match open_adapter(cfg, selector.serial()) {
+            Ok(id) => {
+                unsafe { write(device_id, id) };
+                return PassthruError::STATUS_NOERROR
+            },
+            Err(e) => res = e
+        }
+    }
+    if let Selector::Serial(serial) = &selector {
+        set_error_string(format!("No adapter with serial number {} found", serial));
+        return PassthruError::ERR_DEVICE_NOT_CONNECTED
+    }
+    res
+}
+
+/// Opens the adapter on the port of `cfg`, giving it a device ID. If `serial` is given,
+/// the adapter is closed again unless it reports that serial number
+fn open_adapter(cfg: &DeviceConfig, serial: Option<&str>) -> PTResult<u32> {
+    let device = devices::register(&cfg.port)?; // Fails if already open
+    let res = connect_adapter(device.id, cfg, serial).and_then(|dev| {
+        // Device loaded OK!
+        if let Ok(ptr) = device.connection.write().as_deref_mut() {
+            *ptr = Some(dev);
+            Ok(device.id)
+        } else {
+            // Something happened trying to write to the device's connection
+            set_error_string("Failed to obtain write access to M2".into());
+            Err(PassthruError::ERR_FAILED)
+        }
+    });
+    if res.is_err() {
+        devices::remove(device.id);
+    }
+    res
+}
+
+/// Opens the connection to an adapter, and checks it can be used.
+/// Dropping the connection closes it again
+fn connect_adapter(device_id: u32, cfg: &DeviceConfig, serial: Option<&str>) -> PTResult<MacchinaM2> {
+    let mut dev = match MacchinaM2::open_connection(device_id, cfg) {
+        Ok(d) => d,
+        Err(x) => {
+            // Error loading the device driver. Could be due to the device
+            // not being connected to the PC, or a serial error
+            logger::log_error(format!("Cannot open com port. Error: {}", x));
+            set_error_string(format!("Serial port open failed with error {}", x));
+            return Err(PassthruError::ERR_DEVICE_NOT_CONNECTED)
+        }
+    };
+    if let Err(e) = dev.query_capabilities() {
+        logger::log_error(format!("Refusing adapter: {}", LAST_ERROR_STR.lock().unwrap()));
+        return Err(e);
+    }
+    if let Some(caps) = dev.capabilities() {
+        for protocol in dev.settings().unsupported_claims(caps) {
+            logger::log_warn(format!("Config on {} says the adapter has {:?}, but its firmware does not", cfg.port, protocol));
+        }
+    }
+    // Says which adapter this is in the log, when several are on the bench
+    match dev.read_device_info() {
+        Ok(info) => {
+            if let Some(s) = serial {
+                if !info.serial.eq_ignore_ascii_case(s) {
+                    logger::log_info(format!("Adapter on {} has serial number {}, not {}", cfg.port, info.serial, s));
+                    return Err(PassthruError::ERR_DEVICE_NOT_CONNECTED)
                 }
             }
-            Err(x) => {
-                // Error loading the device driver. Could be due to the device
-                // not being connected to the PC, or a serial error
-                logger::log_error(format!("Cannot open com port. Error: {}", x));
-                set_error_string(format!("Serial port open failed with error {}", x));
-                PassthruError::ERR_DEVICE_NOT_CONNECTED
-            }
-        }
+            logger::log_info(format!(
+                "Opened {} (Board revision {}), serial {}, up for {}ms, as device {}",
+                info.model, info.board_revision, info.serial, info.uptime_ms, device_id
+            ))
+        },
+        // Cannot tell if it is the adapter asked for
+        Err(e) if serial.is_some() => return Err(e),
+        Err(e) => logger::log_warn(format!("Could not read adapter device info: {:?}", e))
     }
+    Ok(dev)
 }
 
 /// Attempts to close the device
 pub fn passthru_close(device_id: u32) -> PassthruError {
     logger::log_info(format!("PassthruClose called. Device ID: {}", device_id));
-    // Device ID which isn't our device ID - So it cannot be for this driver!
-    if device_id != DEVICE_ID {
-        return PassthruError::ERR_INVALID_DEVICE_ID;
-    }
-    if let Ok(d) = M2.write().as_deref_mut() {
-        match d {
-            Some(dev) => {
-                dev.stop(); // Terminate the M2 connection
-                // Kill all open channels if any exist
-                channels::ChannelComm::force_destroy_all_channels();
-                *d = None; // Set M2 reference to None
-                PassthruError::STATUS_NOERROR
-            },
-            // Already terminated, just return NO_ERROR
-            None => PassthruError::STATUS_NOERROR
+    // Taken out of the registry before stopping, as the reader thread may look the device up whilst it is being stopped
+    let device = match devices::remove(device_id) {
+        Some(d) => d,
+        // Not one of our open devices
+        None => return PassthruError::ERR_INVALID_DEVICE_ID
+    };
+    // Before the connection goes, so the last periodic messages are not sent to a closed port
+    device.periodic.shutdown();
+    let taken = device.connection.write().map(|mut d| d.take());
+    // Kill all open channels if any exist
+    device.channels.clear();
+    match taken {
+        Ok(Some(mut dev)) => {
+            dev.stop(); // Terminate the M2 connection, returns once the port is closed
+            PassthruError::STATUS_NOERROR
+        },
+        Ok(None) => PassthruError::STATUS_NOERROR,
+        Err(_) => {
+            // Something unknown happened when trying to write to the RwLockGuard
+            set_error_string("Error obtaining access to RwLockGuard".into());
+            PassthruError::ERR_FAILED
         }
-    } else {
-        // Something unknown happened when trying to write to the RwLockGuard
-        set_error_string("Error obtaining access to RwLockGuard".into());
-        PassthruError::ERR_FAILED
     }
 }
 
@@ -158,9 +242,9 @@ pub fn passthru_close(device_id: u32) -> PassthruError {
 /// * Baud_rate - Bus speed of the communication channel
 /// * channel_id_ptr - Pointer to write the channel ID of the opened communication link to
 pub fn passthru_connect(device_id: u32, protocol_id: u32, flags: u32, baud_rate: u32, channel_id_ptr: *mut u32) -> PassthruError {
-    if device_id != DEVICE_ID {
-        // Diagnostic Software messed up here. Not my device ID!
-        set_error_string(format!("Not M2s device ID. Expected {}, got {}", DEVICE_ID, device_id));
+    if devices::get(device_id).is_err() {
+        // Diagnostic Software messed up here. Not one of our open devices!
+        set_error_string(format!("Device ID {} is not an open adapter", device_id));
         return PassthruError::ERR_DEVICE_NOT_CONNECTED;
     }
     // Fatal error by diagnostic software - Cannot happen!
@@ -170,10 +254,10 @@ pub fn passthru_connect(device_id: u32, protocol_id: u32, flags: u32, baud_rate:
     }
 
     // Obtain the protocol type
-    match Protocol::try_from(protocol_id) {
-        Ok(protocol) => { // Valid protocol
+    match ProtocolId::parse(protocol_id) {
+        Some(protocol) => { // Valid protocol
             // Try to create the logical communication channel
-            match ChannelComm::create_channel(protocol, baud_rate, flags) {
+            match ChannelComm::create_channel(device_id, protocol, baud_rate, flags) {
                 Ok(channel_id) => { // Channel ID creation was OK! - Save it to the pointer
                     unsafe { *channel_id_ptr = channel_id };
                     PassthruError::STATUS_NOERROR
@@ -182,7 +266,7 @@ pub fn passthru_connect(device_id: u32, protocol_id: u32, flags: u32, baud_rate:
                 Err(x) => x
             }
         },
-        Err(_) => { // Protocol ID was invalid (Not found in J2534 spec), throw an error
+        None => { // Protocol ID was invalid (Not found in J2534 spec), throw an error
             logger::log_error(format!("{} is not recognised as a valid protocol ID!", protocol_id));
             PassthruError::ERR_INVALID_PROTOCOL_ID
         }
@@ -212,6 +296,9 @@ pub fn passthru_ioctl(
     input_ptr: *mut libc::c_void,
     output_ptr: *mut libc::c_void,
 ) -> PassthruError {
+    if ioctl_id >= ioctl::TOOL_IOCTL_BASE {
+        return ioctl::tool_ioctl(channel_id, ioctl_id, output_ptr)
+    }
     // Try to parse the IOCTL ID
     let ioctl_opt = match IoctlID::try_from(ioctl_id) {
         Ok(p) => p, // Successful parse
@@ -228,7 +315,7 @@ pub fn passthru_ioctl(
                 log_error_str("Cannot read battery voltage. Output ptr is null");
                 return PassthruError::ERR_NULL_PARAMETER 
             }
-            ioctl::read_vbatt(output_ptr as *mut u32)
+            ioctl::read_vbatt(channel_id, output_ptr as *mut u32)
         },
 
         // READ PROG VOLTAGE: Input: NULL, Output: unsigned long
@@ -392,73 +479,65 @@ pub fn write_msgs(channel_id: u32, msg_ptr: *const PASSTHRU_MSG, num_msg_ptr: *m
         return PassthruError::ERR_NULL_PARAMETER
     }
 
-    let max_msgs = *unsafe { num_msg_ptr.as_ref() }.unwrap() as usize;
-    // Set num_msg_ptr to 0, we will increment it as reading to keep track how many messages have been written
-    unsafe { *num_msg_ptr = 0 };
-    let start_time = Instant::now();
-    for i in 0..max_msgs as isize {
-        if timeout_ms != 0 && start_time.elapsed().as_millis() > timeout_ms as u128 { // Timeout!
-            return PassthruError::ERR_TIMEOUT
-        }
-        let curr_msg = match unsafe { msg_ptr.offset(i).as_ref() } {
-            Some(m) => m,
-            None => return PassthruError::ERR_NULL_PARAMETER
-        };
-        match channels::ChannelComm::write_channel_data(channel_id, curr_msg, timeout_ms != 0) {
-            Ok(()) => {}, // Continue
-            Err(e) => return e // Stop sending and return the error to the application
-        }
-        unsafe { *num_msg_ptr += 1 };
+    let num_msgs = unsafe { *num_msg_ptr } as usize;
+    let msgs = unsafe { std::slice::from_raw_parts(msg_ptr, num_msgs) };
+    // The messages go in the channel's Tx queue. With a timeout, wait for them to be sent too.
+    // Either way, num_msg_ptr says how many made it, even if the rest failed
+    let (written, res) = channels::ChannelComm::write_channel_data(channel_id, msgs, timeout_ms);
+    unsafe { *num_msg_ptr = written };
+    match res {
+        Ok(()) => PassthruError::STATUS_NOERROR,
+        Err(e) => e
+    }
+}
+
+pub fn start_periodic_msg(channel_id: u32, msg_ptr: *const PASSTHRU_MSG, msg_id_ptr: *mut u32, interval_ms: u32) -> PassthruError {
+    if msg_id_ptr.is_null() {
+        return PassthruError::ERR_NULL_PARAMETER
+    }
+    let msg = match unsafe { msg_ptr.as_ref() } {
+        Some(m) => m,
+        None => return PassthruError::ERR_NULL_PARAMETER
+    };
+    match ChannelComm::start_periodic(channel_id, msg, interval_ms) {
+        Ok(id) => {
+            unsafe { *msg_id_ptr = id };
+            PassthruError::STATUS_NOERROR
+        },
+        Err(e) => e
+    }
+}
+
+pub fn stop_periodic_msg(channel_id: u32, msg_id: u32) -> PassthruError {
+    match ChannelComm::stop_periodic(channel_id, msg_id) {
+        Ok(_) => PassthruError::STATUS_NOERROR,
+        Err(e) => e
     }
-    PassthruError::STATUS_NOERROR
 }
 
 pub fn read_msgs(channel_id: u32, msg_ptr: *mut PASSTHRU_MSG, num_msg_ptr: *mut u32, timeout_ms: u32) -> PassthruError {
     if msg_ptr.is_null() || num_msg_ptr.is_null() {
         return PassthruError::ERR_NULL_PARAMETER
     }
-    let max_msgs = *unsafe { num_msg_ptr.as_ref() }.unwrap() as isize;
-    // Set num_msg_ptr to 0, we will increment it as reading to keep track how many messages have been read
+    let max_msgs = unsafe { *num_msg_ptr } as usize;
+    // Set num_msg_ptr to 0, so it says how many messages were read if the read fails
     unsafe { *num_msg_ptr = 0 };
 
-    let mut read_msgs = 0isize;
-    if timeout_ms == 0 { // Non-blocking read
-        loop {
-            match channels::ChannelComm::read_channel_data(channel_id) {
-                Ok(result) => {
-                    if let Some(msg) = result {
-                        unsafe { *msg_ptr.offset(read_msgs) = msg; }
-                        unsafe { *num_msg_ptr += 1 };
-                        read_msgs += 1;
-                        if read_msgs == max_msgs {
-                            return PassthruError::STATUS_NOERROR; // Reading completed!
-                        }
-                    } else {
-                        // No more messages to read, but haven't hit limit
-                        return PassthruError::ERR_BUFFER_EMPTY
-                    }
-                },
-                Err(e) => return e // Unknown error reading!
-            }
-        }
-    } else { // Blocking read
-        let start_time = Instant::now();
-        while start_time.elapsed().as_millis() <= timeout_ms as u128 {
-            match channels::ChannelComm::read_channel_data(channel_id) {
-                Ok(opt) => {
-                    if let Some(msg) = opt { // Read a message
-                        unsafe { *msg_ptr.offset(read_msgs) = msg; }
-                        unsafe { *num_msg_ptr += 1 };
-                        read_msgs += 1;
-                    }
-                }
-                Err(e) => return e // Internal error reading messages!
-            }
-            if read_msgs == max_msgs as isize {
-                return PassthruError::STATUS_NOERROR; // Read completed OK!
-            }
-            std::thread::sleep(std::time::Duration::from_micros(500)); // Wait for a bit before trying to read again
-        }
-        return PassthruError::ERR_TIMEOUT; // Timeout reading
+    // With a timeout, blocks until max_msgs have been received, rather than polling the channel
+    let (msgs, overflowed) = match channels::ChannelComm::read_channel_data(channel_id, max_msgs, timeout_ms) {
+        Ok(m) => m,
+        Err(e) => return e // Unknown error reading!
+    };
+    let out = unsafe { std::slice::from_raw_parts_mut(msg_ptr, max_msgs) };
+    out[..msgs.len()].copy_from_slice(&msgs);
+    unsafe { *num_msg_ptr = msgs.len() as u32 };
+    if overflowed {
+        PassthruError::ERR_BUFFER_OVERFLOW // Messages were lost before these were read
+    } else if msgs.len() == max_msgs {
+        PassthruError::STATUS_NOERROR // Reading completed!
+    } else if timeout_ms == 0 {
+        PassthruError::ERR_BUFFER_EMPTY // No more messages to read, but haven't hit limit
+    } else {
+        PassthruError::ERR_TIMEOUT // Timeout reading
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,103 @@
+// Requests sent to the adapter which are waiting on a response.
+//
+// The adapter echoes the ID of a request in its response, so each request
+// in flight holds a unique ID until it either gets its response or times out.
+// Responses that turn up after their request gave up are logged and dropped,
+// rather than being handed to the next request that happens to reuse the ID
+
+use std::collections::{HashMap, hash_map::Entry};
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::{Duration, Instant};
+use crate::comm::{CommMsg, MsgType, PROBE_MSG_ID};
+use crate::logger::log_warn;
+
+struct Pending {
+    msg_type: MsgType,
+    deadline: Instant,
+    sender: Sender<CommMsg>,
+}
+
+#[derive(Default)]
+struct Table {
+    entries: HashMap<u8, Pending>,
+    last_id: u8,
+}
+
+#[derive(Default)]
+pub struct PendingRequests {
+    table: Mutex<Table>,
+}
+
+impl PendingRequests {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reserves an ID for a request of `msg_type`, which will wait up to `timeout` for its response.
+    /// Returns the ID, and the receiver the response will arrive on. None if every ID is in use
+    pub fn register(&self, msg_type: MsgType, timeout: Duration) -> Option<(u8, Receiver<CommMsg>)> {
+        let mut guard = self.table.lock().unwrap();
+        let table = &mut *guard;
+        let now = Instant::now();
+        table.entries.retain(|_, p| p.deadline > now);
+        // ID 0 tells the adapter not to respond, and PROBE_MSG_ID is kept for probing, so neither is handed out
+        let mut id = table.last_id;
+        for _ in 0..u8::MAX {
+            id = if id == u8::MAX { 1 } else { id + 1 };
+            if id == PROBE_MSG_ID {
+                continue;
+            }
+            if let Entry::Vacant(e) = table.entries.entry(id) {
+                let (sender, receiver) = channel();
+                e.insert(Pending { msg_type, deadline: now + timeout, sender });
+                table.last_id = id;
+                return Some((id, receiver));
+            }
+        }
+        None
+    }
+
+    /// Removes a request that is no longer waiting, such as after a timeout
+    pub fn cancel(&self, id: u8) {
+        self.table.lock().unwrap().entries.remove(&id);
+    }
+
+    /// Hands a response from the adapter to the request waiting on it.
+    /// Returns false if nothing was waiting for it
+    pub fn complete(&self, msg: CommMsg) -> bool {
+        let mut table = self.table.lock().unwrap();
+        match table.entries.get(&msg.msg_id) {
+            None => {
+                log_warn(format!("Orphan response from adapter, nothing waiting on ID {} - Type: {:?}", msg.msg_id, msg.msg_type));
+                false
+            },
+            Some(p) if p.msg_type != msg.msg_type => {
+                // Leave the entry be, its own response may still arrive
+                log_warn(format!("Orphan response from adapter, ID {} is waiting on {:?}, got {:?}", msg.msg_id, p.msg_type, msg.msg_type));
+                false
+            },
+            Some(p) if p.deadline <= Instant::now() => {
+                log_warn(format!("Response for ID {} - Type: {:?} arrived after its deadline", msg.msg_id, msg.msg_type));
+                table.entries.remove(&msg.msg_id);
+                false
+            },
+            Some(_) => {
+                let p = table.entries.remove(&msg.msg_id).unwrap();
+                // Receiver can only be gone if the caller gave up without cancelling
+                p.sender.send(msg).is_ok()
+            }
+        }
+    }
+
+    /// Drops every request, their callers see the response channel disconnect
+    pub fn fail_all(&self) {
+        self.table.lock().unwrap().entries.clear();
+    }
+
+    /// Number of requests still waiting
+    #[cfg(test)]
+    pub fn waiting_count(&self) -> usize {
+        self.table.lock().unwrap().entries.len()
+    }
+}
@@ -0,0 +1,162 @@
+// Periodic messages, started by PassThruStartPeriodicMsg. Each adapter has one scheduler thread
+// which sends every periodic message of its channels, such as tester present keepalives.
+//
+// Send times are kept on a fixed grid from when the message was started, rather than being
+// counted from when the last send finished, so a slow send does not make the rest drift.
+// If the thread falls more than an interval behind, the missed sends are skipped
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{spawn, JoinHandle};
+use std::time::{Duration, Instant};
+use j2534_rust::{PassthruError, PASSTHRU_MSG};
+use crate::channels::ChannelComm;
+use crate::logger::{log_debug, log_debug_str, log_warn};
+use crate::passthru_drv::set_error_string;
+
+/// Defined in J2534 spec. Each channel can have up to 10 periodic messages
+pub const MAX_PERIODIC_PER_CHANNEL: u32 = 10;
+/// Shortest and longest interval J2534 allows, in milliseconds
+const MIN_INTERVAL_MS: u32 = 5;
+const MAX_INTERVAL_MS: u32 = 65535;
+
+type Result<T> = std::result::Result<T, PassthruError>;
+
+struct Periodic {
+    msg: PASSTHRU_MSG,
+    interval: Duration,
+    next: Instant,
+    /// Set once a send has failed, so a failing message is only logged once
+    failing: bool,
+}
+
+#[derive(Default)]
+struct State {
+    /// Keyed by channel ID and message ID
+    msgs: BTreeMap<(u32, u32), Periodic>,
+    stopping: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    /// Signalled when a message is started or stopped, or the scheduler is stopping
+    wake: Condvar,
+}
+
+#[derive(Default)]
+pub struct Scheduler {
+    shared: Arc<Shared>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Scheduler {
+    /// Starts sending `msg` on a channel every `interval_ms`, with the first send straight away.
+    /// The message is expected to have been checked against the channel already
+    /// # Returns
+    /// ID of the periodic message, unique within the channel
+    pub fn start(&self, channel_id: u32, msg: &PASSTHRU_MSG, interval_ms: u32) -> Result<u32> {
+        if !(MIN_INTERVAL_MS..=MAX_INTERVAL_MS).contains(&interval_ms) {
+            set_error_string(format!("Periodic interval must be from {} to {} ms, not {}", MIN_INTERVAL_MS, MAX_INTERVAL_MS, interval_ms));
+            return Err(PassthruError::ERR_INVALID_TIME_INTERVAL);
+        }
+        let mut state = self.shared.state.lock().unwrap();
+        let msg_id = match (0..MAX_PERIODIC_PER_CHANNEL).find(|id| !state.msgs.contains_key(&(channel_id, *id))) {
+            Some(id) => id,
+            None => return Err(PassthruError::ERR_EXCEEDED_LIMIT)
+        };
+        state.msgs.insert((channel_id, msg_id), Periodic {
+            msg: *msg,
+            interval: Duration::from_millis(interval_ms as u64),
+            next: Instant::now(),
+            failing: false,
+        });
+        state.stopping = false;
+        drop(state);
+        log_debug(format!("Channel {} started periodic message {} every {} ms", channel_id, msg_id, interval_ms));
+        let mut thread = self.thread.lock().unwrap();
+        if thread.is_none() {
+            let shared = self.shared.clone();
+            *thread = Some(spawn(move || run(shared)));
+        } else {
+            self.shared.wake.notify_all();
+        }
+        Ok(msg_id)
+    }
+
+    pub fn stop(&self, channel_id: u32, msg_id: u32) -> Result<()> {
+        match self.shared.state.lock().unwrap().msgs.remove(&(channel_id, msg_id)) {
+            Some(_) => {
+                self.shared.wake.notify_all();
+                Ok(())
+            },
+            None => Err(PassthruError::ERR_INVALID_MSG_ID)
+        }
+    }
+
+    /// Stops every periodic message of a channel
+    pub fn clear_channel(&self, channel_id: u32) {
+        self.shared.state.lock().unwrap().msgs.retain(|(c, _), _| *c != channel_id);
+        self.shared.wake.notify_all();
+    }
+
+    /// Periodic messages being sent, across every channel
+    #[cfg(test)]
+    pub fn active(&self) -> usize {
+        self.shared.state.lock().unwrap().msgs.len()
+    }
+
+    /// Stops every periodic message, and waits for the thread to exit. Used when the adapter is closed
+    pub fn shutdown(&self) {
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            state.msgs.clear();
+            state.stopping = true;
+        }
+        self.shared.wake.notify_all();
+        if let Some(t) = self.thread.lock().unwrap().take() {
+            let _ = t.join();
+        }
+    }
+}
+
+fn run(shared: Arc<Shared>) {
+    log_debug_str("Periodic message thread starting!");
+    #[cfg(windows)]
+    unsafe { winapi::um::timeapi::timeBeginPeriod(1); } // Default timer resolution is ~15ms, too coarse for short intervals
+    let mut state = shared.state.lock().unwrap();
+    while !state.stopping {
+        let now = Instant::now();
+        let due: Vec<(u32, u32, PASSTHRU_MSG)> = state.msgs.iter_mut().filter(|(_, p)| p.next <= now).map(|((c, id), p)| {
+            p.next += p.interval;
+            if p.next <= now {
+                p.next = now + p.interval;
+            }
+            (*c, *id, p.msg)
+        }).collect();
+        if !due.is_empty() {
+            // Not held whilst sending, so messages can be started and stopped meanwhile
+            drop(state);
+            let results: Vec<_> = due.iter().map(|(c, _, msg)| ChannelComm::write_channel_data(*c, std::slice::from_ref(msg), 0).1).collect();
+            state = shared.state.lock().unwrap();
+            for ((channel_id, msg_id, _), res) in due.iter().zip(results) {
+                if let Some(p) = state.msgs.get_mut(&(*channel_id, *msg_id)) {
+                    if let Err(e) = &res {
+                        if !p.failing {
+                            log_warn(format!("Channel {} could not send periodic message {}: {:?}", channel_id, msg_id, e));
+                        }
+                    }
+                    p.failing = res.is_err();
+                }
+            }
+            continue;
+        }
+        state = match state.msgs.values().map(|p| p.next).min() {
+            Some(next) => shared.wake.wait_timeout(state, next.saturating_duration_since(now)).unwrap().0,
+            None => shared.wake.wait(state).unwrap()
+        };
+    }
+    #[cfg(windows)]
+    unsafe { winapi::um::timeapi::timeEndPeriod(1); }
+    log_debug_str("Periodic message thread exiting");
+}
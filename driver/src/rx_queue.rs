@@ -0,0 +1,86 @@
+// Messages a channel has received, waiting for PassThruReadMsgs. The connection's receiver thread
+// adds them, and wakes any reader waiting for them, so a blocking read returns as soon as its
+// messages are in rather than polling the channel.
+//
+// Once the queue is full, further messages are dropped and counted. The next read reports the
+// overflow, as J2534 has PassThruReadMsgs return ERR_BUFFER_OVERFLOW.
+//
+// Loopback messages of sent messages go in the same queue as received ones. Each is timestamped as
+// it is queued, so the timestamps of both go up in the order they are read
+
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use j2534_rust::PASSTHRU_MSG;
+
+#[derive(Debug, Default)]
+struct State {
+    msgs: VecDeque<PASSTHRU_MSG>,
+    /// Messages dropped since the channel was connected
+    dropped: u32,
+    /// Messages dropped since a read last reported the overflow
+    unreported: u32,
+}
+
+#[derive(Debug)]
+pub struct RxQueue {
+    state: Mutex<State>,
+    /// Signalled whenever a message is added
+    arrived: Condvar,
+    capacity: usize,
+}
+
+impl RxQueue {
+    pub fn new(capacity: usize) -> Self {
+        Self { state: Mutex::new(State::default()), arrived: Condvar::new(), capacity }
+    }
+
+    /// Timestamps a message and adds it to the back of the queue, or drops it if the queue is full
+    /// # Returns
+    /// If dropped, how many messages have been since a read last reported the overflow
+    pub fn push(&self, mut msg: PASSTHRU_MSG) -> Result<(), u32> {
+        let mut state = self.state.lock().unwrap();
+        if state.msgs.len() >= self.capacity {
+            state.dropped = state.dropped.saturating_add(1);
+            state.unreported = state.unreported.saturating_add(1);
+            return Err(state.unreported);
+        }
+        msg.timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros() as u32;
+        state.msgs.push_back(msg);
+        self.arrived.notify_all();
+        Ok(())
+    }
+
+    /// Takes up to `max` messages from the front of the queue. With a deadline, waits until
+    /// `max` are in or the deadline passes, otherwise takes what is there straight away
+    /// # Returns
+    /// The messages, and whether any were dropped since the last read
+    pub fn read(&self, max: usize, deadline: Option<Instant>) -> (Vec<PASSTHRU_MSG>, bool) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(deadline) = deadline {
+            while state.msgs.len() < max {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                state = self.arrived.wait_timeout(state, deadline - now).unwrap().0;
+            }
+        }
+        let count = state.msgs.len().min(max);
+        let overflowed = std::mem::take(&mut state.unreported) != 0;
+        (state.msgs.drain(..count).collect(), overflowed)
+    }
+
+    /// Messages dropped since the channel was connected, and since a read last reported the overflow
+    pub fn dropped(&self) -> (u32, u32) {
+        let state = self.state.lock().unwrap();
+        (state.dropped, state.unreported)
+    }
+
+    /// Drops every queued message. The overflow, if any, goes with them, but stays counted
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.msgs.clear();
+        state.unreported = 0;
+    }
+}
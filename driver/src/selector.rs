@@ -0,0 +1,85 @@
+// Name given to PassThruOpen, saying which adapter to open. When given, it takes the place
+// of COM-PORT in the config, so a script can pick an adapter without editing the config.
+//
+// Accepted names:
+// * Nothing (null or empty) - The adapter in the config, as before
+// * A port path, such as COM3 or /dev/ttyACM1
+// * A transport URI, serial://<port>[?device=<M2|A0>]
+// * Anything else is taken as the serial number the adapter reports in its device info
+//   (Unique ID of the M2's MCU, or MAC address of the A0), and every configured adapter is tried
+
+use std::ffi::CStr;
+use libc::c_char;
+use crate::comm::DeviceModel;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Selector {
+    /// Use the config file
+    Default,
+    /// Adapter on a port. The model overrides the DEVICE attribute of the config
+    Port { path: String, model: Option<DeviceModel> },
+    /// Adapter reporting this serial number
+    Serial(String),
+}
+
+impl Selector {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Ok(Selector::Default);
+        }
+        if let Some((scheme, rest)) = name.split_once("://") {
+            return match scheme.to_lowercase().as_str() {
+                "serial" => Selector::parse_serial_uri(rest),
+                _ => Err(format!("Unsupported transport '{}' in device name {}", scheme, name))
+            };
+        }
+        if is_port_path(name) {
+            Ok(Selector::Port { path: name.to_string(), model: None })
+        } else {
+            Ok(Selector::Serial(name.to_string()))
+        }
+    }
+
+    /// Parses the name pointer given to PassThruOpen, which may be null
+    pub fn from_ptr(name: *const c_char) -> Result<Self, String> {
+        if name.is_null() {
+            return Ok(Selector::Default);
+        }
+        Selector::parse(&unsafe { CStr::from_ptr(name) }.to_string_lossy())
+    }
+
+    fn parse_serial_uri(rest: &str) -> Result<Self, String> {
+        let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+        if path.is_empty() {
+            return Err("serial:// device name has no port".into());
+        }
+        let mut model = None;
+        for param in query.split('&').filter(|p| !p.is_empty()) {
+            match param.split_once('=') {
+                Some(("device", m)) => match DeviceModel::from_name(m) {
+                    Some(m) => model = Some(m),
+                    None => return Err(format!("Unknown device '{}' in device name", m))
+                },
+                _ => return Err(format!("Unknown option '{}' in device name", param))
+            }
+        }
+        Ok(Selector::Port { path: path.to_string(), model })
+    }
+
+    /// Serial number the adapter must report, if any
+    pub fn serial(&self) -> Option<&str> {
+        match self {
+            Selector::Serial(s) => Some(s),
+            _ => None
+        }
+    }
+}
+
+/// Returns true for names which are paths to a serial port, rather than a serial number
+fn is_port_path(name: &str) -> bool {
+    let upper = name.to_uppercase();
+    name.starts_with('/')
+        || upper.starts_with("\\\\.\\")
+        || upper.strip_prefix("COM").is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+}
@@ -0,0 +1,292 @@
+use std::io::{Error, ErrorKind, Read, Write};
+use serialport::{ClearBuffer, FlowControl, SerialPort};
+use crate::comm::DeviceModel;
+use crate::config::Settings;
+
+type Result<T> = std::io::Result<T>;
+
+/// Byte level link between the driver and the adapter.
+/// The CommMsg protocol in `comm.rs` runs on top of any implementation of this,
+/// so the driver is not tied to a physical serial port
+pub trait Transport: Read + Write + Send {
+    /// Creates a second handle to the same link, so that the
+    /// reader and writer threads can each own one
+    fn try_clone(&self) -> Result<Box<dyn Transport>>;
+}
+
+/// Opens the link to the adapter on `port`. In the tests, a port named `emulator:<name>`
+/// is the emulator plugged into it
+pub fn open(port: &str, model: DeviceModel, settings: &Settings) -> Result<Box<dyn Transport>> {
+    #[cfg(test)]
+    if port.starts_with(EMULATOR_PREFIX) {
+        return crate::emulator::open_port(port).map(|t| Box::new(t) as Box<dyn Transport>);
+    }
+    SerialTransport::open(port, model, settings).map(|t| Box::new(t) as Box<dyn Transport>)
+}
+
+/// Returns true if there is something on `port` to open
+pub fn port_exists(port: &str) -> bool {
+    #[cfg(test)]
+    if port.starts_with(EMULATOR_PREFIX) {
+        return crate::emulator::port_exists(port);
+    }
+    serial_port_exists(port)
+}
+
+#[cfg(test)]
+const EMULATOR_PREFIX: &str = "emulator:";
+
+#[cfg(unix)]
+fn serial_port_exists(port: &str) -> bool {
+    std::path::Path::new(port).exists()
+}
+
+#[cfg(windows)]
+fn serial_port_exists(port: &str) -> bool {
+    serialport::available_ports().unwrap_or_default().iter().any(|p| p.port_name == port)
+}
+
+/// Transport over the adapters USB serial port
+pub struct SerialTransport {
+    port: Box<dyn SerialPort>
+}
+
+impl SerialTransport {
+    pub fn open(path: &str, model: DeviceModel, settings: &Settings) -> Result<Self> {
+        let mut port = serialport::new(path, settings.baud_rate_for(model)).open().map_err(|e| port_error("Error opening port", e))?;
+        // A port which opens but will not be set up is refused, rather than taking the application down with it
+        port.set_flow_control(FlowControl::Hardware).map_err(|e| port_error("Could not set up hardware flow control", e))?;
+        if model == DeviceModel::A0 {
+            port.set_flow_control(FlowControl::None).map_err(|e| port_error("Could not turn off flow control", e))?;
+        }
+        port.set_timeout(std::time::Duration::from_millis(settings.port_timeout_ms)).map_err(|e| port_error("Could not set serial timeout", e))?;
+        port.clear(ClearBuffer::All).map_err(|e| port_error("Could not clear serial buffers", e))?;
+
+        if cfg!(windows) && model == DeviceModel::A0 {
+            // Ok so windows is strange with A0....even with DTR off it seems to reset ESP32
+            // So wait for 1 second for reset, then clear any extra data in the buffer
+            std::thread::sleep(std::time::Duration::from_millis(2000));
+            port.clear(ClearBuffer::All).map_err(|e| port_error("Could not clear serial buffers", e))?;
+        }
+        Ok(Self { port })
+    }
+}
+
+/// Error for something the serial port refused to do
+fn port_error(what: &str, e: serialport::Error) -> Error {
+    Error::new(ErrorKind::Other, format!("{}: {}", what, e))
+}
+
+impl Read for SerialTransport {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.port.read(buf)
+    }
+}
+
+impl Write for SerialTransport {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.port.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.port.flush()
+    }
+}
+
+impl Transport for SerialTransport {
+    fn try_clone(&self) -> Result<Box<dyn Transport>> {
+        match self.port.try_clone() {
+            Ok(port) => Ok(Box::new(SerialTransport { port })),
+            Err(e) => Err(Error::new(ErrorKind::Other, format!("Error cloning port {}", e)))
+        }
+    }
+}
+
+/// In memory transport, used by the tests to talk to a fake adapter
+#[cfg(test)]
+pub mod memory {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Condvar, Mutex, Weak, atomic::AtomicBool, atomic::Ordering};
+    use std::time::Duration;
+
+    /// How long a read waits for data before timing out, same as the serial port
+    const READ_TIMEOUT: Duration = Duration::from_millis(10);
+
+    #[derive(Default)]
+    struct Pipe {
+        data: Mutex<VecDeque<u8>>,
+        cond: Condvar,
+        /// Bytes per second the pipe can carry, None for no limit
+        rate: Option<u32>,
+        closed: AtomicBool
+    }
+
+    /// One end of an in memory link. Bytes written to one end of the pair
+    /// are read from the other
+    #[derive(Clone)]
+    pub struct MemoryTransport {
+        rx: Arc<Pipe>,
+        tx: Arc<Pipe>,
+        /// Shared by every clone of this end, so the other end can tell if it is still open
+        #[allow(dead_code)]
+        end: Arc<()>,
+        peer: Weak<()>
+    }
+
+    /// Creates a connected pair of transports
+    pub fn pair() -> (MemoryTransport, MemoryTransport) {
+        pair_with_rate(None)
+    }
+
+    /// Creates a connected pair of transports, where writes take as long as they would on a link of `rate` bytes per second
+    pub fn pair_with_rate(rate: Option<u32>) -> (MemoryTransport, MemoryTransport) {
+        let a = Arc::new(Pipe { rate, ..Default::default() });
+        let b = Arc::new(Pipe { rate, ..Default::default() });
+        let (end_a, end_b) = (Arc::new(()), Arc::new(()));
+        let (peer_a, peer_b) = (Arc::downgrade(&end_b), Arc::downgrade(&end_a));
+        (
+            MemoryTransport { rx: a.clone(), tx: b.clone(), end: end_a, peer: peer_a },
+            MemoryTransport { rx: b, tx: a, end: end_b, peer: peer_b }
+        )
+    }
+
+    impl MemoryTransport {
+        /// Breaks the link in both directions, like pulling out the cable.
+        /// Anything already sent can still be read, after that both ends get errors
+        pub fn close(&self) {
+            for pipe in [&self.rx, &self.tx].iter() {
+                pipe.closed.store(true, Ordering::Relaxed);
+                pipe.cond.notify_all();
+            }
+        }
+
+        /// Returns true whilst any clone of the other end has not been dropped
+        pub fn peer_is_open(&self) -> bool {
+            self.peer.strong_count() > 0
+        }
+    }
+
+    impl Read for MemoryTransport {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let guard = self.rx.data.lock().unwrap();
+            let (mut data, _) = self.rx.cond.wait_timeout_while(guard, READ_TIMEOUT, |d| d.is_empty() && !self.rx.closed.load(Ordering::Relaxed)).unwrap();
+            if data.is_empty() {
+                if self.rx.closed.load(Ordering::Relaxed) {
+                    return Err(Error::new(ErrorKind::BrokenPipe, "Link closed"));
+                }
+                return Err(Error::new(ErrorKind::TimedOut, "Operation timed out"));
+            }
+            let count = std::cmp::min(buf.len(), data.len());
+            for (dst, src) in buf.iter_mut().zip(data.drain(0..count)) {
+                *dst = src;
+            }
+            Ok(count)
+        }
+    }
+
+    impl Write for MemoryTransport {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            if self.tx.closed.load(Ordering::Relaxed) {
+                return Err(Error::new(ErrorKind::BrokenPipe, "Link closed"));
+            }
+            if let Some(rate) = self.tx.rate {
+                std::thread::sleep(Duration::from_secs_f64(buf.len() as f64 / rate as f64));
+            }
+            self.tx.data.lock().unwrap().extend(buf.iter());
+            self.tx.cond.notify_all();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for MemoryTransport {
+        fn try_clone(&self) -> Result<Box<dyn Transport>> {
+            Ok(Box::new(self.clone()))
+        }
+    }
+}
+
+/// Pseudo terminal pair. The tests put the firmware emulator on the master side,
+/// and the driver opens the slave as if it was the adapters serial port
+#[cfg(all(test, target_os = "linux"))]
+pub mod pty {
+    use super::*;
+    use std::fs::File;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::sync::Arc;
+
+    /// How long a read waits for data before timing out, same as the serial port
+    const READ_TIMEOUT_MS: libc::c_int = 10;
+
+    /// Master side of the pseudo terminal
+    #[derive(Clone)]
+    pub struct PtyMaster {
+        file: Arc<File>
+    }
+
+    /// Creates a new pseudo terminal, returning its master side and the path of the slave
+    pub fn open() -> Result<(PtyMaster, String)> {
+        unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            if fd < 0 {
+                return Err(Error::last_os_error());
+            }
+            // Owned from here on, so the fd is closed if anything fails
+            let file = File::from_raw_fd(fd);
+            if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                return Err(Error::last_os_error());
+            }
+            // No echo or line editing, the link is purely binary
+            let mut tio: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(fd, &mut tio) != 0 {
+                return Err(Error::last_os_error());
+            }
+            libc::cfmakeraw(&mut tio);
+            if libc::tcsetattr(fd, libc::TCSANOW, &tio) != 0 {
+                return Err(Error::last_os_error());
+            }
+            let mut name = [0 as libc::c_char; 128];
+            if libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) != 0 {
+                return Err(Error::last_os_error());
+            }
+            let path = std::ffi::CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned();
+            Ok((PtyMaster { file: Arc::new(file) }, path))
+        }
+    }
+
+    impl Read for PtyMaster {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let mut pfd = libc::pollfd { fd: self.file.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+            let ready = unsafe { libc::poll(&mut pfd, 1, READ_TIMEOUT_MS) };
+            if ready < 0 {
+                return Err(Error::last_os_error());
+            }
+            if pfd.revents & libc::POLLIN == 0 {
+                // Nothing to read, or the slave side is not open yet
+                std::thread::sleep(std::time::Duration::from_millis(1));
+                return Err(Error::new(ErrorKind::TimedOut, "Operation timed out"));
+            }
+            (&*self.file).read(buf)
+        }
+    }
+
+    impl Write for PtyMaster {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            (&*self.file).write(buf)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            (&*self.file).flush()
+        }
+    }
+
+    impl Transport for PtyMaster {
+        fn try_clone(&self) -> Result<Box<dyn Transport>> {
+            Ok(Box::new(self.clone()))
+        }
+    }
+}
@@ -0,0 +1,184 @@
+// Messages waiting to be sent on a channel. PassThruWriteMsgs adds them, and a thread for
+// each channel takes them off in order, sending each to the adapter and waiting for it
+// to be confirmed before sending the next.
+//
+// A write with a timeout is tracked from when it queues its first message, so it can wait for
+// just its own messages, and say how many of them went out if they do not all go. Its messages
+// can fail, or be dropped by a clear, whilst an earlier one is still being sent, so what counts
+// is the first of them that was not sent, once everything before it has been
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Condvar, Mutex};
+use std::time::Instant;
+use j2534_rust::{PassthruError, PASSTHRU_MSG};
+
+/// Which message of which tracked write a queued message is, handed back to `complete`
+#[derive(Debug, Copy, Clone)]
+pub struct Tag(Option<(u64, u32)>);
+
+/// How far the messages of a tracked write have got
+#[derive(Debug, Default, Copy, Clone)]
+struct Progress {
+    /// Messages the write has queued
+    queued: u32,
+    /// Messages sent, up to the first which was not
+    sent: u32,
+    /// First message which was not sent, by its position in the write, and why
+    failed: Option<(u32, PassthruError)>,
+}
+
+impl Progress {
+    /// Records what happened to the message at `index` of the write
+    fn record(&mut self, index: u32, res: Result<(), PassthruError>) {
+        if self.failed.is_some_and(|(first, _)| first < index) {
+            return;
+        }
+        match res {
+            Ok(()) => self.sent += 1,
+            Err(e) => self.failed = Some((index, e))
+        }
+    }
+
+    /// How many messages were sent, and the error which stopped the rest, once that is known
+    fn outcome(&self) -> Option<(u32, Result<(), PassthruError>)> {
+        match self.failed {
+            Some((first, e)) if self.sent == first => Some((first, Err(e))),
+            None if self.sent == self.queued => Some((self.sent, Ok(()))),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    msgs: VecDeque<(Tag, PASSTHRU_MSG)>,
+    /// Writes waiting for their messages to be sent, by ID
+    writes: HashMap<u64, Progress>,
+    /// ID the next tracked write gets
+    next_write: u64,
+    stopping: bool,
+}
+
+impl State {
+    fn record(&mut self, tag: Tag, res: Result<(), PassthruError>) -> bool {
+        match tag.0.and_then(|(write, index)| self.writes.get_mut(&write).map(|p| (p, index))) {
+            Some((progress, index)) => {
+                progress.record(index, res);
+                true
+            },
+            None => false
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TxQueue {
+    state: Mutex<State>,
+    /// Signalled whenever a message is queued or leaves the queue, or the queue is stopped
+    changed: Condvar,
+    capacity: usize,
+}
+
+impl TxQueue {
+    pub fn new(capacity: usize) -> Self {
+        Self { state: Mutex::new(State::default()), changed: Condvar::new(), capacity }
+    }
+
+    /// Starts tracking a write, which then waits for its messages with `wait_sent`
+    /// # Returns
+    /// ID of the write, to queue its messages with
+    pub fn track_write(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let write = state.next_write;
+        state.next_write += 1;
+        state.writes.insert(write, Progress::default());
+        write
+    }
+
+    /// Adds a message to the back of the queue, as part of `write` if it is tracked. If the queue is full,
+    /// waits until `deadline` for space, or fails with ERR_BUFFER_FULL straight away if there is none
+    pub fn push(&self, msg: &PASSTHRU_MSG, write: Option<u64>, deadline: Option<Instant>) -> Result<(), PassthruError> {
+        let mut state = self.state.lock().unwrap();
+        while state.msgs.len() >= self.capacity {
+            let deadline = deadline.ok_or(PassthruError::ERR_BUFFER_FULL)?;
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(PassthruError::ERR_TIMEOUT);
+            }
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        let tag = match write.and_then(|w| state.writes.get_mut(&w).map(|p| (w, p))) {
+            Some((w, progress)) => {
+                progress.queued += 1;
+                Tag(Some((w, progress.queued - 1)))
+            },
+            None => Tag(None)
+        };
+        state.msgs.push_back((tag, *msg));
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    /// Waits until `deadline` for the messages queued as part of `write` to be sent, then stops tracking it
+    /// # Returns
+    /// How many of them were sent, and the error which stopped the rest, if any
+    pub fn wait_sent(&self, write: u64, deadline: Instant) -> (u32, Result<(), PassthruError>) {
+        let mut state = self.state.lock().unwrap();
+        let res = loop {
+            let progress = state.writes[&write];
+            if let Some(res) = progress.outcome() {
+                break res;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                break (progress.sent, Err(PassthruError::ERR_TIMEOUT));
+            }
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        };
+        state.writes.remove(&write);
+        res
+    }
+
+    /// Takes the message at the front of the queue to send, waiting for one to be queued.
+    /// Returns None once the queue is stopped
+    pub fn take(&self) -> Option<(Tag, PASSTHRU_MSG)> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.stopping {
+                return None;
+            }
+            if let Some(msg) = state.msgs.pop_front() {
+                self.changed.notify_all();
+                return Some(msg);
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Marks a message given out by `take` as finished with
+    pub fn complete(&self, tag: Tag, res: Result<(), PassthruError>) {
+        self.state.lock().unwrap().record(tag, res);
+        self.changed.notify_all();
+    }
+
+    /// Drops every message not yet given to the adapter. Writes waiting for any of them fail with `reason`
+    /// # Returns
+    /// True if a write waiting for its messages lost any
+    pub fn clear(&self, reason: PassthruError) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let mut lost = false;
+        for (tag, _) in std::mem::take(&mut state.msgs) {
+            lost |= state.record(tag, Err(reason));
+        }
+        self.changed.notify_all();
+        lost
+    }
+
+    /// Drops every queued message, failing writes waiting for them with ERR_INVALID_CHANNEL_ID,
+    /// and makes `take` return None
+    pub fn stop(&self) {
+        self.clear(PassthruError::ERR_INVALID_CHANNEL_ID);
+        self.state.lock().unwrap().stopping = true;
+        self.changed.notify_all();
+    }
+}
@@ -9,4 +9,7 @@
 #define CFG_MACCHINA_A0
 //#define CFG_MACCHINA_ESP32_TEST
 
+// Revision printed on the board, reported to the driver in the device info
+#define BOARD_REVISION "1"
+
 #endif
\ No newline at end of file
@@ -1,6 +1,7 @@
 #include "channel.h"
 
 Channel* canChannel = nullptr; // Channel for physical canbus link
+Channel* can1Channel = nullptr; // Channel for the second canbus link
 Channel* klineChannel = nullptr; // Channel for physical kline line
 
 int little_endian_decode(uint8_t* src) {
@@ -33,6 +34,15 @@ void setup_channel(COMM_MSG* msg) {
                 create_can_channel(id, protocol, baud, flags);
             }
             break;
+#if CAN_BUS_COUNT > 1
+        case CAN1_CHANNEL_ID:
+            if (can1Channel != nullptr) {
+                PCCOMM::respond_err(MSG_OPEN_CHANNEL, ERR_CHANNEL_IN_USE, nullptr);
+            } else {
+                create_can_channel(id, protocol, baud, flags);
+            }
+            break;
+#endif
 #ifdef CFG_MACCHINA_M2
         case KLINE_CHANNEL_ID:
             if (klineChannel != nullptr) {
@@ -50,16 +60,22 @@ void setup_channel(COMM_MSG* msg) {
 
 void create_can_channel(int id, int protocol, int baud, int flags) {
     Channel *c = nullptr;
+    int bus = id == CAN1_CHANNEL_ID ? 1 : 0;
     if (protocol == ISO15765) { // ISO-TP
-        c = new ISO15765Channel();
+        c = new ISO15765Channel(bus);
     } else { // Standard CAN
-        c = new CanChannel();
+        c = new CanChannel(bus);
     }
     if (!c->setup(id, protocol, baud, flags)) { // This function will return log the error to driver if any error
         delete c;
         return;
     }
-    canChannel = c; // Creation ok!
+    // Creation ok!
+    if (bus == 1) {
+        can1Channel = c;
+    } else {
+        canChannel = c;
+    }
     PCCOMM::respond_ok(MSG_OPEN_CHANNEL, nullptr, 0); // Tell driver CAN based channel is ready!
 }
 
@@ -101,6 +117,10 @@ void remove_channel(COMM_MSG *msg) {
             delete_channel(klineChannel);
             PCCOMM::respond_ok(MSG_CLOSE_CHANNEL, nullptr, 0);
             break;
+        case CAN1_CHANNEL_ID:
+            delete_channel(can1Channel);
+            PCCOMM::respond_ok(MSG_CLOSE_CHANNEL, nullptr, 0);
+            break;
         default:
             PCCOMM::respond_err(MSG_CLOSE_CHANNEL, ERR_FAILED, "Protocol unsupported");
             break;
@@ -125,6 +145,9 @@ void channel_loop() {
     if (klineChannel != nullptr) {
         klineChannel->update();
     }
+    if (can1Channel != nullptr) {
+        can1Channel->update();
+    }
 }
 
 void reset_all_channels() {
@@ -138,6 +161,11 @@ void reset_all_channels() {
         delete klineChannel;
         klineChannel = nullptr;
     }
+    if (can1Channel != nullptr) {
+        can1Channel->destroy();
+        delete can1Channel;
+        can1Channel = nullptr;
+    }
 }
 
 void del_channel_filter(COMM_MSG* msg) {
@@ -164,6 +192,14 @@ void del_channel_filter(COMM_MSG* msg) {
             PCCOMM::respond_err(MSG_REM_CHAN_FILT, ERR_INVALID_CHANNEL_ID, "Kline based channel null");
             return;
         }
+    } else if (channel_id == CAN1_CHANNEL_ID) {
+        if (can1Channel != nullptr) {
+            can1Channel->removeFilter(filter_id);
+            return;
+        } else {
+            PCCOMM::respond_err(MSG_REM_CHAN_FILT, ERR_INVALID_CHANNEL_ID, "Can1 based channel null");
+            return;
+        }
     }
 
 
@@ -181,7 +217,7 @@ void add_channel_filter(COMM_MSG* msg) {
         return;
     }
     // Check if the channel is valid?
-    if (channel_id != CAN_CHANNEL_ID && channel_id != KLINE_CHANNEL_ID) {
+    if (channel_id != CAN_CHANNEL_ID && channel_id != KLINE_CHANNEL_ID && channel_id != CAN1_CHANNEL_ID) {
         PCCOMM::respond_err(MSG_SET_CHAN_FILT, ERR_INVALID_CHANNEL_ID, "Channel ID does not exist");
         return;
     }
@@ -215,6 +251,12 @@ void add_channel_filter(COMM_MSG* msg) {
         } else {
              PCCOMM::respond_err(MSG_SET_CHAN_FILT, ERR_INVALID_CHANNEL_ID, nullptr);
         }
+    } else if (channel_id == CAN1_CHANNEL_ID) {
+        if (can1Channel != nullptr) {
+            can1Channel->addFilter(filter_type, filter_id, mask, pattern, flowcontrol, mask_size, pattern_size, flowcontrol_size);
+        } else {
+            PCCOMM::respond_err(MSG_SET_CHAN_FILT, ERR_INVALID_CHANNEL_ID, nullptr);
+        }
     }
     // Done with these arrays, hardware has applied them, destroy
     delete[] mask;
@@ -255,6 +297,16 @@ void send_data(COMM_MSG *msg) {
                 PCCOMM::log_message("Cannot send, Channel null!");
             }
         }
+    } else if (channel_id == CAN1_CHANNEL_ID) {
+        if (can1Channel != nullptr) {
+            can1Channel->sendMsg(tx_flags, buf, data_size, require_response);
+        } else {
+            if (require_response) {
+                PCCOMM::respond_err(MSG_TX_CHAN_DATA, ERR_INVALID_CHANNEL_ID, nullptr);
+            } else {
+                PCCOMM::log_message("Cannot send, Channel null!");
+            }
+        }
     } else {
         if (require_response) {
              PCCOMM::respond_err(MSG_TX_CHAN_DATA, ERR_FAILED, "Tx data not implemented for this protocol");
@@ -291,6 +343,13 @@ void ioctl_get(COMM_MSG *msg) {
             PCCOMM::respond_err(MSG_IOCTL_GET, ERR_FAILED, "Can channel is null!");
         }
         break;
+    case CAN1_CHANNEL_ID:
+        if (can1Channel != nullptr) {
+            can1Channel->ioctl_get(ioctl_id);
+        } else {
+            PCCOMM::respond_err(MSG_IOCTL_GET, ERR_FAILED, "Can1 channel is null!");
+        }
+        break;
     
     default:
         PCCOMM::respond_err(MSG_IOCTL_GET, ERR_INVALID_CHANNEL_ID, nullptr);
@@ -325,6 +384,13 @@ void ioctl_set(COMM_MSG *msg) {
             PCCOMM::respond_err(MSG_IOCTL_SET, ERR_FAILED, "Can channel is null!");
         }
         break;
+    case CAN1_CHANNEL_ID:
+        if (can1Channel != nullptr) {
+            can1Channel->ioctl_set(ioctl_id, value);
+        } else {
+            PCCOMM::respond_err(MSG_IOCTL_SET, ERR_FAILED, "Can1 channel is null!");
+        }
+        break;
     default:
         PCCOMM::respond_err(MSG_IOCTL_SET, ERR_INVALID_CHANNEL_ID, nullptr);
         break;
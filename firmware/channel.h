@@ -10,6 +10,7 @@
 #define KLINE_CHANNEL_ID 1
 #define J1850_CHANNEL_ID 2
 #define SCI_CHANNEL_ID 3
+#define CAN1_CHANNEL_ID 4 // CAN or ISO15765 on the second CAN interface (M2 only)
 
 void setup_channel(COMM_MSG* msg);
 void remove_channel(COMM_MSG *msg);
@@ -19,8 +19,18 @@
 #define MSG_IOCTL_SET 0x09
 #define MSG_IOCTL_GET 0x10
 #define MSG_INIT_LIN_CHANNEL 0x11
-#define MSG_STATUS 0xAA // Args: [0] -> 0x00 = Goodbye, 0x01 = Hellow
+#define MSG_STATUS 0xAA // Args: [0] -> 0x00 = Goodbye, 0x01 = Hellow, 0x02 = Keepalive. [1] -> Hello flags
+#define STATUS_GOODBYE 0x00 // Driver disconnecting
+#define STATUS_HELLO 0x01 // Driver connecting, followed by its hello flags
+#define STATUS_KEEPALIVE 0x02 // Driver checking the firmware is still alive, answered without resetting anything
+#define HELLO_FLAG_UNPADDED 0x01 // Driver can read frames that are not padded to COMM_MSG_SIZE
 #define MSG_GET_FW_VERSION 0xAB
+#define MSG_GET_CAPABILITIES 0xAC // Response args: [Major, Minor, Max payload (2), Protocols bitmask (4), Max filters, CAN buses]
+#define MSG_GET_DEVICE_INFO 0xAD // Response args: [Uptime ms (4), Model, Board revision, Serial], strings are null terminated
+
+// CommMsg protocol version. The driver refuses firmware with a different major version
+#define COMM_PROTOCOL_MAJOR 1
+#define COMM_PROTOCOL_MINOR 1
 #define MSG_TEST 0xFF
 
 // Reserve 4Kb of memory for a temp buffer for reading and writing comm messages. Basically, a larger serial buffer
@@ -34,6 +44,9 @@ struct __attribute__ ((packed)) COMM_MSG {
     uint8_t args[COMM_MSG_ARG_SIZE];
 };
 
+// Sync marker, size and CRC around each message on the link
+#define FRAME_OVERHEAD 8
+
 namespace PCCOMM {
     
     void init();
@@ -48,11 +61,31 @@ namespace PCCOMM {
 
     void respond_err(uint8_t op, uint8_t error_id, char* txt);
 
+    // Responds to an earlier request, from get_last_id() when it was received, rather than the last one
+    void respond_ok_custom_id(uint8_t op, uint8_t custom_id, uint8_t* args, uint16_t arg_size);
+
+    void respond_err_custom_id(uint8_t op, uint8_t custom_id, uint8_t error_id, char* txt);
+
     void send_rx_data(uint8_t channel_id, uint32_t rx_status, char* data, uint16_t data_len);
 
     void reset();
 
+    // Called with the hello flags when the driver connects
+    void set_hello_flags(uint8_t flags);
+
     uint8_t get_last_id();
+
+    // Framing, shared by all adapters (comm_frame.cpp)
+
+    uint16_t frame_crc(const uint8_t* data, uint16_t len, uint16_t crc);
+
+    // Writes the frame for msg to out, which must fit msg->arg_size + FRAME_OVERHEAD bytes. Returns the frame size
+    uint16_t encode_frame(COMM_MSG *msg, uint8_t* out);
+
+    // Feeds one byte from the link to the decoder. Returns true once msg holds a complete frame with a valid CRC
+    bool decode_frame_byte(uint8_t b, COMM_MSG *msg);
+
+    void reset_frame_decoder();
 }
 
 #endif
\ No newline at end of file
@@ -12,10 +12,11 @@ bool send_change = false;
 COMM_MSG rx_tmp;
 bool receive_change = false;
 
-uint8_t tmp_queue[COMM_MSG_SIZE];
-uint16_t read_pos = 0;
-uint16_t target_read = 0;
-bool is_reading = false;
+uint8_t rx_chunk[256];
+uint16_t rx_chunk_len = 0;
+uint16_t rx_chunk_pos = 0;
+
+uint8_t tx_frame[COMM_MSG_SIZE + FRAME_OVERHEAD];
 
 
 namespace PCCOMM {
@@ -23,35 +24,23 @@ namespace PCCOMM {
     COMM_MSG res = {0x00};
 
     bool read_message(COMM_MSG *msg) {
-        size_t avaliable;
-        uart_get_buffered_data_len(UART_NUM_0, &avaliable);
-        if (avaliable > 2 && !is_reading) {
-            // Payload begin!
-            uint8_t buf[2];
-            if (uart_read_bytes(UART_NUM_0, buf, 2, 0) == 2) {
-                target_read = buf[1] << 8 | buf[0];
-                PT_DEVICE->set_rx_led(true);
-                is_reading = true;
-                read_pos = 0;
-            }
-        } else if (avaliable > 0 && is_reading) {
-            int max_to_read = std::min((size_t)(target_read - read_pos), avaliable);
-            int actual_read = uart_read_bytes(UART_NUM_0, &tmp_queue[read_pos], max_to_read, 0);
-            read_pos += actual_read;
-            if (read_pos >= target_read) {
-                // Payload complete!
-                is_reading = false;
-                msg->arg_size = target_read - 2;
-                msg->msg_id = tmp_queue[0];
-                msg->msg_type = tmp_queue[1];
+        if (rx_chunk_pos == rx_chunk_len) {
+            // Refill from the UART, anything not decoded this call is kept for the next one
+            int read = uart_read_bytes(UART_NUM_0, rx_chunk, sizeof(rx_chunk), 0);
+            rx_chunk_len = read > 0 ? read : 0;
+            rx_chunk_pos = 0;
+        }
+        while (rx_chunk_pos < rx_chunk_len) {
+            PT_DEVICE->set_rx_led(true);
+            if (decode_frame_byte(rx_chunk[rx_chunk_pos++], msg)) {
                 if (msg->msg_id != 0) {
                     last_id = msg->msg_id;
                 }
-                memcpy(&msg->args[0], &tmp_queue[2], target_read-2);
                 PT_DEVICE->set_rx_led(false);
                 return true;
             }
         }
+        PT_DEVICE->set_rx_led(false);
         return false;
     }
 
@@ -69,14 +58,8 @@ namespace PCCOMM {
 
     void send_message(COMM_MSG *msg) {
         PT_DEVICE->set_tx_led(true);
-        char* tmp = new char[msg->arg_size + 4];
-        tmp[0] = (msg->arg_size + 2);
-        tmp[1] = (msg->arg_size + 2) >> 8;
-        tmp[2] = msg->msg_id;
-        tmp[3] = msg->msg_type;
-        memcpy(&tmp[4], &msg->args[0], msg->arg_size);
-        uart_write_bytes(UART_NUM_0, (const char*)tmp, msg->arg_size+4); // buffer
-        delete[] tmp;
+        uint16_t len = encode_frame(msg, tx_frame);
+        uart_write_bytes(UART_NUM_0, (const char*)tx_frame, len);
         PT_DEVICE->set_tx_led(false);
     }
 
@@ -121,15 +104,44 @@ namespace PCCOMM {
         send_message(&res);
     }
 
+    void respond_ok_custom_id(uint8_t op, uint8_t custom_id, uint8_t* args, uint16_t arg_size) {
+        memset(&res, 0x00, sizeof(COMM_MSG));
+        res.msg_type = op;
+        res.arg_size = 1 + min((int)arg_size, COMM_MSG_ARG_SIZE);
+        res.msg_id = custom_id;
+        res.args[0] = 0x00; // STATUS_NOERROR
+        if (arg_size != 0) {
+            memcpy(&res.args[1], args, res.arg_size-1);
+        }
+        send_message(&res);
+    }
+
+    void respond_err_custom_id(uint8_t op, uint8_t custom_id, uint8_t error_id, char* txt) {
+        memset(&res, 0x00, sizeof(COMM_MSG));
+        res.msg_type = op;
+        res.arg_size = 1 + min((int)strlen(txt), COMM_MSG_ARG_SIZE);
+        res.args[0] = error_id;
+        res.msg_id = custom_id;
+        memcpy(&res.args[1], txt, res.arg_size-1);
+        send_message(&res);
+    }
+
     uint8_t get_last_id() {
         return last_id;
     }
 
+    /**
+     * Called with the hello flags when the driver connects
+     */
+    void set_hello_flags(uint8_t flags) {
+        // A0 never pads its frames
+    }
+
     /**
      * Called on M2 disconnect
      */
     void reset() {
-        
+        reset_frame_decoder();
     }
 }
 
@@ -3,7 +3,7 @@
 
 bool CanChannel::setup(int id, int protocol, int baud, int flags) {
     // Here we go, setup a CAN channel!
-    if (!CustomCan::enableCanBus(baud)) {
+    if (!CustomCan::enableCanBus(this->can_bus, baud)) {
          PCCOMM::respond_err(MSG_OPEN_CHANNEL, ERR_FAILED, "CAN Controller setup failed!");
          return false;
     }
@@ -57,10 +57,10 @@ void CanChannel::addFilter(int type, int filter_id, char* mask, char* pattern, c
     }
 
     if (type == BLOCK_FILTER) { // Block filter. Set the CAN Filter ID to be open, and then we will block it in software
-        CustomCan::enableCanFilter(filter_id, 0x0000, 0x0000, isExtended); // Open the mailbox filter to everything
+        CustomCan::enableCanFilter(this->can_bus, filter_id, 0x0000, 0x0000, isExtended); // Open the mailbox filter to everything
         blocking_filters[filter_id] = true; // Mark this as yes for the update function
     } else { // Pass filter, use hardware filter
-        CustomCan::enableCanFilter(filter_id, ptn_id, mask_id, isExtended); // Open the mailbox filter to everything
+        CustomCan::enableCanFilter(this->can_bus, filter_id, ptn_id, mask_id, isExtended); // Open the mailbox filter to everything
         blocking_filters[filter_id] = false;
 
     }
@@ -73,7 +73,7 @@ void CanChannel::addFilter(int type, int filter_id, char* mask, char* pattern, c
 void CanChannel::update() {
     for (int i = 0; i < MAILBOX_COUNT; i++) { // Check all our filters in use
         if (used_mailboxes[i] == true) { // We should this filter
-            if (CustomCan::receiveFrame(i, &f)) {
+            if (CustomCan::receiveFrame(this->can_bus, i, &f)) {
                 bool send_frame = true;
                 if (blocking_filters[i] == true) { // Check block filter
                     send_frame = masks[i] & f.id != patterns[i]; // Block filter check
@@ -100,7 +100,7 @@ void CanChannel::removeFilter(int id) {
         this->masks[id] = 0;
         this->patterns[id] = 0;
         this->blocking_filters[id] = false;
-        CustomCan::disableCanFilter(id);
+        CustomCan::disableCanFilter(this->can_bus, id);
         PCCOMM::respond_ok(MSG_REM_CHAN_FILT, nullptr, 0);
     } else {
         PCCOMM::respond_err(MSG_REM_CHAN_FILT, ERR_INVALID_FILTER_ID, nullptr);
@@ -108,7 +108,7 @@ void CanChannel::removeFilter(int id) {
 }
 
 void CanChannel::destroy() {
-    CustomCan::disableCanBus();
+    CustomCan::disableCanBus(this->can_bus);
     PT_DEVICE->set_can_led(false);
 }
 
@@ -121,7 +121,7 @@ void CanChannel::sendMsg(uint32_t tx_flags, char* data, int data_size, bool resp
     f.length = data_size - 4;
     f.id = data[0] << 24 | data[1] << 16 | data[2] << 8 | data[3] << 0;
     memcpy(&f.data.bytes[0], &data[4], data_size-4);
-    CustomCan::sendFrame(&f);
+    CustomCan::sendFrame(this->can_bus, &f);
     if (respond) {
         PCCOMM::respond_ok(MSG_TX_CHAN_DATA, nullptr, 0);
     }
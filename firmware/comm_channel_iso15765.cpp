@@ -3,7 +3,7 @@
 
 bool ISO15765Channel::setup(int id, int protocol, int baud, int flags) {
     // Here we go, setup a ISO15765 channel!
-    if (!CustomCan::enableCanBus(baud)) {
+    if (!CustomCan::enableCanBus(this->can_bus, baud)) {
          PCCOMM::respond_err(MSG_OPEN_CHANNEL, ERR_FAILED, "CAN Controller setup failed!");
          return false;
     }
@@ -64,7 +64,7 @@ void ISO15765Channel::addFilter(int type, int filter_id, char* mask, char* patte
     this->mask_ids[filter_id] = mask_u32;
     this->pattern_ids[filter_id] = pattern_u32;
     this->flowcontrol_ids[filter_id] = flowcontrol_u32;
-    CustomCan::enableCanFilter(filter_id, pattern_u32, mask_u32, use29bitCid);
+    CustomCan::enableCanFilter(this->can_bus, filter_id, pattern_u32, mask_u32, use29bitCid);
     PCCOMM::respond_ok(MSG_SET_CHAN_FILT, nullptr, 0);
 }
 
@@ -72,7 +72,7 @@ void ISO15765Channel::removeFilter(int id) {
     if (this->used_mailboxes[id] == true) {
         this->used_mailboxes[id] = false;
         this->flowcontrol_ids[id] = 0x00;
-        CustomCan::disableCanFilter(id);
+        CustomCan::disableCanFilter(this->can_bus, id);
         if (this->isReceiving) {
             delete [] this->rxPayload.payload;
         }
@@ -85,14 +85,14 @@ void ISO15765Channel::removeFilter(int id) {
 }
 
 void ISO15765Channel::destroy() {
-    CustomCan::disableCanBus();
+    CustomCan::disableCanBus(this->can_bus);
     PT_DEVICE->set_can_led(false);
 }
 
 void ISO15765Channel::update() {
     for (int i = 0; i < MAILBOX_COUNT; i++) {
         if (used_mailboxes[i] == true) {
-            if (CustomCan::receiveFrame(i, &f)) {
+            if (CustomCan::receiveFrame(this->can_bus, i, &f)) {
                 debug_read_frame(f);
                 // which byte do we listen to based on addressing method
                 uint8_t cmp = 0;
@@ -156,7 +156,7 @@ void ISO15765Channel::tx_multi_frame() {
     f.data.bytes[0] = tx_pci;
     memcpy(&f.data.bytes[1], &txPayload.payload[txPayload.payloadPos], max_cpy);
     txPayload.payloadPos += max_cpy;
-    debug_send_frame(f);
+    debug_send_frame(this->can_bus, f);
     tx_pci++;
     this->tx_frames_sent++;
     // Rollover
@@ -166,6 +166,10 @@ void ISO15765Channel::tx_multi_frame() {
     if (txPayload.payloadPos >= txPayload.payloadSize) {
         this->clear_to_send = false;
         this->isSending = false;
+        // The driver waits for the whole message to go before confirming it to the application
+        if (this->respond_after_send) {
+            PCCOMM::respond_ok_custom_id(MSG_TX_CHAN_DATA, this->tx_id, nullptr, 0);
+        }
         // Send our TxConfirm
         PCCOMM::send_rx_data(this->channel_id, TX_MSG_TYPE, (char*)&txPayload, 4); // Only first 4 bytes are copied
         return;
@@ -228,7 +232,7 @@ void ISO15765Channel::rx_multi_frame(CAN_FRAME *read, int id) {
         f.data.bytes[0] = 0x30;
         f.data.bytes[1] = this->block_size_tx; // BLOCK SIZE
         f.data.bytes[2] = this->sep_time_tx; // ST_MIN
-        if (!debug_send_frame(f)) {
+        if (!debug_send_frame(this->can_bus, f)) {
             PCCOMM::log_message("CAN TX FAILED!");
         }
         // ECU should now continue sending data...
@@ -270,7 +274,7 @@ void ISO15765Channel::send_ff_indication(CAN_FRAME *read, int id) {
     f.data.bytes[0] = 0x30; // Flow control (Clear to send!)
     f.data.bytes[1] = this->block_size; // BLOCK SIZE
     f.data.bytes[2] = this->sep_time; // ST_MIN
-    debug_send_frame(f);
+    debug_send_frame(this->can_bus, f);
     // Send the first frame indication back to the user application
     // 4 additional bytes should be sent which represents the Can ID of the message
     char* buf2 = new char[4];
@@ -297,7 +301,7 @@ void ISO15765Channel::sendMsg(uint32_t tx_flags, char* data, int data_size, bool
         f.rtr = false;
         f.data.bytes[0] = data_size - 4; // First byte is the length of the ISO message
         memcpy(&f.data.bytes[1], &data[4], data_size-4); // Copy data to bytes [1] and beyond
-        if (!debug_send_frame(f)) {
+        if (!debug_send_frame(this->can_bus, f)) {
             if (respond) {
                 PCCOMM::respond_err(MSG_TX_CHAN_DATA, ERR_FAILED, "CAN Tx failed");
             } else {
@@ -310,8 +314,6 @@ void ISO15765Channel::sendMsg(uint32_t tx_flags, char* data, int data_size, bool
         }
         PCCOMM::send_rx_data(this->channel_id, TX_MSG_TYPE, data, 4); // Only first 4 bytes are copied
     } else {
-        this->tx_id = PCCOMM::get_last_id();
-        this->respond_after_send = respond;
         if (this->isSending) {
             if (respond) {
                 PCCOMM::respond_err(MSG_TX_CHAN_DATA, ERR_BUFFER_FULL, nullptr);
@@ -322,6 +324,9 @@ void ISO15765Channel::sendMsg(uint32_t tx_flags, char* data, int data_size, bool
             }
             return;
         }
+        // Only once accepted, so a rejected send does not take over the response to the one in flight
+        this->tx_id = PCCOMM::get_last_id();
+        this->respond_after_send = respond;
         char buf[40];
         sprintf(buf, "Sending %d bytes", data_size);
         PCCOMM::log_message(buf);
@@ -342,7 +347,7 @@ void ISO15765Channel::sendMsg(uint32_t tx_flags, char* data, int data_size, bool
         this->clear_to_send = false;
         this->isSending = true;
         this->tx_pci = 0x21;
-        if (!debug_send_frame_force(f)) {
+        if (!debug_send_frame_force(this->can_bus, f)) {
             PCCOMM::log_message("CAN TX FAILED!");
         }
     }
@@ -2,7 +2,7 @@
 
 
 // Debug function
-bool debug_send_frame(CAN_FRAME &f) {
+bool debug_send_frame(int bus, CAN_FRAME &f) {
     #ifdef FW_TEST
     char buf[80] = {0x00};
     char *pos = buf;
@@ -13,10 +13,10 @@ bool debug_send_frame(CAN_FRAME &f) {
     sprintf(pos-1,"]");
     PCCOMM::log_message(buf);
     #endif
-    return CustomCan::sendFrame(&f);
+    return CustomCan::sendFrame(bus, &f);
 }
 
-bool debug_send_frame_force(CAN_FRAME &f) {
+bool debug_send_frame_force(int bus, CAN_FRAME &f) {
     char buf[80] = {0x00};
     char *pos = buf;
     pos += sprintf(pos, "Send frame -> %04X (LEN: %d) [", f.id, f.length);
@@ -25,7 +25,7 @@ bool debug_send_frame_force(CAN_FRAME &f) {
     }
     sprintf(pos-1,"]");
     PCCOMM::log_message(buf);
-    return CustomCan::sendFrame(&f);
+    return CustomCan::sendFrame(bus, &f);
 }
 
 void debug_read_frame(CAN_FRAME &f) {
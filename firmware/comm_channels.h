@@ -8,9 +8,9 @@
 #include "j2534_mini.h"
 
 
-bool debug_send_frame(CAN_FRAME &f);
+bool debug_send_frame(int bus, CAN_FRAME &f);
 void debug_read_frame(CAN_FRAME &f);
-bool debug_send_frame_force(CAN_FRAME &f);
+bool debug_send_frame_force(int bus, CAN_FRAME &f);
 
 class Channel {
     public:
@@ -25,6 +25,7 @@ class Channel {
         virtual void ioctl_set(uint32_t id, uint32_t value);
     protected:
         int channel_id;
+        int can_bus = 0; // CAN interface a CAN based channel uses
 };
 
 #if defined(CFG_MACCHINA_M2)
@@ -87,6 +88,7 @@ struct CanRingBuffer {
 
 class CanChannel : public Channel {
     public:
+        explicit CanChannel(int bus) { this->can_bus = bus; }
         void wakeup(uint8_t type, uint8_t* request, uint8_t request_len){};
         bool setup(int id, int protocol, int baud, int flags);
         void addFilter(int type, int filter_id, char* mask, char* pattern, char* flowcontrol, int mask_len, int pattern_len, int flowcontrol_len);
@@ -114,6 +116,7 @@ struct isoPayload {
 
 class ISO15765Channel : public Channel {
     public:
+        explicit ISO15765Channel(int bus) { this->can_bus = bus; }
         void wakeup(uint8_t type, uint8_t* request, uint8_t request_len){};
         bool setup(int id, int protocol, int baud, int flags);
         void addFilter(int type, int filter_id, char* mask, char* pattern, char* flowcontrol, int mask_len, int pattern_len, int flowcontrol_len);
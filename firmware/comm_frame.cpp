@@ -0,0 +1,110 @@
+#include "comm.h"
+
+// Framing shared by both adapters, see frame.rs in the driver.
+// [0xA5, 0x5A, Size (2 bytes), ID, Type, Args, CRC (2 bytes)]
+// Size covers ID, Type and Args. CRC is CRC-16/CCITT-FALSE of everything after the sync marker
+
+#define FRAME_SYNC_1 0xA5
+#define FRAME_SYNC_2 0x5A
+
+namespace PCCOMM {
+
+    enum FrameState {
+        WAIT_SYNC_1,
+        WAIT_SYNC_2,
+        READ_SIZE_1,
+        READ_SIZE_2,
+        READ_BODY,
+        READ_CRC_1,
+        READ_CRC_2
+    };
+
+    FrameState frame_state = WAIT_SYNC_1;
+    uint16_t frame_size = 0;
+    uint16_t frame_pos = 0;
+    uint16_t frame_crc_rx = 0;
+
+    uint16_t frame_crc(const uint8_t* data, uint16_t len, uint16_t crc) {
+        for (uint16_t i = 0; i < len; i++) {
+            crc ^= (uint16_t)data[i] << 8;
+            for (uint8_t bit = 0; bit < 8; bit++) {
+                crc = (crc & 0x8000) ? (crc << 1) ^ 0x1021 : crc << 1;
+            }
+        }
+        return crc;
+    }
+
+    uint16_t encode_frame(COMM_MSG *msg, uint8_t* out) {
+        uint16_t size = msg->arg_size + 2;
+        out[0] = FRAME_SYNC_1;
+        out[1] = FRAME_SYNC_2;
+        out[2] = size & 0xFF;
+        out[3] = size >> 8;
+        out[4] = msg->msg_id;
+        out[5] = msg->msg_type;
+        memcpy(&out[6], msg->args, msg->arg_size);
+        uint16_t crc = frame_crc(&out[2], size + 2, 0xFFFF);
+        out[size + 4] = crc & 0xFF;
+        out[size + 5] = crc >> 8;
+        return size + FRAME_OVERHEAD;
+    }
+
+    void reset_frame_decoder() {
+        frame_state = WAIT_SYNC_1;
+        frame_pos = 0;
+    }
+
+    bool decode_frame_byte(uint8_t b, COMM_MSG *msg) {
+        switch (frame_state) {
+            case WAIT_SYNC_1:
+                if (b == FRAME_SYNC_1) {
+                    frame_state = WAIT_SYNC_2;
+                }
+                break;
+            case WAIT_SYNC_2:
+                if (b == FRAME_SYNC_2) {
+                    frame_state = READ_SIZE_1;
+                } else if (b != FRAME_SYNC_1) {
+                    frame_state = WAIT_SYNC_1;
+                }
+                break;
+            case READ_SIZE_1:
+                frame_size = b;
+                frame_state = READ_SIZE_2;
+                break;
+            case READ_SIZE_2:
+                frame_size |= (uint16_t)b << 8;
+                frame_pos = 0;
+                // Not a real frame, go back to looking for the sync marker
+                frame_state = (frame_size < 2 || frame_size > COMM_MSG_ARG_SIZE + 2) ? WAIT_SYNC_1 : READ_BODY;
+                break;
+            case READ_BODY:
+                if (frame_pos == 0) {
+                    msg->msg_id = b;
+                } else if (frame_pos == 1) {
+                    msg->msg_type = b;
+                } else {
+                    msg->args[frame_pos - 2] = b;
+                }
+                frame_pos++;
+                if (frame_pos == frame_size) {
+                    frame_state = READ_CRC_1;
+                }
+                break;
+            case READ_CRC_1:
+                frame_crc_rx = b;
+                frame_state = READ_CRC_2;
+                break;
+            case READ_CRC_2: {
+                frame_crc_rx |= (uint16_t)b << 8;
+                frame_state = WAIT_SYNC_1;
+                msg->arg_size = frame_size - 2;
+                uint8_t header[4] = { (uint8_t)(frame_size & 0xFF), (uint8_t)(frame_size >> 8), msg->msg_id, msg->msg_type };
+                uint16_t crc = frame_crc(header, 4, 0xFFFF);
+                crc = frame_crc(msg->args, msg->arg_size, crc);
+                return crc == frame_crc_rx;
+            }
+        }
+        return false;
+    }
+}
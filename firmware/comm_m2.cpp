@@ -9,39 +9,22 @@ namespace PCCOMM {
     uint8_t last_id = 0;
     COMM_MSG res = {0x00};
     
-    char* tempbuf;
-    uint16_t read_count = 0;
-    bool isReadingMsg = false;
-    uint16_t read_target = 0;
+    uint8_t tx_frame[COMM_MSG_SIZE + FRAME_OVERHEAD];
+    // Pad frames to COMM_MSG_SIZE, for drivers that did not ask otherwise in the hello
+    bool pad_frames = true;
+
     bool read_message(COMM_MSG *msg) {
-        if (!isReadingMsg && SerialUSB.available() >= 2) { // Starter of payload
+        while (SerialUSB.available() > 0) {
             PT_DEVICE->set_rx_led(true);
-            isReadingMsg = true;
-            read_count = 0;
-            SerialUSB.readBytes((char*)&read_target, 2);
-            tempbuf = new char[read_target];
-            return false;
-        } else if(isReadingMsg && SerialUSB.available() > 0) { // Just reading data
-            // Calculate how many bytes to read (min of avaliable bytes, or left to read to complete the data)
-            uint16_t maxRead = min(SerialUSB.available(), read_target-read_count);
-            SerialUSB.readBytes(&tempbuf[read_count], maxRead);
-            read_count += maxRead;
-
-            // Size OK now, full payload received
-            if(read_count == read_target) {
-                msg->arg_size = read_target - 2;
-                isReadingMsg = false;
-                msg->msg_id = tempbuf[0];
-                msg->msg_type = tempbuf[1];
-                memcpy(msg->args, &tempbuf[2], msg->arg_size);
+            if (decode_frame_byte(SerialUSB.read(), msg)) {
                 if (msg->msg_id != 0x00) {
                     last_id = msg->msg_id;
                 }
-                delete[] tempbuf;
                 PT_DEVICE->set_rx_led(false);
                 return true;
             }
         }
+        PT_DEVICE->set_rx_led(false);
         return false;
     }
 
@@ -49,7 +32,12 @@ namespace PCCOMM {
 
     void send_message(COMM_MSG *msg) {
         PT_DEVICE->set_tx_led(true);
-        SerialUSB.write((uint8_t*)msg, sizeof(COMM_MSG));
+        uint16_t len = encode_frame(msg, tx_frame);
+        if (pad_frames && len < COMM_MSG_SIZE) {
+            memset(&tx_frame[len], 0x00, COMM_MSG_SIZE - len);
+            len = COMM_MSG_SIZE;
+        }
+        SerialUSB.write(tx_frame, len);
         SerialUSB.flush(); // Wait for IO to complete!
         PT_DEVICE->set_tx_led(false);
     }
@@ -121,19 +109,20 @@ namespace PCCOMM {
         return last_id;
     }
 
+    /**
+     * Called with the hello flags when the driver connects
+     */
+    void set_hello_flags(uint8_t flags) {
+        pad_frames = (flags & HELLO_FLAG_UNPADDED) == 0;
+    }
+
     /**
      * Called on M2 disconnect
      */
     void reset() {
-        isReadingMsg = false;
-        read_count = 0;
-        read_target = 0;
         last_id = 0;
-        if (isReadingMsg) {
-            delete[] tempbuf;
-            tempbuf = nullptr;
-            isReadingMsg = false;
-        }
+        pad_frames = true;
+        reset_frame_decoder();
         // Empty any remaining serial data
         while (Serial.available()) {
             Serial.read();
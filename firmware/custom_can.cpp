@@ -1,17 +1,62 @@
 #include "custom_can.h"
 #include "comm.h"
 
-CustomCan::rxQueue rxQueues[MAILBOX_COUNT];
+CustomCan::rxQueue rxQueues[CAN_BUS_COUNT][MAILBOX_COUNT];
 
-void CustomCan::__delete_check_rx_ring(int i) {
-    rxQueues[i].head = 0;
-    rxQueues[i].tail = 0;
-    Can0.removeCallback(i);
+// Controller of a CAN interface. Anything but bus 1 is CAN0
+static CAN_COMMON& can_bus(int bus) {
+#if CAN_BUS_COUNT > 1
+    if (bus == 1) {
+        return Can1;
+    }
+#endif
+    return Can0;
+}
+
+static bool valid_bus(int bus) {
+    return bus >= 0 && bus < CAN_BUS_COUNT;
+}
+
+void CustomCan::__delete_check_rx_ring(int bus, int i) {
+    rxQueues[bus][i].head = 0;
+    rxQueues[bus][i].tail = 0;
+    can_bus(bus).removeCallback(i);
 }
 
-void CustomCan::__create_check_rx_ring(int i) {
-    rxQueues[i].head = 0;
-    rxQueues[i].tail = 0;
+void CustomCan::__create_check_rx_ring(int bus, int i) {
+    rxQueues[bus][i].head = 0;
+    rxQueues[bus][i].tail = 0;
+#if CAN_BUS_COUNT > 1
+    if (bus == 1) {
+        switch (i)
+        {
+        case 0:
+            Can1.setCallback(i, CustomCan::__callback_can1_mb0);
+            break;
+        case 1:
+            Can1.setCallback(i, CustomCan::__callback_can1_mb1);
+            break;
+        case 2:
+            Can1.setCallback(i, CustomCan::__callback_can1_mb2);
+            break;
+        case 3:
+            Can1.setCallback(i, CustomCan::__callback_can1_mb3);
+            break;
+        case 4:
+            Can1.setCallback(i, CustomCan::__callback_can1_mb4);
+            break;
+        case 5:
+            Can1.setCallback(i, CustomCan::__callback_can1_mb5);
+            break;
+        case 6:
+            Can1.setCallback(i, CustomCan::__callback_can1_mb6);
+            break;
+        default:
+            break;
+        }
+        return;
+    }
+#endif
     // Register callback for hardware interrupt
     switch (i)
     {
@@ -52,7 +97,8 @@ void CustomCan::__create_check_rx_ring(int i) {
     }
 }
 
-bool CustomCan::enableCanBus(int baud) {
+bool CustomCan::enableCanBus(int bus, int baud) {
+    if (!valid_bus(bus)) return false; // No such interface
 #if defined(CFG_MACCHINA_A0) || defined(CFG_MACCHINA_ESP32_TEST)
     pinMode(GPIO_NUM_21, OUTPUT);
     digitalWrite(GPIO_NUM_21, LOW); // Fix for TJA1042 (Production Macchina A0). Set HSC_S pin low
@@ -61,28 +107,29 @@ bool CustomCan::enableCanBus(int baud) {
     }
 #else
     // Begin bus
-    if (Can0.init(baud) == 0) {
+    if (can_bus(bus).init(baud) == 0) {
         return false;
     }
 #endif
     
     // Block all traffic
     for (int i = 0; i < MAILBOX_COUNT; i++) {
-        Can0.setRXFilter(i, 0xFFFF, 0x0000, false);
+        can_bus(bus).setRXFilter(i, 0xFFFF, 0x0000, false);
         // In case rxQueue is still there, delete it
-        __delete_check_rx_ring(i);
+        __delete_check_rx_ring(bus, i);
     }
     // No software queues created in this method
     return true;
 }
 
-void CustomCan::disableCanBus() {
-    Can0.disable();
+void CustomCan::disableCanBus(int bus) {
+    if (!valid_bus(bus)) return; // No such interface
+    can_bus(bus).disable();
     // Block all traffic
     for (int i = 0; i < 7; i++) {
-        Can0.setRXFilter(i, 0xFFFF, 0x0000, false);
+        can_bus(bus).setRXFilter(i, 0xFFFF, 0x0000, false);
         // In case rxQueue is still there, delete it
-        __delete_check_rx_ring(i);
+        __delete_check_rx_ring(bus, i);
     }
 }
 
@@ -102,48 +149,58 @@ bool CustomCan::__rx_queue_pop_frame(rxQueue &r, CAN_FRAME &f) {
     return true;
 }
 
-void CustomCan::enableCanFilter(int id, uint32_t pattern, uint32_t mask, bool isExtended) {
-    if (id < 0 || id >= MAILBOX_COUNT) return; // Invalid mailbox ID
+void CustomCan::enableCanFilter(int bus, int id, uint32_t pattern, uint32_t mask, bool isExtended) {
+    if (!valid_bus(bus) || id < 0 || id >= MAILBOX_COUNT) return; // Invalid mailbox ID
 
     // Set pattern and mask on the specified mailbox
-    Can0.setRXFilter(id, pattern, mask, isExtended);
+    can_bus(bus).setRXFilter(id, pattern, mask, isExtended);
     // Delete any old buffer if it for some reason exists
-    __delete_check_rx_ring(id);
+    __delete_check_rx_ring(bus, id);
     // Create our new ring
-    __create_check_rx_ring(id);
+    __create_check_rx_ring(bus, id);
     // Now register the callback so that frames get pushed to our mailbox
 }
 
-void CustomCan::disableCanFilter(int id) {
-    if (id < 0 || id >= MAILBOX_COUNT) return; // Invalid mailbox ID
-    Can0.setRXFilter(id, 0xFFFF, 0x0000, false);
-    __delete_check_rx_ring(id);
+void CustomCan::disableCanFilter(int bus, int id) {
+    if (!valid_bus(bus) || id < 0 || id >= MAILBOX_COUNT) return; // Invalid mailbox ID
+    can_bus(bus).setRXFilter(id, 0xFFFF, 0x0000, false);
+    __delete_check_rx_ring(bus, id);
 }
 
-bool CustomCan::receiveFrame(int mailbox_id, CAN_FRAME *f) {
-    if (mailbox_id < 0 || mailbox_id >= MAILBOX_COUNT) return false; // Invalid malbox ID
-    return __rx_queue_pop_frame(rxQueues[mailbox_id], *f);
+bool CustomCan::receiveFrame(int bus, int mailbox_id, CAN_FRAME *f) {
+    if (!valid_bus(bus) || mailbox_id < 0 || mailbox_id >= MAILBOX_COUNT) return false; // Invalid malbox ID
+    return __rx_queue_pop_frame(rxQueues[bus][mailbox_id], *f);
 }
 
-bool CustomCan::sendFrame(CAN_FRAME *cf) {
-    Can0.sendFrame(*cf);
+bool CustomCan::sendFrame(int bus, CAN_FRAME *cf) {
+    if (!valid_bus(bus)) return false; // No such interface
+    return can_bus(bus).sendFrame(*cf);
 }
 
-void CustomCan::clearMailboxQueue(int mailbox_id) {
-    if (mailbox_id < 0 || mailbox_id >= MAILBOX_COUNT) return; // Invalid malbox ID
-    rxQueues[mailbox_id].head = 0;
-    rxQueues[mailbox_id].tail = 0;
+void CustomCan::clearMailboxQueue(int bus, int mailbox_id) {
+    if (!valid_bus(bus) || mailbox_id < 0 || mailbox_id >= MAILBOX_COUNT) return; // Invalid malbox ID
+    rxQueues[bus][mailbox_id].head = 0;
+    rxQueues[bus][mailbox_id].tail = 0;
 }
 
-void CustomCan::__callback_mb0(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[0], *f); }
-void CustomCan::__callback_mb1(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[1], *f); }
-void CustomCan::__callback_mb2(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[2], *f); }
-void CustomCan::__callback_mb3(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[3], *f); }
-void CustomCan::__callback_mb4(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[4], *f); }
-void CustomCan::__callback_mb5(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[5], *f); }
-void CustomCan::__callback_mb6(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[6], *f); }
+void CustomCan::__callback_mb0(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[0][0], *f); }
+void CustomCan::__callback_mb1(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[0][1], *f); }
+void CustomCan::__callback_mb2(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[0][2], *f); }
+void CustomCan::__callback_mb3(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[0][3], *f); }
+void CustomCan::__callback_mb4(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[0][4], *f); }
+void CustomCan::__callback_mb5(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[0][5], *f); }
+void CustomCan::__callback_mb6(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[0][6], *f); }
 #ifdef CFG_MACCHINA_A0
-void CustomCan::__callback_mb7(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[7], *f); }
-void CustomCan::__callback_mb8(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[8], *f); }
-void CustomCan::__callback_mb9(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[9], *f); }
-#endif
\ No newline at end of file
+void CustomCan::__callback_mb7(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[0][7], *f); }
+void CustomCan::__callback_mb8(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[0][8], *f); }
+void CustomCan::__callback_mb9(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[0][9], *f); }
+#endif
+#if CAN_BUS_COUNT > 1
+void CustomCan::__callback_can1_mb0(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[1][0], *f); }
+void CustomCan::__callback_can1_mb1(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[1][1], *f); }
+void CustomCan::__callback_can1_mb2(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[1][2], *f); }
+void CustomCan::__callback_can1_mb3(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[1][3], *f); }
+void CustomCan::__callback_can1_mb4(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[1][4], *f); }
+void CustomCan::__callback_can1_mb5(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[1][5], *f); }
+void CustomCan::__callback_can1_mb6(CAN_FRAME *f) { __rx_queue_push_frame(rxQueues[1][6], *f); }
+#endif
@@ -18,6 +18,14 @@
 #define MAILBOX_COUNT 10
 #endif
 
+// CAN controllers with a transceiver on the board. Bus 0 is CAN0, bus 1 is CAN1
+#ifdef CFG_MACCHINA_M2
+#define CAN_BUS_COUNT 2
+#endif
+#ifdef CFG_MACCHINA_A0
+#define CAN_BUS_COUNT 1
+#endif
+
 namespace CustomCan {
 
     // Each mailbox has a rxMailbox of 8 frames
@@ -29,29 +37,32 @@ namespace CustomCan {
     };
 
     /**
-     * Sets up the CAN0 interface on the M2, and pre-configures all the mailboxes
+     * Sets up a CAN interface, and pre-configures all the mailboxes
      * to block all traffic
      * 
-     * @param baud Bus speed to initialize the CAN0 controller with
+     * @param bus CAN interface (0 or 1)
+     * @param baud Bus speed to initialize the CAN controller with
      * 
      * @returns Boolean indicating if CAN was setup successfully
      */
-    bool enableCanBus(int baud);
+    bool enableCanBus(int bus, int baud);
 
     /**
      * Deletes one of the mailboxes Rx ring buffer
+     * @param bus CAN interface the mailbox is on
      * @param i Mailbox ID to delete its ring buffer
      */
-    void __delete_check_rx_ring(int i);
+    void __delete_check_rx_ring(int bus, int i);
 
     /**
      * Creates a new Rx ring buffer for a CAN mailbox
      * If the ring buffer is already setup for the target mailbox,
      * it is simply cleared of any data
      * 
+     * @param bus CAN interface the mailbox is on
      * @param i Mailbox ID to set up a new Rx ring buffer
      */
-    void __create_check_rx_ring(int i);
+    void __create_check_rx_ring(int bus, int i);
 
     /**
      * Called on mailbox interrupt. This function will attempt to push
@@ -98,47 +109,63 @@ namespace CustomCan {
      // Callback function for mailbox 9
     void __callback_mb9(CAN_FRAME *f);
 #endif
+#if CAN_BUS_COUNT > 1
+    // Callback functions for the mailboxes of CAN1
+    void __callback_can1_mb0(CAN_FRAME *f);
+    void __callback_can1_mb1(CAN_FRAME *f);
+    void __callback_can1_mb2(CAN_FRAME *f);
+    void __callback_can1_mb3(CAN_FRAME *f);
+    void __callback_can1_mb4(CAN_FRAME *f);
+    void __callback_can1_mb5(CAN_FRAME *f);
+    void __callback_can1_mb6(CAN_FRAME *f);
+#endif
 
 
     /**
-     * Disables the Can0 interface
+     * Disables a CAN interface
+     * @param bus CAN interface (0 or 1)
      */
-    void disableCanBus();
+    void disableCanBus(int bus);
 
     /**
      * Disables a CAN mailbox filter
+     * @param bus CAN interface the mailbox is on
      * @param id Mailbox ID to disable 
      */
-    void disableCanFilter(int id);
+    void disableCanFilter(int bus, int id);
 
     /**
      * Enables a CAN mailbox with a specified filter
+     * @param bus CAN interface the mailbox is on
      * @param id Mailbox ID (0-6)
      * @param pattern Pattern for CAN ID
      * @param mask Mask for CAN ID
      * @param isExtended Boolean indicating if the mailbox should be configured for Extended CAN or not
      */
-    void enableCanFilter(int id, uint32_t pattern, uint32_t mask, bool isExtended);
+    void enableCanFilter(int bus, int id, uint32_t pattern, uint32_t mask, bool isExtended);
 
     /**
-     * Transmits a CAN Frame to the vehicles CAN Network using the CAN0 interface on the M2
+     * Transmits a CAN Frame to the vehicles CAN Network
+     * @param bus CAN interface to send on
      */
-    bool sendFrame(CAN_FRAME *cf);
+    bool sendFrame(int bus, CAN_FRAME *cf);
 
     /**
-     * Attempts to read a frame from one of the pre-configured mailboxes queues on the CAN0 interface
+     * Attempts to read a frame from one of the pre-configured mailboxes queues on a CAN interface
+     * @param bus CAN interface the mailbox is on
      * @param mailbox_id mailbox ID (0-6) to grab a frame from
      * @param f Pointer to CAN Frame to read into if data is in the mailbox queue
      * 
      * @returns Boolean indicating if read was successful or not
      */
-    bool receiveFrame(int mailbox_id, CAN_FRAME *f);
+    bool receiveFrame(int bus, int mailbox_id, CAN_FRAME *f);
 
     /**
      * Clears a mailboxes Rx ring buffer queue
+     * @param bus CAN interface the mailbox is on
      * @param mailbox_id the mailbox ID to clear
      */
-    void clearMailboxQueue(int mailbox_id);
+    void clearMailboxQueue(int bus, int mailbox_id);
 }
 
 #endif
\ No newline at end of file
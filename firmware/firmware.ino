@@ -3,6 +3,7 @@
 #include "channel.h"
 
 #include "pt_device.h"
+#include "custom_can.h"
 #include "MACCHINA_CONFIG.h"
 
 CAN_FRAME input;
@@ -24,7 +25,7 @@ void set_status_led(uint8_t status) {
     // Clear no matter what!
     reset_all_channels();
     PCCOMM::reset();
-    if (status == 0x00) {
+    if (status == STATUS_GOODBYE) {
       PT_DEVICE->set_status_led(false);
       // TODO Reset M2 to default state when we disconnect
     } else {
@@ -37,6 +38,40 @@ void get_fw_version(COMM_MSG *msg) {
   PCCOMM::respond_ok(MSG_GET_FW_VERSION, (uint8_t*)fw_str, strlen(fw_str));
 }
 
+void get_capabilities() {
+  uint8_t caps[10];
+  caps[0] = COMM_PROTOCOL_MAJOR;
+  caps[1] = COMM_PROTOCOL_MINOR;
+  // Tx args are [Channel ID, Tx flags, Data]
+  uint16_t max_payload = COMM_MSG_ARG_SIZE - 8;
+  memcpy(&caps[2], &max_payload, 2);
+  uint32_t protocols = (1 << CAN) | (1 << ISO15765);
+#if defined(CFG_MACCHINA_M2)
+  protocols |= (1 << ISO9141);
+#endif
+  memcpy(&caps[4], &protocols, 4);
+  caps[8] = MAILBOX_COUNT; // Each filter takes a mailbox
+  caps[9] = CAN_BUS_COUNT; // Since minor version 1
+  PCCOMM::respond_ok(MSG_GET_CAPABILITIES, caps, sizeof(caps));
+}
+
+void get_device_info() {
+  uint8_t info[4 + 32 + 16 + 33];
+  uint32_t uptime = millis();
+  memcpy(&info[0], &uptime, 4);
+  uint16_t len = 4;
+  const char* model = PT_DEVICE->get_model();
+  const char* revision = PT_DEVICE->get_board_revision();
+  // Strings are copied along with their null terminators
+  memcpy(&info[len], model, strlen(model) + 1);
+  len += strlen(model) + 1;
+  memcpy(&info[len], revision, strlen(revision) + 1);
+  len += strlen(revision) + 1;
+  PT_DEVICE->get_serial((char*)&info[len]);
+  len += strlen((char*)&info[len]) + 1;
+  PCCOMM::respond_ok(MSG_GET_DEVICE_INFO, info, len);
+}
+
 #ifdef FW_TEST
 unsigned long lastPing = millis();
 #endif
@@ -51,7 +86,14 @@ void loop() {
       break;
 #endif
     case MSG_STATUS:
+      if (msg.args[0] == STATUS_KEEPALIVE) {
+        PCCOMM::respond_ok(MSG_STATUS, NULL, 0);
+        break;
+      }
       set_status_led(msg.args[0]);
+      if (msg.args[0] == STATUS_HELLO && msg.arg_size > 1) {
+        PCCOMM::set_hello_flags(msg.args[1]);
+      }
       break;
     case MSG_READ_BATT:
       send_v_batt();
@@ -83,6 +125,12 @@ void loop() {
     case MSG_GET_FW_VERSION:
       get_fw_version(&msg);
       break;
+    case MSG_GET_CAPABILITIES:
+      get_capabilities();
+      break;
+    case MSG_GET_DEVICE_INFO:
+      get_device_info();
+      break;
     default:
       break;
     }
@@ -22,15 +22,26 @@ public:
     const char* get_firmware_version() {
         return this->FIRMWARE_VERSION;
     };
+    const char* get_model() {
+        return this->MODEL;
+    };
+    const char* get_board_revision() {
+        return BOARD_REVISION;
+    };
+    // Writes the unique ID of this board as a null terminated string. buf must fit 33 chars
+    void get_serial(char* buf);
 private:
 #if defined (CFG_MACCHINA_M2)
     const char* FIRMWARE_VERSION = "1.0.0_M2";
+    const char* MODEL = "Macchina M2";
 #endif
 #if defined(CFG_MACCHINA_A0)
     const char* FIRMWARE_VERSION = "1.0.1_A0";
+    const char* MODEL = "Macchina A0";
 #endif
 #if defined(CFG_MACCHINA_ESP32_TEST)
     const char* FIRMWARE_VERSION = "0.5.0_ESP32_DEV";
+    const char* MODEL = "ESP32 dev board";
 #endif
 };
 
@@ -6,6 +6,7 @@
 #include "pt_device.h"
 #include <esp32_can.h>
 #include <FastLED.h>
+#include <esp_system.h>
 
 pt_device* PT_DEVICE = new pt_device();
 
@@ -63,6 +64,13 @@ void pt_device::set_tx_led(bool state){
     }
 }
 
+// Serial is the factory MAC address
+void pt_device::get_serial(char* buf) {
+    uint8_t mac[6];
+    esp_efuse_mac_get_default(mac);
+    sprintf(buf, "%02X:%02X:%02X:%02X:%02X:%02X", mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]);
+}
+
 void pt_device::set_can_led(bool state){}
 void pt_device::set_kline_led(bool state){}
 
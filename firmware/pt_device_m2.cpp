@@ -39,6 +39,15 @@ int pt_device::read_batt_mv() {
     return voltage * 100;
 }
 
+// Serial is the 128 bit unique ID of the SAM3X
+void pt_device::get_serial(char* buf) {
+    uint32_t uid[4];
+    __disable_irq(); // Flash cannot be read whilst in unique ID mode
+    efc_perform_read_sequence(EFC0, EFC_FCMD_STUI, EFC_FCMD_SPUI, uid, 4);
+    __enable_irq();
+    sprintf(buf, "%08lX%08lX%08lX%08lX", uid[0], uid[1], uid[2], uid[3]);
+}
+
 void pt_device::set_status_led(bool state) {
     if (state) {
         digitalWrite(DS6, LOW);